@@ -1,10 +1,26 @@
 pub mod address;
 pub mod block;
 pub mod blockchain;
+pub mod consensus;
 pub mod crypto;
+pub mod difficulty;
+pub mod encoding;
+pub mod header;
+pub mod headers;
+pub mod ledger;
+pub mod merkle;
+pub mod params;
+pub mod store;
 pub mod transaction;
 
 pub use address::Address;
 pub use block::Block;
-pub use blockchain::Blockchain;
-pub use transaction::{Input, Output, Transaction, Transactor};
+pub use blockchain::{Blockchain, ChainError, Reorg};
+pub use encoding::{Decode, DecodeError, Encode};
+pub use header::BlockHeader;
+pub use headers::HeaderChain;
+pub use ledger::Ledger;
+pub use merkle::{MerkleProof, TransactionProof};
+pub use params::ChainParams;
+pub use store::{ChainStore, MemoryChainStore, MemoryStateStore, StateStore};
+pub use transaction::{Coinbase, Input, Output, Transaction, Transactor};
@@ -2,6 +2,7 @@ use crate::core::crypto::{
     generate_random_rsa_pair, BigUint, Digest, PublicKey, PublicKeyParts, RsaPublicKey, Sha256,
     Sha256Hash,
 };
+use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
 use serde::{Deserialize, Serialize};
 
 #[derive(PartialEq, Eq, Clone, Debug, Deserialize, Serialize, Hash)]
@@ -10,6 +11,14 @@ pub struct Address
     key: RsaPublicKey,
 }
 
+impl Default for Address
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
 impl Address
 {
     pub fn new() -> Address
@@ -34,7 +43,7 @@ impl Address
 
     pub fn get_key(&self) -> &RsaPublicKey
     {
-        return &self.key;
+        &self.key
     }
 
     pub fn verify_data(&self, data: &[u8], sig: &[u8]) -> Result<(), String>
@@ -52,15 +61,32 @@ impl Sha256Hash for Address
 {
     fn hash(&self) -> Vec<u8>
     {
-        let mut hasher = Sha256::new();
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
 
-        let n_byts = self.key.n().to_bytes_be();
-        let e_byts = self.key.e().to_bytes_be();
+/// the modulus followed by the public exponent
+impl Encode for Address
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.key.n().encode(buf);
+        self.key.e().encode(buf);
+    }
+}
 
-        hasher.update(&n_byts[..]);
-        hasher.update(&e_byts[..]);
+impl Decode for Address
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        let n = BigUint::decode(reader)?;
+        let e = BigUint::decode(reader)?;
 
-        hasher.finalize().to_vec()
+        match RsaPublicKey::new(n, e)
+        {
+            Ok(key) => Ok(Address::with_key(key)),
+            Err(e) => Err(DecodeError::InvalidValue(e.to_string())),
+        }
     }
 }
 
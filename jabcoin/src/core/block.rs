@@ -1,78 +1,102 @@
 use crate::core::crypto::Sha256Hash;
-use crate::core::{Address, Transaction};
+use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::core::merkle::{merkle_root, MerkleProof, TransactionProof};
+use crate::core::{Address, BlockHeader, ChainParams, Coinbase, Transaction};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
-struct Nounce
-{
-    nounce: u64,
-}
-
-impl Nounce
-{
-    fn new() -> Nounce
-    {
-        Nounce { nounce: 0 }
-    }
-
-    fn incr(&mut self)
-    {
-        self.nounce += 1;
-    }
-}
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// a header together with the body it commits to
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Block
 {
-    id: u64,
-    nounce: Nounce,
-    miner: Address,
+    header: BlockHeader,
+    coinbase: Coinbase,
     transactions: Vec<Transaction>,
-    hash_prev: Vec<u8>,
 }
 
 impl Block
 {
-    /// construct an empty block
+    /// construct an empty block, the miner's
+    /// reward has to be set with `set_reward`
     pub fn new(miner: Address) -> Block
     {
-        Block {
-            id: 0,
-            hash_prev: Vec::new(),
-            miner,
-            nounce: Nounce::new(),
-            transactions: Vec::new(),
-        }
+        let header = BlockHeader::new(
+            Vec::new(),
+            Vec::new(),
+            now(),
+            ChainParams::default().initial_bits,
+            0,
+        );
+        Block::with_header(header, Coinbase::new(0, miner, 0))
     }
 
     /// construct a block with information
-    /// about its predecessor
+    /// about its predecessor, the target is taken over
+    /// from the predecessor and the timestamp is at least
+    /// one second after the predecessor's
     pub fn with_previous(miner: Address, prev: &Block) -> Block
     {
-        Block {
-            id: prev.id + 1,
-            hash_prev: prev.hash(),
-            miner,
-            nounce: Nounce::new(),
+        let height = prev.id() + 1;
+        let header = BlockHeader::new(
+            prev.hash(),
+            Vec::new(),
+            now().max(prev.timestamp() + 1),
+            prev.bits(),
+            height,
+        );
+        Block::with_header(header, Coinbase::new(height, miner, 0))
+    }
+
+    fn with_header(header: BlockHeader, coinbase: Coinbase) -> Block
+    {
+        let mut blk = Block {
+            header,
+            coinbase,
             transactions: Vec::new(),
-        }
+        };
+        blk.update_merkle_root();
+        blk
+    }
+
+    pub fn header(&self) -> &BlockHeader
+    {
+        &self.header
     }
 
     pub fn id(&self) -> u64
     {
-        self.id
+        self.header.height()
     }
 
     pub fn add_transaction(&mut self, trx: Transaction)
     {
-        // check validity? todo
-        self.transactions.push(trx);
+        self.add_transactions(std::iter::once(trx));
+    }
+
+    /// append several transactions, updating the merkle root only once
+    pub fn add_transactions<I>(&mut self, trxs: I)
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        self.transactions.extend(trxs);
+        self.update_merkle_root();
     }
 
     pub fn get_miner(&self) -> &Address
     {
-        &self.miner
+        self.coinbase.get_addr()
+    }
+
+    pub fn coinbase(&self) -> &Coinbase
+    {
+        &self.coinbase
+    }
+
+    /// set the value the miner claims through the coinbase
+    pub fn set_reward(&mut self, value: u64)
+    {
+        self.coinbase.set_value(value);
+        self.update_merkle_root();
     }
 
     pub fn transactions(&self) -> &Vec<Transaction>
@@ -80,37 +104,143 @@ impl Block
         &self.transactions
     }
 
+    /// leaves of the merkle tree, the coinbase followed by the transactions
+    fn merkle_leaves(&self) -> Vec<Vec<u8>>
+    {
+        std::iter::once(self.coinbase.hash())
+            .chain(self.transactions.iter().map(|t| t.hash()))
+            .collect()
+    }
+
+    fn update_merkle_root(&mut self)
+    {
+        let root = self.compute_merkle_root();
+        self.header.set_merkle_root(root);
+    }
+
+    /// merkle root the header commits to
+    pub fn merkle_root(&self) -> &Vec<u8>
+    {
+        self.header.merkle_root()
+    }
+
+    /// merkle root over the coinbase and all transactions of the body,
+    /// differs from `merkle_root` if the body doesn't match the header
+    pub fn compute_merkle_root(&self) -> Vec<u8>
+    {
+        merkle_root(&self.merkle_leaves())
+    }
+
+    /// proof that `trx` is part of the block, None if it isn't
+    pub fn merkle_proof(&self, trx: &Transaction) -> Option<MerkleProof>
+    {
+        let pos = self.transactions.iter().position(|t| t == trx)?;
+        MerkleProof::new(&self.merkle_leaves(), pos + 1)
+    }
+
+    /// proof of `trx` for light clients, which only know the header
+    pub fn transaction_proof(&self, trx: &Transaction) -> Option<TransactionProof>
+    {
+        Some(TransactionProof {
+            block: self.hash(),
+            transaction: trx.clone(),
+            proof: self.merkle_proof(trx)?,
+        })
+    }
+
+    /// number of bytes the encoded block occupies
+    pub fn size(&self) -> usize
+    {
+        self.to_bytes().len()
+    }
+
     pub fn update_nounce(&mut self)
     {
-        self.nounce.incr()
+        self.header.set_nounce(self.header.nounce().wrapping_add(1))
+    }
+
+    pub fn set_nounce(&mut self, nounce: u64)
+    {
+        self.header.set_nounce(nounce)
+    }
+
+    pub fn timestamp(&self) -> u64
+    {
+        self.header.timestamp()
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u64)
+    {
+        self.header.set_timestamp(timestamp)
+    }
+
+    pub fn bits(&self) -> u32
+    {
+        self.header.bits()
+    }
+
+    pub fn set_bits(&mut self, bits: u32)
+    {
+        self.header.set_bits(bits)
+    }
+
+    pub fn check_pow(&self) -> bool
+    {
+        self.header.check_pow()
+    }
+
+    pub fn work(&self) -> u128
+    {
+        self.header.work()
     }
 
     pub fn hash_prev(&self) -> &Vec<u8>
     {
-        &self.hash_prev
+        self.header.hash_prev()
     }
 
     pub fn set_hash_prev(&mut self, hash: Vec<u8>)
     {
-        self.hash_prev = hash;
+        self.header.set_hash_prev(hash)
     }
 }
 
+/// current time in seconds since the unix epoch
+pub(crate) fn now() -> u64
+{
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
 impl Sha256Hash for Block
 {
+    /// the hash of the header, the body is covered through the merkle root
     fn hash(&self) -> Vec<u8>
     {
-        let mut hasher = Sha256::new();
+        self.header.hash()
+    }
+}
 
-        hasher.update(self.id.to_be_bytes());
-        hasher.update(self.nounce.nounce.to_be_bytes());
-        hasher.update(&self.hash_prev[..]);
-        hasher.update(&self.miner.hash());
-        for transaction in &self.transactions
-        {
-            hasher.update(&transaction.hash()[..]);
-        }
-        hasher.finalize().to_vec()
+impl Encode for Block
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.header.encode(buf);
+        self.coinbase.encode(buf);
+        self.transactions.encode(buf);
+    }
+}
+
+impl Decode for Block
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Block {
+            header: BlockHeader::decode(reader)?,
+            coinbase: Coinbase::decode(reader)?,
+            transactions: Vec::decode(reader)?,
+        })
     }
 }
 
@@ -151,9 +281,9 @@ mod tests
 
         for i in 1..100
         {
-            let inp = Input::new(initiator.clone(), i);
+            let inp = Input::new(initiator.clone(), i, i);
             let outp = Output::with_addrs(vec![(recipient.clone(), i)]).unwrap();
-            let trx = Transaction::with_signature(inp, outp, vec![123, 12, 31, 23, 123]);
+            let trx = Transaction::with_signature(inp, outp, 0, vec![123, 12, 31, 23, 123]);
             blk.add_transaction(trx);
         }
 
@@ -183,22 +313,109 @@ mod tests
         serde_json::from_str::<Block>(&data).unwrap();
     }
 
+    #[test]
+    fn encode_roundtrip()
+    {
+        let data = std::fs::read_to_string("etc/mock/block.json").unwrap();
+        let blk = serde_json::from_str::<Block>(&data).unwrap();
+
+        let bytes = blk.to_bytes();
+        assert_eq!(blk.size(), bytes.len());
+
+        let decoded = Block::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, blk);
+        assert_eq!(decoded.hash(), blk.hash());
+
+        // truncated or extended data is rejected
+        assert!(Block::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut longer = bytes;
+        longer.push(0);
+        assert!(Block::from_bytes(&longer).is_err());
+    }
+
     #[test]
     fn find_nounce()
     {
         let miner = read_mock_address();
         let mut block = Block::new(miner);
 
-        let pred = |x: &String| return x.starts_with("0");
+        let pred = |x: &String| x.starts_with("0");
 
         let mut hashes = vec![];
         for _ in 0..100
         {
             hashes.push(block.hash_str());
-            block.nounce.incr();
+            block.update_nounce();
         }
 
         let good: Vec<String> = hashes.clone().into_iter().filter(pred).collect();
-        assert!(good.len() > 0);
+        assert!(!good.is_empty());
+    }
+
+    #[test]
+    fn merkle_proof()
+    {
+        let mut block = Block::new(read_mock_address());
+        let sender = Address::generate_random();
+
+        let trxs: Vec<Transaction> = (0..5)
+            .map(|i| {
+                let out = Output::with_addrs(vec![(Address::generate_random(), i)]).unwrap();
+                Transaction::new(Input::new(sender.clone(), i, i), out, 0)
+            })
+            .collect();
+
+        for trx in &trxs[..4]
+        {
+            block.add_transaction(trx.clone());
+        }
+
+        let root = block.merkle_root().clone();
+        for trx in &trxs[..4]
+        {
+            assert!(block.merkle_proof(trx).unwrap().verify(trx, &root));
+        }
+
+        assert!(block.merkle_proof(&trxs[4]).is_none());
+        let proof = block.merkle_proof(&trxs[0]).unwrap();
+        assert!(!proof.verify(&trxs[4], &root));
+
+        let trx_proof = block.transaction_proof(&trxs[1]).unwrap();
+        assert!(trx_proof.verify(block.header()));
+        assert!(!trx_proof.verify(Block::new(read_mock_address()).header()));
+
+        // the root commits to the coinbase
+        block.set_reward(1);
+        assert!(!proof.verify(&trxs[0], block.merkle_root()));
+    }
+
+    #[test]
+    fn pow_target()
+    {
+        let mut block = Block::new(read_mock_address());
+
+        // every hash is below this target
+        block.set_bits(0x2100_ffff);
+        assert!(block.check_pow());
+
+        // no hash is below zero
+        block.set_bits(0x0300_0000);
+        assert!(!block.check_pow());
+    }
+
+    #[test]
+    fn set_nounce()
+    {
+        let miner = read_mock_address();
+        let mut block = Block::new(miner);
+        let mut other = block.clone();
+
+        for _ in 0..42
+        {
+            block.update_nounce();
+        }
+        other.set_nounce(42);
+
+        assert_eq!(block.hash(), other.hash());
     }
 }
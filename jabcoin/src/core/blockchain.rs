@@ -1,183 +1,913 @@
 use crate::core::block::Block;
+use crate::core::consensus::{self, HeaderLookup};
 use crate::core::crypto::Sha256Hash;
-use std::collections::HashMap;
+use crate::core::header::BlockHeader;
+use crate::core::ledger::Ledger;
+use crate::core::params::ChainParams;
+use crate::core::store::{ChainStore, MemoryChainStore, MemoryStateStore, StateStore};
+use crate::core::transaction::Transaction;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// position of a block inside the block tree
+struct BlockIndex
+{
+    height: u64,
+
+    // accumulated work of the branch ending in this block
+    work: u128,
+
+    // kept at hand so walking the tree doesn't load any block
+    header: BlockHeader,
+}
+
+/// reasons for rejecting a block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainError
+{
+    /// the block is already part of the block tree
+    KnownBlock,
+
+    /// `hash_prev` does not reference a known block
+    UnknownPredecessor,
+
+    /// a second block without predecessor
+    MultipleGenesis,
+
+    /// the id is not the id of the predecessor + 1
+    InvalidId
+    {
+        expected: u64, found: u64
+    },
+
+    /// the target differs from the one required by the retargeting rule
+    InvalidBits
+    {
+        expected: u32, found: u32
+    },
+
+    /// the hash does not satisfy the proof-of-work
+    InvalidPow,
+
+    /// the merkle root of the header doesn't match the body
+    InvalidMerkleRoot,
+
+    /// the timestamp doesn't exceed the median time of the preceding blocks
+    TimestampTooOld
+    {
+        median: u64, found: u64
+    },
+
+    /// the timestamp is too far ahead of the local clock
+    TimestampTooNew
+    {
+        limit: u64, found: u64
+    },
+
+    /// the serialized block exceeds the size limit
+    TooLarge
+    {
+        size: usize, limit: usize
+    },
+
+    /// a contained transaction is invalid
+    InvalidTransaction(String),
+
+    /// the coinbase has the wrong height or claims too much
+    InvalidCoinbase(String),
+
+    /// the block or the resulting state couldn't be stored
+    Storage(String),
+}
+
+impl ChainError
+{
+    /// true if the block breaks the rules no matter what else we know,
+    /// a node sending such a block is misbehaving
+    pub fn is_invalid(&self) -> bool
+    {
+        !matches!(
+            self,
+            ChainError::KnownBlock
+                | ChainError::UnknownPredecessor
+                | ChainError::MultipleGenesis
+                | ChainError::TimestampTooNew { .. }
+                | ChainError::Storage(_)
+        )
+    }
+}
+
+impl fmt::Display for ChainError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ChainError::KnownBlock => write!(f, "block already known"),
+            ChainError::UnknownPredecessor => write!(f, "unknown predecessor"),
+            ChainError::MultipleGenesis => write!(f, "multiple genesis blocks"),
+            ChainError::InvalidId { expected, found } =>
+            {
+                write!(f, "invalid block id: expected {expected}, found {found}")
+            }
+            ChainError::InvalidBits { expected, found } =>
+            {
+                write!(
+                    f,
+                    "invalid target: expected {expected:#010x}, found {found:#010x}"
+                )
+            }
+            ChainError::InvalidPow => write!(f, "hash does not satisfy the proof-of-work"),
+            ChainError::InvalidMerkleRoot => write!(f, "merkle root doesn't match the body"),
+            ChainError::TimestampTooOld { median, found } =>
+            {
+                write!(
+                    f,
+                    "timestamp {found} doesn't exceed the median time past {median}"
+                )
+            }
+            ChainError::TimestampTooNew { limit, found } =>
+            {
+                write!(f, "timestamp {found} is ahead of the limit {limit}")
+            }
+            ChainError::TooLarge { size, limit } =>
+            {
+                write!(
+                    f,
+                    "block of {size} bytes exceeds the limit of {limit} bytes"
+                )
+            }
+            ChainError::InvalidTransaction(e) => write!(f, "invalid transaction: {e}"),
+            ChainError::InvalidCoinbase(e) => write!(f, "invalid coinbase: {e}"),
+            ChainError::Storage(e) => write!(f, "storage failure: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// change of the best chain caused by appending a block
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reorg
+{
+    /// blocks removed from the best chain, starting at the old head
+    pub disconnected: Vec<Block>,
+
+    /// blocks added to the best chain, ending at the new head
+    pub connected: Vec<Block>,
+}
+
+impl Reorg
+{
+    /// true if the best chain did not change at all
+    pub fn is_empty(&self) -> bool
+    {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
+}
 
 pub struct Blockchain
 {
     length: u64,
 
-    // holds the hash of the latest block
+    // holds the hash of the tip with the most accumulated work
     head: Option<Vec<u8>>,
-    blocks: HashMap<Vec<u8>, Block>,
+    genesis: Option<Vec<u8>>,
+    blocks: Box<dyn ChainStore + Send>,
+    index: HashMap<Vec<u8>, BlockIndex>,
+
+    // hashes of all blocks without successors
+    tips: HashSet<Vec<u8>>,
+
+    // account state at the head
+    state: Box<dyn StateStore + Send>,
+
+    params: ChainParams,
+}
+
+impl Default for Blockchain
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
 }
 
 impl Blockchain
 {
     pub fn new() -> Blockchain
+    {
+        Blockchain::with_params(ChainParams::default())
+    }
+
+    pub fn with_params(params: ChainParams) -> Blockchain
+    {
+        Blockchain::empty(
+            params,
+            Box::new(MemoryChainStore::new()),
+            Box::new(MemoryStateStore::new()),
+        )
+    }
+
+    fn empty(
+        params: ChainParams,
+        blocks: Box<dyn ChainStore + Send>,
+        state: Box<dyn StateStore + Send>,
+    ) -> Blockchain
     {
         Blockchain {
             length: 0,
             head: None,
-            blocks: HashMap::new(),
+            genesis: None,
+            blocks,
+            index: HashMap::new(),
+            tips: HashSet::new(),
+            state,
+            params,
         }
     }
 
-    pub fn head(&self) -> Option<&Block>
+    /// build the chain on top of the given backends. blocks already held
+    /// by the chain store have been verified before and are only indexed,
+    /// the stored state is brought up to the head by replaying the blocks
+    /// it is missing
+    pub fn with_stores(
+        params: ChainParams,
+        blocks: Box<dyn ChainStore + Send>,
+        state: Box<dyn StateStore + Send>,
+    ) -> Result<Blockchain, String>
     {
-        match &self.head
+        let stored: Vec<BlockHeader> = blocks.blocks().map(|b| b.header().clone()).collect();
+
+        let mut blkchain = Blockchain::empty(params, blocks, state);
+        for header in Blockchain::parents_first(&stored, |h| h)?
         {
-            Some(h) => Some(&self.blocks[h]),
-            None => None,
+            let (height, work) = blkchain.link(header);
+
+            // of several best tips the one of the stored state is kept
+            let known = blkchain.state.head() == header.hash();
+            if work > blkchain.work() || (work == blkchain.work() && known)
+            {
+                blkchain.head = Some(header.hash());
+                blkchain.length = height;
+            }
         }
+
+        let head = blkchain.head.clone().unwrap_or_default();
+        let base = blkchain.state.head().to_vec();
+
+        // the state might be behind the head or left over from an abandoned chain
+        if head != base
+        {
+            let ledger = blkchain
+                .replay(&head, &base, blkchain.state.ledger().clone())
+                .map_err(|e| format!("failed to replay the stored blocks with error {e}"))?;
+
+            blkchain
+                .state
+                .commit(&head, ledger)
+                .map_err(|e| format!("failed to update the state with error {e}"))?;
+        }
+
+        Ok(blkchain)
     }
 
-    pub fn get_blocks(&self) -> &HashMap<Vec<u8>, Block>
+    pub fn params(&self) -> &ChainParams
     {
-        &self.blocks
+        &self.params
     }
 
-    pub fn len(&self) -> u64
+    pub fn head(&self) -> Option<Block>
     {
-        self.length
+        self.head.as_ref().map(|h| self.block(h))
     }
 
-    fn verify(&self, block: &Block) -> Result<(), String>
+    /// the block all branches start at
+    pub fn genesis(&self) -> Option<Block>
     {
-        // naive consensus
-        if !block.hash_str().starts_with("000")
+        self.genesis.as_ref().map(|h| self.block(h))
+    }
+
+    /// all known blocks, including those on side branches
+    pub fn get_blocks(&self) -> impl Iterator<Item = Block> + '_
+    {
+        self.index.keys().map(|h| self.block(h))
+    }
+
+    pub fn get_block(&self, hash: &[u8]) -> Option<Block>
+    {
+        if !self.index.contains_key(hash)
         {
-            return Err(format!("hash is invalid: {}", block.hash_str()));
+            return None;
         }
+        self.blocks.get(hash)
+    }
 
-        for i in block.transactions()
+    fn block(&self, hash: &[u8]) -> Block
+    {
+        self.blocks.get(hash).expect("indexed blocks are stored")
+    }
+
+    /// the headers of the best chain, starting at the genesis block
+    pub fn best_headers(&self) -> Vec<&BlockHeader>
+    {
+        let mut chain = vec![];
+        let mut next = self.head.as_deref().and_then(|h| self.header(h));
+
+        while let Some(header) = next
         {
-            if let Some(sig) = &i.signature()
+            chain.push(header);
+            next = self.header(header.hash_prev());
+        }
+
+        chain.reverse();
+        chain
+    }
+
+    /// the blocks of the best chain, starting at the genesis block
+    pub fn best_chain(&self) -> Vec<Block>
+    {
+        self.best_headers()
+            .into_iter()
+            .map(|h| self.block(&h.hash()))
+            .collect()
+    }
+
+    /// hashes of blocks of the best chain, starting at the head and getting
+    /// sparser towards the genesis block, which is always included
+    pub fn locator(&self) -> Vec<Vec<u8>>
+    {
+        let chain = self.best_headers();
+
+        consensus::locator_heights(chain.len())
+            .into_iter()
+            .map(|i| chain[i].hash())
+            .collect()
+    }
+
+    /// up to `max` headers of the best chain following the first block
+    /// of the locator which is part of it, starting at the genesis block
+    /// if none is
+    pub fn headers_after(&self, locator: &[Vec<u8>], max: usize) -> Vec<BlockHeader>
+    {
+        let chain = self.best_headers();
+
+        let start = locator
+            .iter()
+            .find_map(|hash| {
+                // side branches may be taller than the best chain
+                let height = self.index.get(hash)?.height as usize;
+                chain
+                    .get(height - 1)
+                    .filter(|header| &header.hash() == hash)
+                    .map(|_| height)
+            })
+            .unwrap_or(0);
+
+        chain[start..]
+            .iter()
+            .take(max)
+            .map(|&header| header.clone())
+            .collect()
+    }
+
+    /// check that the headers form a chain extending a known block and
+    /// satisfy their proof-of-work, returns the hashes of the blocks
+    /// which are not known yet
+    pub fn missing_blocks(&self, headers: &[BlockHeader]) -> Result<Vec<Vec<u8>>, ChainError>
+    {
+        self.missing_blocks_after(None, headers)
+    }
+
+    /// like `missing_blocks`, but the headers may also extend `prev`,
+    /// a header which has been checked before but isn't part of the block tree
+    pub fn missing_blocks_after(
+        &self,
+        prev: Option<&BlockHeader>,
+        headers: &[BlockHeader],
+    ) -> Result<Vec<Vec<u8>>, ChainError>
+    {
+        let mut missing = vec![];
+
+        // hash and height of the previous header
+        let mut prev: Option<(Vec<u8>, u64)> = prev.map(|h| (h.hash(), h.height()));
+
+        for header in headers
+        {
+            let expected = match &prev
             {
-                if let Err(e) = i.input().get_addr().verify_data(&i.hash_ignore_sig(), sig)
+                Some((hash, height)) if hash == header.hash_prev() => height + 1,
+                _ if header.hash_prev().is_empty() => 0,
+                _ => match self.header(header.hash_prev())
                 {
-                    return Err(format!(
-                        "failed to verify signature for transaction: {} with error {}",
-                        i.hash_str(),
-                        e
-                    ));
-                }
-            }
-            else
+                    Some(parent) => parent.height() + 1,
+                    None => return Err(ChainError::UnknownPredecessor),
+                },
+            };
+
+            if header.height() != expected
             {
-                return Err(format!(
-                    "signature missing for transaction: {}",
-                    i.hash_str()
-                ));
+                return Err(ChainError::InvalidId {
+                    expected,
+                    found: header.height(),
+                });
             }
 
-            let out_sum = i
-                .output()
-                .transactors()
-                .iter()
-                .fold(0, |acc, trxactor| acc + trxactor.get_value());
+            if !header.check_pow()
+            {
+                return Err(ChainError::InvalidPow);
+            }
 
-            if out_sum > i.input().get_value()
+            let hash = header.hash();
+            if !self.contains(&hash)
             {
-                return Err(format!(
-                    "insufficient input value in transaction: {}:\n {} < {}",
-                    i.hash_str(),
-                    i.input().get_value(),
-                    out_sum
-                ));
+                missing.push(hash.clone());
             }
+            prev = Some((hash, header.height()));
+        }
+
+        Ok(missing)
+    }
+
+    /// all tips of the block tree together with their accumulated work
+    pub fn tips(&self) -> Vec<(&BlockHeader, u128)>
+    {
+        self.tips
+            .iter()
+            .map(|h| (&self.index[h].header, self.index[h].work))
+            .collect()
+    }
+
+    /// account state at the head of the best chain
+    pub fn ledger(&self) -> &Ledger
+    {
+        self.state.ledger()
+    }
+
+    /// account state after the block with the given hash
+    fn ledger_at(&self, hash: &[u8]) -> Ledger
+    {
+        if self.head.as_deref() == Some(hash)
+        {
+            return self.ledger().clone();
+        }
+
+        self.replay(hash, &[], Ledger::new())
+            .expect("stored blocks have been verified")
+    }
+
+    /// account state after the block `hash`, built upon the state `ledger`
+    /// after the block `base` if that is one of its predecessors and
+    /// upon the empty state otherwise
+    fn replay(&self, hash: &[u8], base: &[u8], ledger: Ledger) -> Result<Ledger, String>
+    {
+        let mut branch = vec![];
+        let mut next = self.header(hash);
+
+        while let Some(header) = next.filter(|header| header.hash() != base)
+        {
+            branch.push(header.hash());
+            next = self.header(header.hash_prev());
+        }
+
+        let mut ledger = match next
+        {
+            Some(_) => ledger,
+            None => Ledger::new(),
+        };
+
+        // blocks are loaded one at a time, the branch might be the whole chain
+        for hash in branch.into_iter().rev()
+        {
+            ledger.apply_block(&self.block(&hash))?;
+        }
+        Ok(ledger)
+    }
+
+    /// median timestamp of the block with the given hash and
+    /// its 10 predecessors, 0 for unknown blocks
+    pub fn median_time_past(&self, hash: &[u8]) -> u64
+    {
+        consensus::median_time_past(self, hash)
+    }
+
+    /// the blocks of the best chain with a timestamp in [from, to)
+    pub fn blocks_between(&self, from: u64, to: u64) -> Vec<Block>
+    {
+        self.best_headers()
+            .into_iter()
+            .filter(|header| (from..to).contains(&header.timestamp()))
+            .map(|header| self.block(&header.hash()))
+            .collect()
+    }
+
+    /// compact target required for a successor of `prev`,
+    /// the genesis block uses the initial target
+    pub fn next_bits(&self, prev: Option<&Block>) -> u32
+    {
+        consensus::next_bits(&self.params, self, prev.map(Block::header))
+    }
+
+    /// accumulated work of the best chain
+    pub fn work(&self) -> u128
+    {
+        match &self.head
+        {
+            Some(h) => self.index[h].work,
+            None => 0,
+        }
+    }
+
+    /// number of blocks in the best chain
+    pub fn len(&self) -> u64
+    {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.length == 0
+    }
+
+    /// check whether a block with the given hash is part of the chain
+    pub fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.index.contains_key(hash)
+    }
+
+    /// verify a block exactly as it was received,
+    /// returns the account state after the block
+    fn verify(&self, block: &Block) -> Result<Ledger, ChainError>
+    {
+        consensus::verify_header(&self.params, self, block.header())?;
+
+        if block.merkle_root() != &block.compute_merkle_root()
+        {
+            return Err(ChainError::InvalidMerkleRoot);
+        }
+
+        let size = block.size();
+
+        if size > self.params.max_block_size
+        {
+            return Err(ChainError::TooLarge {
+                size,
+                limit: self.params.max_block_size,
+            });
+        }
+
+        for i in block.transactions()
+        {
+            Blockchain::check_transaction(i).map_err(ChainError::InvalidTransaction)?;
+        }
+
+        let coinbase = block.coinbase();
+
+        if coinbase.height() != block.id()
+        {
+            return Err(ChainError::InvalidCoinbase(format!(
+                "height {} doesn't match block id {}",
+                coinbase.height(),
+                block.id()
+            )));
+        }
+
+        let mut ledger = self.ledger_at(block.hash_prev());
+        let fees = ledger
+            .apply_block(block)
+            .map_err(ChainError::InvalidTransaction)?;
+
+        let available = self.params.subsidy(block.id()).saturating_add(fees);
+
+        if coinbase.get_value() > available
+        {
+            return Err(ChainError::InvalidCoinbase(format!(
+                "claims {} but only {available} are available",
+                coinbase.get_value()
+            )));
+        }
+
+        Ok(ledger)
+    }
+
+    /// check whether a transaction may be included in a future block on top of the head
+    pub fn verify_transaction(&self, trx: &Transaction) -> Result<(), String>
+    {
+        Blockchain::check_transaction(trx)?;
+        self.verify_transaction_state(trx)
+    }
+
+    /// the checks of `verify_transaction` depending on the state at the head,
+    /// for transactions whose signature has been verified before
+    pub fn verify_transaction_state(&self, trx: &Transaction) -> Result<(), String>
+    {
+        let inp = trx.input();
+        let next_nonce = self.ledger().next_nonce(inp.get_addr());
+
+        if inp.get_nonce() < next_nonce
+        {
+            return Err(format!(
+                "nonce {} of transaction {} has already been used",
+                inp.get_nonce(),
+                trx.hash_str()
+            ));
+        }
+
+        let balance = self.ledger().balance(inp.get_addr());
+
+        if balance < inp.get_value()
+        {
+            return Err(format!(
+                "insufficient balance for transaction {}: {balance} < {}",
+                trx.hash_str(),
+                inp.get_value()
+            ));
         }
 
         Ok(())
     }
 
-    pub fn append_block(&mut self, mut block: Block) -> Result<(), (String, Block)>
+    /// checks which don't depend on the state of the chain
+    fn check_transaction(trx: &Transaction) -> Result<(), String>
     {
-        if let Err(e) = Blockchain::verify(self, &block)
+        if let Some(sig) = &trx.signature()
         {
-            Err((
-                format!("block verification failed with error: {}", e),
-                block,
-            ))
+            if let Err(e) = trx
+                .input()
+                .get_addr()
+                .verify_data(&trx.hash_ignore_sig(), sig)
+            {
+                return Err(format!(
+                    "failed to verify signature for transaction: {} with error {}",
+                    trx.hash_str(),
+                    e
+                ));
+            }
         }
         else
         {
-            if let Some(last) = self.head()
+            return Err(format!(
+                "signature missing for transaction: {}",
+                trx.hash_str()
+            ));
+        }
+
+        let out_sum = match trx.output().total_value()
+        {
+            Some(v) => v,
+            None =>
             {
-                block.set_hash_prev(last.hash());
+                return Err(format!(
+                    "output value overflow in transaction: {}",
+                    trx.hash_str()
+                ))
             }
-            else
+        };
+
+        if out_sum.checked_add(trx.fee()) != Some(trx.input().get_value())
+        {
+            return Err(format!(
+                "input value doesn't match outputs and fee in transaction: {}:\n {} != {} + {}",
+                trx.hash_str(),
+                trx.input().get_value(),
+                out_sum,
+                trx.fee()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// insert a block into the block tree and switch to its branch
+    /// if it carries more work than the current best chain
+    pub fn append_block(&mut self, block: Block) -> Result<Reorg, ChainError>
+    {
+        let hash = block.hash();
+
+        if self.contains(&hash)
+        {
+            return Err(ChainError::KnownBlock);
+        }
+
+        let ledger = Blockchain::verify(self, &block)?;
+
+        // ties are resolved in favour of the branch seen first
+        let work = self.work_after(&block);
+        let best = work > self.work();
+
+        // the state is committed first, a state behind the stored blocks
+        // is caught up with on the next start
+        if best
+        {
+            self.state
+                .commit(&hash, ledger)
+                .map_err(ChainError::Storage)?;
+        }
+
+        if let Err(e) = self.blocks.insert(block.clone())
+        {
+            if best
             {
-                block.set_hash_prev(Vec::new());
+                // put back the state of the head
+                let head = self.head.clone().unwrap_or_default();
+                let ledger = self
+                    .replay(&head, &[], Ledger::new())
+                    .expect("stored blocks have been verified");
+                let _ = self.state.commit(&head, ledger);
             }
+            return Err(ChainError::Storage(e));
+        }
 
-            let h = block.hash();
-            self.head = Some(h.clone());
-            self.blocks.insert(h, block);
-            self.length += 1;
+        let (height, _) = self.link(block.header());
 
-            Ok(())
+        if !best
+        {
+            return Ok(Reorg::default());
         }
+
+        let reorg = self.reorg_to(&hash);
+        self.head = Some(hash);
+        self.length = height;
+
+        Ok(reorg)
     }
-}
 
-impl TryFrom<Vec<Block>> for Blockchain
-{
-    type Error = String;
+    /// accumulated work of the branch ending in `block`
+    fn work_after(&self, block: &Block) -> u128
+    {
+        self.work_after_header(block.header())
+    }
 
-    fn try_from(blks: Vec<Block>) -> Result<Self, Self::Error>
+    fn work_after_header(&self, header: &BlockHeader) -> u128
     {
-        let mut blkchain = Blockchain::new();
-        let mut map: HashMap<&Vec<u8>, &Block> = HashMap::new();
+        match self.index.get(header.hash_prev())
+        {
+            Some(prev) => prev.work + header.work(),
+            None => header.work(),
+        }
+    }
+
+    /// add a stored block to the block tree without moving the head,
+    /// returns its height and the accumulated work of its branch
+    fn link(&mut self, header: &BlockHeader) -> (u64, u128)
+    {
+        let hash = header.hash();
+        let prev = header.hash_prev();
+
+        let height = match self.index.get(prev)
+        {
+            Some(prev) => prev.height + 1,
+            None => 1,
+        };
+        let work = self.work_after_header(header);
+
+        if prev.is_empty()
+        {
+            self.genesis = Some(hash.clone());
+        }
+
+        self.tips.remove(prev);
+        self.tips.insert(hash.clone());
+        self.index.insert(
+            hash,
+            BlockIndex {
+                height,
+                work,
+                header: header.clone(),
+            },
+        );
 
-        for blk in &blks
+        (height, work)
+    }
+
+    /// compute the blocks which change when moving the head to `hash`
+    fn reorg_to(&self, hash: &[u8]) -> Reorg
+    {
+        let mut reorg = Reorg::default();
+
+        let mut old = self.head.clone();
+        let mut new = Some(hash.to_vec());
+
+        // walk both branches down to the common ancestor
+        loop
         {
-            if blk.hash_prev().len() == 0
+            let old_height = old.as_ref().map_or(0, |h| self.index[h].height);
+            let new_height = new.as_ref().map_or(0, |h| self.index[h].height);
+
+            if old == new
             {
-                if blkchain.len() == 0
-                {
-                    if let Err((e, _)) = blkchain.append_block(blk.clone())
-                    {
-                        return Err(format!(
-                            "failed to insert blk: {} with error {e}",
-                            blk.hash_str()
-                        ));
-                    }
-                }
-                else
-                {
-                    return Err(String::from("multiple genesis blocks found"));
-                }
+                break;
+            }
+
+            if old_height >= new_height
+            {
+                let blk = self.block(old.as_ref().unwrap());
+                old = Some(blk.hash_prev().clone()).filter(|h| !h.is_empty());
+                reorg.disconnected.push(blk);
             }
-            else
+
+            if new_height >= old_height
             {
-                map.insert(blk.hash_prev(), &blk);
+                let blk = self.block(new.as_ref().unwrap());
+                new = Some(blk.hash_prev().clone()).filter(|h| !h.is_empty());
+                reorg.connected.push(blk);
             }
         }
 
-        if blkchain.len() == 0
+        reorg.connected.reverse();
+        reorg
+    }
+
+    /// order blocks or headers given in any order so parents come before
+    /// their children, those without a known predecessor are left out
+    fn parents_first<T>(blks: &[T], header: impl Fn(&T) -> &BlockHeader)
+        -> Result<Vec<&T>, String>
+    {
+        if blks.is_empty()
         {
-            return Err(String::from("no genesis block found"));
+            return Ok(vec![]);
+        }
+
+        let mut children: HashMap<&Vec<u8>, Vec<&T>> = HashMap::new();
+
+        for blk in blks
+        {
+            children
+                .entry(header(blk).hash_prev())
+                .or_default()
+                .push(blk);
         }
 
-        for _ in 1..
+        let empty = Vec::new();
+        let mut queue = match children.get(&empty)
         {
-            let head = blkchain.head().unwrap();
-            let mut next = map.get(&head.hash());
-            if let Some(&blk) = next.take()
+            Some(genesis) if genesis.len() > 1 =>
             {
-                if let Err((e, _)) = blkchain.append_block(blk.clone())
-                {
-                    return Err(format!(
-                        "failed to insert blk: {} with error {e}",
-                        blk.hash_str()
-                    ));
-                }
+                return Err(String::from("multiple genesis blocks found"))
             }
-            else
+            Some(genesis) => genesis.clone(),
+            None => return Err(String::from("no genesis block found")),
+        };
+
+        let mut ordered = vec![];
+        while let Some(blk) = queue.pop()
+        {
+            if let Some(next) = children.get(&header(blk).hash())
             {
-                break;
+                queue.extend(next);
             }
+            ordered.push(blk);
         }
+
+        Ok(ordered)
+    }
+
+    /// append blocks given in any order, parents are inserted before
+    /// their children and blocks without a known predecessor are ignored
+    pub fn insert_blocks(&mut self, blks: Vec<Block>) -> Result<(), String>
+    {
+        for blk in Blockchain::parents_first(&blks, Block::header)?
+        {
+            if let Err(e) = self.append_block(blk.clone())
+            {
+                return Err(format!(
+                    "failed to insert blk: {} with error {e}",
+                    blk.hash_str()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl HeaderLookup for Blockchain
+{
+    fn header(&self, hash: &[u8]) -> Option<&BlockHeader>
+    {
+        self.index.get(hash).map(|i| &i.header)
+    }
+
+    fn is_empty(&self) -> bool
+    {
+        self.index.is_empty()
+    }
+}
+
+impl TryFrom<Vec<Block>> for Blockchain
+{
+    type Error = String;
+
+    fn try_from(blks: Vec<Block>) -> Result<Self, Self::Error>
+    {
+        if blks.is_empty()
+        {
+            return Err(String::from("no genesis block found"));
+        }
+
+        let mut blkchain = Blockchain::new();
+        blkchain.insert_blocks(blks)?;
+
         Ok(blkchain)
     }
 }
@@ -187,7 +917,8 @@ mod tests
 {
     use super::*;
     use crate::core::address::Address;
-    use crate::core::crypto::generate_random_rsa_pair;
+    use crate::core::block::now;
+    use crate::core::crypto::{generate_random_rsa_pair, RsaPrivateKey};
     use crate::core::transaction::{Input, Output, Transaction};
 
     fn read_mock_address() -> Address
@@ -195,18 +926,18 @@ mod tests
         serde_json::from_str(&std::fs::read_to_string("etc/mock/address.json").unwrap()).unwrap()
     }
 
-    fn generate_random_transaction() -> Transaction
+    /// send `value` from the owner of `key` to a random recipient
+    fn signed_transaction(key: &RsaPrivateKey, value: u64, nonce: u64) -> Transaction
     {
-        let rsa = generate_random_rsa_pair();
-        let initiator = Address::with_key(rsa.to_public_key());
+        let initiator = Address::with_key(key.to_public_key());
 
         let recipient = Address::generate_random();
-        let inp = Input::new(initiator, 100);
-        let outp = Output::with_addrs(vec![(recipient, 10)]).unwrap();
+        let inp = Input::new(initiator, value, nonce);
+        let outp = Output::with_addrs(vec![(recipient, value)]).unwrap();
 
-        let mut trx = Transaction::new(inp.clone(), outp.clone());
+        let mut trx = Transaction::new(inp, outp, 0);
 
-        let sig = rsa
+        let sig = key
             .sign(
                 rsa::PaddingScheme::new_pkcs1v15_sign(None),
                 &trx.hash_ignore_sig(),
@@ -220,74 +951,540 @@ mod tests
     #[test]
     fn from_vec()
     {
-        let miner = read_mock_address();
-        let mut gen = Block::new(miner.clone());
-        let trx: Vec<Transaction> = (0..10).map(|_| generate_random_transaction()).collect();
+        let rsa = generate_random_rsa_pair();
+        let miner = Address::with_key(rsa.to_public_key());
+
+        let gen = mine(claim(Block::new(miner.clone())));
+
+        // spend the reward of the genesis block
+        let trx = signed_transaction(&rsa, 4, 0);
+        let mut second = Block::with_previous(miner.clone(), &gen);
+        second.add_transaction(trx.clone());
+        second.add_transaction(signed_transaction(&rsa, 3, 1));
+        let second = mine(claim(second));
 
-        for i in &trx
+        let mut third = Block::with_previous(miner.clone(), &second);
+        third.add_transaction(signed_transaction(&rsa, BLOCK_REWARD, 2));
+        let third = mine(claim(third));
+
+        let blkchain = Blockchain::try_from(vec![third, gen.clone(), second.clone()]).unwrap();
+        assert_eq!(blkchain.len(), 3);
+        assert_eq!(
+            blkchain.ledger().balance(&miner),
+            3 * BLOCK_REWARD - 4 - 3 - BLOCK_REWARD
+        );
+
+        // replaying a confirmed transaction is rejected
+        let mut replay = Block::with_previous(miner, &second);
+        replay.add_transaction(trx);
+        let replay = mine(replay);
+
+        let mut blkchain = Blockchain::try_from(vec![gen, second]).unwrap();
+        match blkchain.append_block(replay).unwrap_err()
         {
-            gen.add_transaction(i.clone());
+            ChainError::InvalidTransaction(_) => (),
+            e => panic!("unexpected error {e}"),
         }
-        loop
+    }
+
+    #[test]
+    fn with_stores()
+    {
+        let miner = Address::generate_random();
+
+        let gen = mine(claim(Block::new(miner.clone())));
+        let second = mine(claim(Block::with_previous(miner.clone(), &gen)));
+
+        let mut blocks = MemoryChainStore::new();
+        blocks.insert(second.clone()).unwrap();
+        blocks.insert(gen.clone()).unwrap();
+
+        // a state behind the head catches up with the stored chain
+        let mut stale = Ledger::new();
+        stale.apply_block(&gen).unwrap();
+        let mut state = MemoryStateStore::new();
+        state.commit(&gen.hash(), stale.clone()).unwrap();
+
+        let blkchain = Blockchain::with_stores(
+            ChainParams::default(),
+            Box::new(blocks.clone()),
+            Box::new(state.clone()),
+        )
+        .unwrap();
+        assert_eq!(blkchain.len(), 2);
+        assert_eq!(blkchain.head(), Some(second.clone()));
+        assert_eq!(blkchain.genesis(), Some(gen.clone()));
+        assert_eq!(blkchain.ledger().balance(&miner), 2 * BLOCK_REWARD);
+        assert_eq!(blkchain.get_blocks().count(), 2);
+
+        // a state of an unknown block is rebuilt from the genesis block
+        let mut unknown = MemoryStateStore::new();
+        unknown.commit(&[1, 2, 3], stale.clone()).unwrap();
+
+        let blkchain = Blockchain::with_stores(
+            ChainParams::default(),
+            Box::new(blocks.clone()),
+            Box::new(unknown),
+        )
+        .unwrap();
+        assert_eq!(blkchain.ledger().balance(&miner), 2 * BLOCK_REWARD);
+
+        // a state at the head is loaded without replaying any block
+        let mut current = MemoryStateStore::new();
+        current.commit(&second.hash(), stale.clone()).unwrap();
+
+        let blkchain =
+            Blockchain::with_stores(ChainParams::default(), Box::new(blocks), Box::new(current))
+                .unwrap();
+        assert_eq!(blkchain.ledger(), &stale);
+
+        // without blocks the state is reset
+        let blkchain = Blockchain::with_stores(
+            ChainParams::default(),
+            Box::new(MemoryChainStore::new()),
+            Box::new(state),
+        )
+        .unwrap();
+        assert!(blkchain.is_empty());
+        assert_eq!(blkchain.ledger(), &Ledger::new());
+    }
+
+    /// chain store refusing every block
+    struct FullStore;
+
+    impl ChainStore for FullStore
+    {
+        fn get(&self, _: &[u8]) -> Option<Block>
         {
-            if gen.hash_str().starts_with("000")
-            {
-                break;
-            }
-            gen.update_nounce()
+            None
         }
 
-        let mut second = Block::new(miner.clone());
-        for i in &trx
+        fn insert(&mut self, _: Block) -> Result<(), String>
         {
-            second.add_transaction(i.clone());
+            Err(String::from("no space left"))
         }
-        second.set_hash_prev(gen.hash());
-        loop
+
+        fn len(&self) -> usize
         {
-            if second.hash_str().starts_with("000")
+            0
+        }
+
+        fn blocks(&self) -> Box<dyn Iterator<Item = Block> + '_>
+        {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    #[test]
+    fn storage_failure()
+    {
+        let miner = Address::generate_random();
+        let gen = mine(claim(Block::new(miner.clone())));
+
+        let mut blkchain = Blockchain::with_stores(
+            ChainParams::default(),
+            Box::new(FullStore),
+            Box::new(MemoryStateStore::new()),
+        )
+        .unwrap();
+
+        match blkchain.append_block(gen).unwrap_err()
+        {
+            ChainError::Storage(_) => (),
+            e => panic!("unexpected error {e}"),
+        }
+
+        // the state stays at the previous head
+        assert!(blkchain.is_empty());
+        assert_eq!(blkchain.ledger(), &Ledger::new());
+        assert_eq!(blkchain.state.head(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn verify_block()
+    {
+        let rsa = generate_random_rsa_pair();
+        let miner = Address::with_key(rsa.to_public_key());
+
+        // the sender doesn't own anything
+        let mut blk = Block::new(read_mock_address());
+        blk.add_transaction(signed_transaction(&rsa, 1, 0));
+        let blk = mine(blk);
+
+        let mut blkchain = Blockchain::new();
+        match blkchain.append_block(blk).unwrap_err()
+        {
+            ChainError::InvalidTransaction(_) => (),
+            e => panic!("unexpected error {e}"),
+        }
+
+        // claiming more than the subsidy
+        let mut blk = Block::new(miner.clone());
+        blk.set_reward(BLOCK_REWARD + 1);
+        match blkchain.append_block(mine(blk)).unwrap_err()
+        {
+            ChainError::InvalidCoinbase(_) => (),
+            e => panic!("unexpected error {e}"),
+        }
+
+        // a body which doesn't match the header
+        let blk = mine(claim(Block::new(miner.clone())));
+        let mut json = serde_json::to_value(&blk).unwrap();
+        json["coinbase"]["output"]["value"] = (BLOCK_REWARD - 1).into();
+        let tampered: Block = serde_json::from_value(json).unwrap();
+        assert_eq!(tampered.hash(), blk.hash());
+        assert_eq!(
+            blkchain.append_block(tampered).unwrap_err(),
+            ChainError::InvalidMerkleRoot
+        );
+
+        let hash = blk.hash();
+        assert!(!blkchain.contains(&hash));
+
+        blkchain.append_block(blk).unwrap();
+        assert!(blkchain.contains(&hash));
+        assert_eq!(blkchain.ledger().balance(&miner), BLOCK_REWARD);
+
+        // blocks above the size limit
+        let mut small = Blockchain::with_params(ChainParams {
+            max_block_size: 100,
+            ..Default::default()
+        });
+        match small
+            .append_block(mine(claim(Block::new(miner))))
+            .unwrap_err()
+        {
+            ChainError::TooLarge { limit: 100, .. } => (),
+            e => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn reject_overspending()
+    {
+        let rsa = generate_random_rsa_pair();
+        let miner = Address::with_key(rsa.to_public_key());
+
+        let gen = mine(claim(Block::new(miner.clone())));
+        let mut blkchain = Blockchain::new();
+        blkchain.append_block(gen.clone()).unwrap();
+
+        let trx = signed_transaction(&rsa, BLOCK_REWARD + 1, 0);
+        blkchain.verify_transaction(&trx).unwrap_err();
+
+        // each transaction is covered on its own, but not both together
+        let mut blk = Block::with_previous(read_mock_address(), &gen);
+        blk.add_transaction(signed_transaction(&rsa, 6, 0));
+        blk.add_transaction(signed_transaction(&rsa, 6, 1));
+        let blk = mine(blk);
+
+        match blkchain.append_block(blk).unwrap_err()
+        {
+            ChainError::InvalidTransaction(_) => (),
+            e => panic!("unexpected error {e}"),
+        }
+        assert_eq!(blkchain.ledger().balance(&miner), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn retarget()
+    {
+        let params = ChainParams {
+            retarget_interval: 4,
+            target_block_time: 10,
+            ..Default::default()
+        };
+        let miner = read_mock_address();
+        let mut blkchain = Blockchain::with_params(params.clone());
+
+        // blocks arrive twice as fast as desired
+        let mut blk = Block::new(miner.clone());
+        for id in 0..4
+        {
+            if id > 0
             {
-                break;
+                blk = Block::with_previous(miner.clone(), &blk);
             }
-            second.update_nounce()
+            blk.set_timestamp(1000 + id * 5);
+            blk = mine(blk);
+            blkchain.append_block(blk.clone()).unwrap();
+            assert_eq!(blk.bits(), params.initial_bits);
         }
 
-        let mut third = Block::new(miner.clone());
-        for i in &trx
+        let bits = blkchain.next_bits(blkchain.head().as_ref());
+        assert_eq!(bits, 0x1f07_ffff);
+
+        // keeping the old target is rejected
+        let mut next = Block::with_previous(miner.clone(), &blk);
+        next.set_timestamp(1020);
+        match blkchain.append_block(mine(next.clone())).unwrap_err()
         {
-            third.add_transaction(i.clone());
+            ChainError::InvalidBits { expected, .. } => assert_eq!(expected, bits),
+            e => panic!("unexpected error {e}"),
         }
-        third.set_hash_prev(second.hash());
-        loop
+
+        next.set_bits(bits);
+        let next = mine(next);
+        blkchain.append_block(next.clone()).unwrap();
+        assert_eq!(blkchain.work(), 4 * blk.work() + next.work());
+        assert!(next.work() > blk.work());
+    }
+
+    #[test]
+    fn headers_after_taller_side_branch()
+    {
+        let params = ChainParams {
+            retarget_interval: 4,
+            target_block_time: 10,
+            ..Default::default()
+        };
+        let miner = read_mock_address();
+        let mut blkchain = Blockchain::with_params(params);
+
+        let mut gen = Block::new(miner.clone());
+        gen.set_timestamp(1000);
+        let gen = mine(gen);
+        blkchain.append_block(gen.clone()).unwrap();
+
+        let extend = |chain: &mut Blockchain, prev: &Block, spacing: u64| {
+            let mut blk = Block::with_previous(miner.clone(), prev);
+            blk.set_timestamp(prev.timestamp() + spacing);
+            blk.set_bits(chain.next_bits(Some(prev)));
+            let blk = mine(blk);
+            chain.append_block(blk.clone()).unwrap();
+            blk
+        };
+
+        // fast blocks raise the difficulty of the best chain
+        let mut best = gen.clone();
+        for _ in 0..5
+        {
+            best = extend(&mut blkchain, &best, 5);
+        }
+
+        // a longer branch of slow blocks has less work
+        let mut side = gen.clone();
+        for _ in 0..6
+        {
+            side = extend(&mut blkchain, &side, 20);
+        }
+
+        assert_eq!(blkchain.head(), Some(best.clone()));
+        assert!(side.id() > best.id());
+
+        let headers = blkchain.headers_after(&[side.hash()], 16);
+        assert_eq!(headers.len(), 6);
+        assert_eq!(headers[0], *gen.header());
+
+        let headers = blkchain.headers_after(&[side.hash(), gen.hash()], 16);
+        assert_eq!(headers.len(), 5);
+    }
+
+    #[test]
+    fn headers_sync()
+    {
+        let miner = read_mock_address();
+        let mut full = Blockchain::new();
+        let mut fresh = Blockchain::new();
+
+        let mut blk = mine(Block::new(miner.clone()));
+        for id in 0..30
         {
-            if third.hash_str().starts_with("000")
+            if id > 0
             {
-                break;
+                blk = mine(Block::with_previous(miner.clone(), &blk));
+            }
+            full.append_block(blk.clone()).unwrap();
+
+            if id < 2
+            {
+                fresh.append_block(blk.clone()).unwrap();
             }
-            third.update_nounce()
         }
 
-        let _blkchain = Blockchain::try_from(vec![gen, second, third]).unwrap();
+        let locator = full.locator();
+        assert_eq!(locator.first(), Some(&full.head().unwrap().hash()));
+        assert_eq!(locator.last(), Some(&full.best_chain()[0].hash()));
+        assert!(locator.len() < 20);
+
+        // an empty chain starts at the genesis block
+        assert_eq!(full.headers_after(&[], 5).len(), 5);
+
+        let headers = full.headers_after(&fresh.locator(), 16);
+        assert_eq!(headers.len(), 16);
+        assert_eq!(headers[0].height(), 2);
+
+        let missing = fresh.missing_blocks(&headers).unwrap();
+        assert_eq!(missing.len(), 16);
+
+        for hash in missing
+        {
+            fresh.append_block(full.get_block(&hash).unwrap()).unwrap();
+        }
+
+        let headers = full.headers_after(&fresh.locator(), 16);
+        assert_eq!(headers.len(), 12);
+        assert_eq!(fresh.missing_blocks(&headers).unwrap().len(), 12);
+
+        // headers have to extend a known block
+        assert_eq!(
+            fresh.missing_blocks(&headers[1..]).unwrap_err(),
+            ChainError::UnknownPredecessor
+        );
+        assert_eq!(
+            fresh
+                .missing_blocks_after(Some(&headers[0]), &headers[1..])
+                .unwrap()
+                .len(),
+            11
+        );
     }
 
     #[test]
-    fn verify_block()
+    fn timestamps()
     {
-        let trx = generate_random_transaction();
-
         let miner = read_mock_address();
-        let mut blk = Block::new(miner);
-        blk.add_transaction(trx);
-        loop
+        let mut blkchain = Blockchain::new();
+
+        let mut blk = Block::new(miner.clone());
+        for id in 0..11
         {
-            if blk.hash_str().starts_with("000")
+            if id > 0
             {
-                break;
+                blk = Block::with_previous(miner.clone(), &blk);
+            }
+            blk.set_timestamp(1000 + id * 10);
+            blk = mine(blk);
+            blkchain.append_block(blk.clone()).unwrap();
+        }
+
+        assert_eq!(blkchain.median_time_past(&blk.hash()), 1050);
+        assert_eq!(blkchain.blocks_between(1000, 1030).len(), 3);
+
+        let mut next = Block::with_previous(miner.clone(), &blk);
+        next.set_timestamp(1050);
+        assert_eq!(
+            blkchain.append_block(mine(next.clone())).unwrap_err(),
+            ChainError::TimestampTooOld {
+                median: 1050,
+                found: 1050
             }
+        );
+
+        let future = now() + ChainParams::default().max_future_drift + 60;
+        next.set_timestamp(future);
+        match blkchain.append_block(mine(next.clone())).unwrap_err()
+        {
+            ChainError::TimestampTooNew { found, .. } => assert_eq!(found, future),
+            e => panic!("unexpected error {e}"),
+        }
+
+        // may be older than the predecessor
+        next.set_timestamp(1051);
+        blkchain.append_block(mine(next)).unwrap();
+    }
+
+    const BLOCK_REWARD: u64 = 10;
+
+    /// claim the full subsidy of the default chain parameters
+    fn claim(mut blk: Block) -> Block
+    {
+        blk.set_reward(ChainParams::default().subsidy(blk.id()));
+        blk
+    }
+
+    fn mine(mut blk: Block) -> Block
+    {
+        while !blk.check_pow()
+        {
             blk.update_nounce()
         }
+        blk
+    }
+
+    #[test]
+    fn fork_and_reorg()
+    {
+        let miner = read_mock_address();
+        let other = Address::generate_random();
+
+        let gen = mine(Block::new(miner.clone()));
+        let a = mine(Block::with_previous(miner.clone(), &gen));
+        let b = mine(Block::with_previous(other.clone(), &gen));
+        let c = mine(Block::with_previous(other, &b));
 
         let mut blkchain = Blockchain::new();
-        blkchain.append_block(blk).unwrap();
+        let reorg = blkchain.append_block(gen.clone()).unwrap();
+        assert_eq!(reorg.connected, vec![gen.clone()]);
+
+        let reorg = blkchain.append_block(a.clone()).unwrap();
+        assert_eq!(reorg.connected, vec![a.clone()]);
+        assert!(reorg.disconnected.is_empty());
+
+        // same work as the current head, stays a side branch
+        let reorg = blkchain.append_block(b.clone()).unwrap();
+        assert!(reorg.is_empty());
+        assert_eq!(blkchain.head().unwrap(), a);
+        assert_eq!(blkchain.tips().len(), 2);
+
+        let reorg = blkchain.append_block(c.clone()).unwrap();
+        assert_eq!(reorg.disconnected, vec![a]);
+        assert_eq!(reorg.connected, vec![b.clone(), c.clone()]);
+        assert_eq!(blkchain.head().unwrap(), c);
+        assert_eq!(blkchain.len(), 3);
+        assert_eq!(
+            blkchain.best_chain(),
+            vec![gen.clone(), b.clone(), c.clone()]
+        );
+
+        blkchain.append_block(c).unwrap_err();
+    }
+
+    #[test]
+    fn reject_unlinked_blocks()
+    {
+        let miner = read_mock_address();
+        let gen = mine(Block::new(miner.clone()));
+
+        let mut blkchain = Blockchain::new();
+        blkchain.append_block(gen.clone()).unwrap();
+
+        assert_eq!(
+            blkchain.append_block(gen.clone()).unwrap_err(),
+            ChainError::KnownBlock
+        );
+
+        let orphan = mine(Block::with_previous(
+            miner.clone(),
+            &Block::new(Address::generate_random()),
+        ));
+        assert_eq!(
+            blkchain.append_block(orphan).unwrap_err(),
+            ChainError::UnknownPredecessor
+        );
+
+        let mut wrong_id = Block::new(miner.clone());
+        wrong_id.set_hash_prev(gen.hash());
+        assert_eq!(
+            blkchain.append_block(mine(wrong_id)).unwrap_err(),
+            ChainError::InvalidId {
+                expected: 1,
+                found: 0
+            }
+        );
+
+        let mut bad_pow = Block::with_previous(miner, &gen);
+        while bad_pow.check_pow()
+        {
+            bad_pow.update_nounce();
+        }
+        assert_eq!(
+            blkchain.append_block(bad_pow).unwrap_err(),
+            ChainError::InvalidPow
+        );
+
+        // the stored block is exactly the one that was appended
+        assert_eq!(blkchain.head().unwrap(), gen);
+        assert_eq!(blkchain.head().unwrap().hash(), gen.hash());
     }
 }
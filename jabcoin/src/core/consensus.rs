@@ -0,0 +1,158 @@
+use crate::core::block::now;
+use crate::core::blockchain::ChainError;
+use crate::core::difficulty::retarget;
+use crate::core::header::BlockHeader;
+use crate::core::params::ChainParams;
+
+/// number of headers the median time past is computed from
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// a tree of block headers the consensus rules are checked against
+pub trait HeaderLookup
+{
+    fn header(&self, hash: &[u8]) -> Option<&BlockHeader>;
+
+    /// true if the tree doesn't hold any header yet
+    fn is_empty(&self) -> bool;
+}
+
+/// median timestamp of the header with the given hash and
+/// its 10 predecessors, 0 for unknown headers
+pub fn median_time_past<T: HeaderLookup + ?Sized>(tree: &T, hash: &[u8]) -> u64
+{
+    let mut times = vec![];
+    let mut next = tree.header(hash);
+
+    while let Some(header) = next.filter(|_| times.len() < MEDIAN_TIME_SPAN)
+    {
+        times.push(header.timestamp());
+        next = tree.header(header.hash_prev());
+    }
+
+    times.sort_unstable();
+    times.get(times.len() / 2).copied().unwrap_or(0)
+}
+
+/// compact target required for a successor of `prev`,
+/// the genesis block uses the initial target
+pub fn next_bits<T: HeaderLookup + ?Sized>(
+    params: &ChainParams,
+    tree: &T,
+    prev: Option<&BlockHeader>,
+) -> u32
+{
+    let prev = match prev
+    {
+        Some(prev) => prev,
+        None => return params.initial_bits,
+    };
+
+    let height = prev.height() + 1;
+    if height % params.retarget_interval != 0
+    {
+        return prev.bits();
+    }
+
+    // first header of the period which just ended
+    let mut first = prev;
+    while first.height() > height - params.retarget_interval
+    {
+        first = tree
+            .header(first.hash_prev())
+            .expect("predecessors of known headers are known");
+    }
+
+    retarget(params, prev.bits(), first.timestamp(), prev.timestamp())
+}
+
+/// heights of the best chain of length `len` a locator refers to, starting at
+/// the tip and getting sparser towards the first block, which is always included
+pub fn locator_heights(len: usize) -> Vec<usize>
+{
+    let mut heights = vec![];
+    let mut step = 1;
+    let mut i = len;
+
+    while i > 0
+    {
+        heights.push(i - 1);
+
+        if heights.len() >= 10
+        {
+            step *= 2;
+        }
+        i = i.saturating_sub(step);
+    }
+
+    if len > 0 && heights.last() != Some(&0)
+    {
+        heights.push(0);
+    }
+    heights
+}
+
+/// check the linkage, timestamp, target and proof-of-work of a header
+/// about to be added to the tree, returns its predecessor
+pub fn verify_header<'a, T: HeaderLookup + ?Sized>(
+    params: &ChainParams,
+    tree: &'a T,
+    header: &BlockHeader,
+) -> Result<Option<&'a BlockHeader>, ChainError>
+{
+    let prev = match header.hash_prev().is_empty()
+    {
+        true if !tree.is_empty() => return Err(ChainError::MultipleGenesis),
+        true => None,
+        false => Some(
+            tree.header(header.hash_prev())
+                .ok_or(ChainError::UnknownPredecessor)?,
+        ),
+    };
+
+    let expected = prev.map_or(0, |p| p.height() + 1);
+    if header.height() != expected
+    {
+        return Err(ChainError::InvalidId {
+            expected,
+            found: header.height(),
+        });
+    }
+
+    if prev.is_some()
+    {
+        let median = median_time_past(tree, header.hash_prev());
+
+        if header.timestamp() <= median
+        {
+            return Err(ChainError::TimestampTooOld {
+                median,
+                found: header.timestamp(),
+            });
+        }
+    }
+
+    let limit = now().saturating_add(params.max_future_drift);
+    if header.timestamp() > limit
+    {
+        return Err(ChainError::TimestampTooNew {
+            limit,
+            found: header.timestamp(),
+        });
+    }
+
+    let expected = next_bits(params, tree, prev);
+    if header.bits() != expected
+    {
+        return Err(ChainError::InvalidBits {
+            expected,
+            found: header.bits(),
+        });
+    }
+
+    if !header.check_pow()
+    {
+        return Err(ChainError::InvalidPow);
+    }
+
+    Ok(prev)
+}
@@ -0,0 +1,112 @@
+use crate::core::crypto::BigUint;
+use crate::core::params::ChainParams;
+
+/// decode the compact representation of a target,
+/// the highest byte is the length of the target in bytes
+/// and the lower three bytes are its most significant digits
+pub fn target_from_bits(bits: u32) -> BigUint
+{
+    let exponent = (bits >> 24) as usize;
+    let mantissa = BigUint::from(bits & 0x007f_ffff);
+
+    if exponent <= 3
+    {
+        mantissa >> (8 * (3 - exponent))
+    }
+    else
+    {
+        mantissa << (8 * (exponent - 3))
+    }
+}
+
+/// encode a target into its compact representation, rounding down
+pub fn bits_from_target(target: &BigUint) -> u32
+{
+    let mut exponent = target.bits().div_ceil(8);
+
+    let shifted = if exponent <= 3
+    {
+        target << (8 * (3 - exponent))
+    }
+    else
+    {
+        target >> (8 * (exponent - 3))
+    };
+
+    let mut mantissa = shifted
+        .to_bytes_be()
+        .iter()
+        .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+
+    // the highest bit of the mantissa is reserved as a sign bit
+    if mantissa & 0x0080_0000 != 0
+    {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+
+    ((exponent as u32) << 24) | mantissa
+}
+
+/// compact target of the period following one which started at `first` and ended
+/// at `last`, the adjustment is limited to a factor of 4 in both directions
+pub fn retarget(params: &ChainParams, bits: u32, first: u64, last: u64) -> u32
+{
+    let expected = params.target_block_time * (params.retarget_interval - 1);
+    let actual = last
+        .saturating_sub(first)
+        .clamp((expected / 4).max(1), expected * 4);
+
+    let target = target_from_bits(bits) * BigUint::from(actual) / BigUint::from(expected);
+    let limit = target_from_bits(params.initial_bits);
+
+    bits_from_target(&target.min(limit))
+}
+
+/// expected number of hashes needed to find a hash below the target,
+/// saturates for targets too small to be represented
+pub fn work_from_bits(bits: u32) -> u128
+{
+    let space = BigUint::from(1u32) << 256;
+    let work = space / (target_from_bits(bits) + BigUint::from(1u32));
+    let bytes = work.to_bytes_be();
+
+    if bytes.len() > 16
+    {
+        return u128::MAX;
+    }
+
+    bytes.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn compact_roundtrip()
+    {
+        for bits in [0x1f0f_ffff, 0x1d00_ffff, 0x0312_3456, 0x1e7f_ffff]
+        {
+            assert_eq!(bits_from_target(&target_from_bits(bits)), bits);
+        }
+
+        // 3 leading zero hex digits
+        let target = target_from_bits(0x1f0f_ffff);
+        assert_eq!(target.bits(), 244);
+
+        // the sign bit is never set
+        let target = BigUint::from(0x80u32) << 240;
+        assert_eq!(bits_from_target(&target), 0x2000_8000);
+        assert_eq!(target_from_bits(0x2000_8000), target);
+    }
+
+    #[test]
+    fn work()
+    {
+        assert_eq!(work_from_bits(0x1f0f_ffff), 4096);
+        assert!(work_from_bits(0x1f07_ffff) > work_from_bits(0x1f0f_ffff));
+        assert_eq!(work_from_bits(0x0100_0001), u128::MAX);
+    }
+}
@@ -0,0 +1,481 @@
+// canonical binary encoding used for hashing, the wire and the disk.
+// integers are written as fixed width big-endian numbers, variable
+// length data is prefixed with its length as u32 and optional values
+// with a tag byte. every value has exactly one encoding.
+
+use crate::core::crypto::BigUint;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// reasons for rejecting encoded data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError
+{
+    /// the data ended in the middle of a value
+    UnexpectedEnd,
+
+    /// the data continues after the value
+    TrailingBytes(usize),
+
+    /// a tag byte has no meaning at its position
+    InvalidTag(u8),
+
+    /// a length exceeds the data left or a limit of the type
+    InvalidLength(usize),
+
+    /// the decoded fields don't form a valid value
+    InvalidValue(String),
+}
+
+impl fmt::Display for DecodeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            DecodeError::TrailingBytes(n) => write!(f, "{n} trailing byte(s)"),
+            DecodeError::InvalidTag(tag) => write!(f, "invalid tag {tag}"),
+            DecodeError::InvalidLength(len) => write!(f, "invalid length {len}"),
+            DecodeError::InvalidValue(e) => write!(f, "invalid value: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait Encode
+{
+    /// append the encoding of `self` to `buf`
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+pub trait Decode: Sized
+{
+    /// read a value from the front of `reader`
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    /// decode a value which has to span all of `data`
+    fn from_bytes(data: &[u8]) -> Result<Self, DecodeError>
+    {
+        let mut reader = Reader::new(data);
+        let value = Self::decode(&mut reader)?;
+
+        match reader.remaining()
+        {
+            0 => Ok(value),
+            n => Err(DecodeError::TrailingBytes(n)),
+        }
+    }
+}
+
+/// cursor over encoded data
+pub struct Reader<'a>
+{
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a>
+{
+    pub fn new(data: &'a [u8]) -> Reader<'a>
+    {
+        Reader { data }
+    }
+
+    pub fn remaining(&self) -> usize
+    {
+        self.data.len()
+    }
+
+    /// consume the next `len` bytes
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError>
+    {
+        if len > self.data.len()
+        {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError>
+    {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// read a length prefix, which can't exceed the data left
+    /// as every element takes up at least one byte
+    pub fn take_len(&mut self) -> Result<usize, DecodeError>
+    {
+        let len = u32::decode(self)? as usize;
+
+        if len > self.remaining()
+        {
+            return Err(DecodeError::InvalidLength(len));
+        }
+        Ok(len)
+    }
+}
+
+fn encode_len(len: usize, buf: &mut Vec<u8>)
+{
+    let len = u32::try_from(len).expect("length exceeds the encoding");
+    len.encode(buf);
+}
+
+impl Encode for u8
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(reader.take(1)?[0])
+    }
+}
+
+impl Encode for u16
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Decode for u16
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(u16::from_be_bytes(reader.take_array()?))
+    }
+}
+
+impl Encode for u32
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Decode for u32
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(u32::from_be_bytes(reader.take_array()?))
+    }
+}
+
+impl Encode for u64
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Decode for u64
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(u64::from_be_bytes(reader.take_array()?))
+    }
+}
+
+impl Encode for bool
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        buf.push(*self as u8);
+    }
+}
+
+impl Decode for bool
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        match u8::decode(reader)?
+        {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for String
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        encode_len(self.len(), buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        let len = reader.take_len()?;
+        let bytes = reader.take(len)?.to_vec();
+
+        String::from_utf8(bytes).map_err(|e| DecodeError::InvalidValue(e.to_string()))
+    }
+}
+
+/// the number of elements followed by the elements
+impl<T: Encode> Encode for [T]
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        encode_len(self.len(), buf);
+
+        for i in self
+        {
+            i.encode(buf);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T>
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.as_slice().encode(buf);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T>
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        let len = reader.take_len()?;
+        (0..len).map(|_| T::decode(reader)).collect()
+    }
+}
+
+impl<T: Encode> Encode for Option<T>
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        match self
+        {
+            Some(value) =>
+            {
+                buf.push(1);
+                value.encode(buf);
+            }
+            None => buf.push(0),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T>
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        match u8::decode(reader)?
+        {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(reader)?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B)
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B)
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+/// big-endian digits without leading zeros
+impl Encode for BigUint
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        let bytes = if *self == BigUint::from(0u32)
+        {
+            vec![]
+        }
+        else
+        {
+            self.to_bytes_be()
+        };
+        bytes.encode(buf);
+    }
+}
+
+impl Decode for BigUint
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        let bytes = Vec::<u8>::decode(reader)?;
+
+        if bytes.first() == Some(&0)
+        {
+            return Err(DecodeError::InvalidValue(String::from(
+                "number with leading zeros",
+            )));
+        }
+        Ok(BigUint::from_bytes_be(&bytes))
+    }
+}
+
+/// a tag for the family followed by the octets
+impl Encode for IpAddr
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        match self
+        {
+            IpAddr::V4(ip) =>
+            {
+                buf.push(4);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) =>
+            {
+                buf.push(6);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+}
+
+impl Decode for IpAddr
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        match u8::decode(reader)?
+        {
+            4 => Ok(IpAddr::V4(Ipv4Addr::from(reader.take_array::<4>()?))),
+            6 =>
+            {
+                let ip = Ipv6Addr::from(reader.take_array::<16>()?);
+
+                // ipv4 addresses have a single encoding
+                match ip.to_ipv4_mapped()
+                {
+                    Some(_) => Err(DecodeError::InvalidValue(format!(
+                        "ipv4-mapped address {ip}"
+                    ))),
+                    None => Ok(IpAddr::V6(ip)),
+                }
+            }
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for SocketAddr
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.ip().encode(buf);
+        self.port().encode(buf);
+    }
+}
+
+impl Decode for SocketAddr
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(SocketAddr::new(
+            IpAddr::decode(reader)?,
+            u16::decode(reader)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn roundtrip<T: Encode + Decode + PartialEq + fmt::Debug>(value: T)
+    {
+        let bytes = value.to_bytes();
+        assert_eq!(T::from_bytes(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn primitives()
+    {
+        roundtrip(0xabu8);
+        roundtrip(0xbeefu16);
+        roundtrip(0xdead_beefu32);
+        roundtrip(u64::MAX);
+        roundtrip(true);
+        roundtrip(String::from("jabcoin"));
+        roundtrip(vec![vec![1u8, 2], vec![], vec![3]]);
+        roundtrip(Some(7u64));
+        roundtrip(None::<u64>);
+        roundtrip((1u32, vec![2u8]));
+        roundtrip(BigUint::from(0u32));
+        roundtrip(BigUint::from(65537u32));
+        roundtrip(SocketAddr::from(([10, 0, 0, 1], 27182)));
+        roundtrip("[2001:db8::1]:27182".parse::<SocketAddr>().unwrap());
+
+        assert_eq!(258u32.to_bytes(), vec![0, 0, 1, 2]);
+        assert_eq!(vec![1u8, 2].to_bytes(), vec![0, 0, 0, 2, 1, 2]);
+    }
+
+    #[test]
+    fn unambiguous()
+    {
+        // the length prefixes keep differently split data apart
+        let a = (vec![1u8], vec![2u8, 3]).to_bytes();
+        let b = (vec![1u8, 2], vec![3u8]).to_bytes();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn reject_malformed()
+    {
+        assert_eq!(u64::from_bytes(&[0; 7]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(u32::from_bytes(&[0; 5]), Err(DecodeError::TrailingBytes(1)));
+        assert_eq!(bool::from_bytes(&[2]), Err(DecodeError::InvalidTag(2)));
+        assert_eq!(
+            Option::<u8>::from_bytes(&[3, 0]),
+            Err(DecodeError::InvalidTag(3))
+        );
+
+        // lengths beyond the data are rejected before allocating
+        assert_eq!(
+            Vec::<u64>::from_bytes(&[0xff, 0xff, 0xff, 0xff]),
+            Err(DecodeError::InvalidLength(u32::MAX as usize))
+        );
+
+        // numbers and addresses have a single encoding
+        assert!(BigUint::from_bytes(&[0, 0, 0, 2, 0, 1]).is_err());
+
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(IpAddr::from_bytes(&mapped.to_bytes()).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::core::crypto::{BigUint, Sha256Hash};
+use crate::core::difficulty::{target_from_bits, work_from_bits};
+use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// version of the block format
+pub const BLOCK_VERSION: u32 = 1;
+
+/// fixed size part of a block, the proof-of-work is computed over it alone
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BlockHeader
+{
+    version: u32,
+    hash_prev: Vec<u8>,
+
+    /// root of the merkle tree over the coinbase and the transactions
+    merkle_root: Vec<u8>,
+
+    /// seconds since the unix epoch at which the block was created
+    timestamp: u64,
+
+    /// compact target the hash of the block has to satisfy
+    bits: u32,
+    nounce: u64,
+
+    /// position of the block in its chain, starting at 0
+    height: u64,
+}
+
+impl BlockHeader
+{
+    pub fn new(
+        hash_prev: Vec<u8>,
+        merkle_root: Vec<u8>,
+        timestamp: u64,
+        bits: u32,
+        height: u64,
+    ) -> BlockHeader
+    {
+        BlockHeader {
+            version: BLOCK_VERSION,
+            hash_prev,
+            merkle_root,
+            timestamp,
+            bits,
+            nounce: 0,
+            height,
+        }
+    }
+
+    pub fn version(&self) -> u32
+    {
+        self.version
+    }
+
+    pub fn hash_prev(&self) -> &Vec<u8>
+    {
+        &self.hash_prev
+    }
+
+    pub fn set_hash_prev(&mut self, hash: Vec<u8>)
+    {
+        self.hash_prev = hash;
+    }
+
+    pub fn merkle_root(&self) -> &Vec<u8>
+    {
+        &self.merkle_root
+    }
+
+    pub fn set_merkle_root(&mut self, root: Vec<u8>)
+    {
+        self.merkle_root = root;
+    }
+
+    pub fn timestamp(&self) -> u64
+    {
+        self.timestamp
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u64)
+    {
+        self.timestamp = timestamp;
+    }
+
+    pub fn bits(&self) -> u32
+    {
+        self.bits
+    }
+
+    pub fn set_bits(&mut self, bits: u32)
+    {
+        self.bits = bits;
+    }
+
+    pub fn nounce(&self) -> u64
+    {
+        self.nounce
+    }
+
+    pub fn set_nounce(&mut self, nounce: u64)
+    {
+        self.nounce = nounce;
+    }
+
+    pub fn height(&self) -> u64
+    {
+        self.height
+    }
+
+    /// check whether the hash of the header, read as a number,
+    /// doesn't exceed the target
+    pub fn check_pow(&self) -> bool
+    {
+        BigUint::from_bytes_be(&self.hash()) <= target_from_bits(self.bits)
+    }
+
+    /// expected number of hashes needed to find the proof-of-work
+    pub fn work(&self) -> u128
+    {
+        work_from_bits(self.bits)
+    }
+}
+
+impl Sha256Hash for BlockHeader
+{
+    fn hash(&self) -> Vec<u8>
+    {
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
+
+impl Encode for BlockHeader
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.version.encode(buf);
+        self.hash_prev.encode(buf);
+        self.merkle_root.encode(buf);
+        self.timestamp.encode(buf);
+        self.bits.encode(buf);
+        self.nounce.encode(buf);
+        self.height.encode(buf);
+    }
+}
+
+impl Decode for BlockHeader
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(BlockHeader {
+            version: u32::decode(reader)?,
+            hash_prev: Vec::decode(reader)?,
+            merkle_root: Vec::decode(reader)?,
+            timestamp: u64::decode(reader)?,
+            bits: u32::decode(reader)?,
+            nounce: u64::decode(reader)?,
+            height: u64::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn hash_covers_all_fields()
+    {
+        let header = BlockHeader::new(vec![1; 32], vec![2; 32], 1000, 0x1f0f_ffff, 1);
+        let hash = header.hash();
+
+        let mut other = header.clone();
+        other.set_nounce(1);
+        assert_ne!(other.hash(), hash);
+
+        let mut other = header.clone();
+        other.set_merkle_root(vec![3; 32]);
+        assert_ne!(other.hash(), hash);
+
+        let mut other = header.clone();
+        other.set_timestamp(1001);
+        assert_ne!(other.hash(), hash);
+
+        let mut other = header;
+        other.set_bits(0x1f07_ffff);
+        assert_ne!(other.hash(), hash);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::core::blockchain::ChainError;
+use crate::core::consensus::{locator_heights, verify_header, HeaderLookup};
+use crate::core::crypto::Sha256Hash;
+use crate::core::header::BlockHeader;
+use crate::core::params::ChainParams;
+use std::collections::HashMap;
+
+/// a header and the accumulated work of the branch ending in it
+struct Entry
+{
+    header: BlockHeader,
+    work: u128,
+}
+
+/// the tree of block headers without their bodies, as kept by light clients.
+/// headers are checked for their linkage and proof-of-work only, the
+/// transactions are trusted to be valid if enough work has been spent on them
+pub struct HeaderChain
+{
+    params: ChainParams,
+    headers: HashMap<Vec<u8>, Entry>,
+
+    /// hashes of the best chain, indexed by height
+    best: Vec<Vec<u8>>,
+}
+
+impl Default for HeaderChain
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl HeaderChain
+{
+    pub fn new() -> HeaderChain
+    {
+        HeaderChain::with_params(ChainParams::default())
+    }
+
+    pub fn with_params(params: ChainParams) -> HeaderChain
+    {
+        HeaderChain {
+            params,
+            headers: HashMap::new(),
+            best: vec![],
+        }
+    }
+
+    /// number of headers of the best chain
+    pub fn len(&self) -> u64
+    {
+        self.best.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.best.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.headers.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&BlockHeader>
+    {
+        self.headers.get(hash).map(|e| &e.header)
+    }
+
+    /// last header of the best chain
+    pub fn tip(&self) -> Option<&BlockHeader>
+    {
+        self.best.last().and_then(|h| self.get(h))
+    }
+
+    /// accumulated work of the best chain
+    pub fn work(&self) -> u128
+    {
+        self.best.last().map_or(0, |h| self.headers[h].work)
+    }
+
+    /// true if the header with the given hash is part of the best chain
+    pub fn is_best(&self, hash: &[u8]) -> bool
+    {
+        self.get(hash)
+            .and_then(|h| self.best.get(h.height() as usize))
+            .is_some_and(|best| best == hash)
+    }
+
+    /// number of headers of the best chain from the given one up to the tip,
+    /// 0 for headers which are unknown or not part of the best chain
+    pub fn confirmations(&self, hash: &[u8]) -> u64
+    {
+        match self.is_best(hash)
+        {
+            true => self.len() - self.headers[hash].header.height(),
+            false => 0,
+        }
+    }
+
+    /// height of the last header the branch ending in `hash` has in
+    /// common with the best chain, None for unknown headers
+    pub fn fork_point(&self, hash: &[u8]) -> Option<u64>
+    {
+        let mut header = self.get(hash)?;
+
+        while !self.is_best(&header.hash())
+        {
+            header = self.get(header.hash_prev())?;
+        }
+        Some(header.height())
+    }
+
+    /// hashes of headers of the best chain, starting at the tip and getting
+    /// sparser towards the first header, which is always included
+    pub fn locator(&self) -> Vec<Vec<u8>>
+    {
+        locator_heights(self.best.len())
+            .into_iter()
+            .map(|i| self.best[i].clone())
+            .collect()
+    }
+
+    fn verify(&self, header: &BlockHeader) -> Result<u128, ChainError>
+    {
+        let work = match verify_header(&self.params, self, header)?
+        {
+            Some(_) => self.headers[header.hash_prev()].work,
+            None => 0,
+        };
+
+        Ok(work.saturating_add(header.work()))
+    }
+
+    /// add a header extending a known one, the branch with
+    /// the most work becomes the best chain
+    pub fn add(&mut self, header: BlockHeader) -> Result<(), ChainError>
+    {
+        let hash = header.hash();
+
+        if self.contains(&hash)
+        {
+            return Err(ChainError::KnownBlock);
+        }
+
+        let work = self.verify(&header)?;
+        let height = header.height() as usize;
+        self.headers.insert(hash.clone(), Entry { header, work });
+
+        if work <= self.work()
+        {
+            return Ok(());
+        }
+
+        // replace the best chain from the fork point on
+        let mut branch = vec![hash];
+        while !self.is_best(branch.last().unwrap()) && branch.len() <= height
+        {
+            let prev = self.headers[branch.last().unwrap()].header.hash_prev();
+            branch.push(prev.clone());
+        }
+
+        if self.is_best(branch.last().unwrap())
+        {
+            branch.pop();
+        }
+
+        self.best.truncate(height + 1 - branch.len());
+        self.best.extend(branch.into_iter().rev());
+        Ok(())
+    }
+}
+
+impl HeaderLookup for HeaderChain
+{
+    fn header(&self, hash: &[u8]) -> Option<&BlockHeader>
+    {
+        self.get(hash)
+    }
+
+    fn is_empty(&self) -> bool
+    {
+        self.headers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::core::block::now;
+
+    fn mine(mut header: BlockHeader) -> BlockHeader
+    {
+        while !header.check_pow()
+        {
+            header.set_nounce(header.nounce() + 1);
+        }
+        header
+    }
+
+    fn child(prev: &BlockHeader, root: u8) -> BlockHeader
+    {
+        mine(BlockHeader::new(
+            prev.hash(),
+            vec![root; 32],
+            prev.timestamp() + 30,
+            prev.bits(),
+            prev.height() + 1,
+        ))
+    }
+
+    fn genesis() -> BlockHeader
+    {
+        let bits = ChainParams::default().initial_bits;
+        mine(BlockHeader::new(vec![], vec![0; 32], now() - 3600, bits, 0))
+    }
+
+    #[test]
+    fn reject_invalid()
+    {
+        let gen = genesis();
+        let mut chain = HeaderChain::new();
+        chain.add(gen.clone()).unwrap();
+        assert_eq!(chain.add(gen.clone()), Err(ChainError::KnownBlock));
+
+        let mut second = gen.clone();
+        second.set_merkle_root(vec![1; 32]);
+        assert_eq!(chain.add(mine(second)), Err(ChainError::MultipleGenesis));
+
+        let orphan = child(&child(&gen, 1), 2);
+        assert_eq!(chain.add(orphan), Err(ChainError::UnknownPredecessor));
+
+        let mut unmined = child(&gen, 1);
+        while unmined.check_pow()
+        {
+            unmined.set_nounce(unmined.nounce() + 1);
+        }
+        assert_eq!(chain.add(unmined), Err(ChainError::InvalidPow));
+
+        // an easier target than required is rejected even with a valid pow
+        let mut easy = child(&gen, 1);
+        easy.set_bits(0x2000_ffff);
+        assert!(matches!(
+            chain.add(mine(easy)),
+            Err(ChainError::InvalidBits { .. })
+        ));
+
+        let mut old = child(&gen, 1);
+        old.set_timestamp(gen.timestamp());
+        assert!(matches!(
+            chain.add(mine(old)),
+            Err(ChainError::TimestampTooOld { .. })
+        ));
+
+        let high = mine(BlockHeader::new(
+            gen.hash(),
+            vec![1; 32],
+            gen.timestamp() + 30,
+            gen.bits(),
+            5,
+        ));
+        assert!(matches!(chain.add(high), Err(ChainError::InvalidId { .. })));
+
+        assert_eq!(chain.len(), 1);
+    }
+
+    #[test]
+    fn follow_most_work()
+    {
+        let gen = genesis();
+        let a1 = child(&gen, 1);
+        let a2 = child(&a1, 1);
+        let b1 = child(&gen, 2);
+        let b2 = child(&b1, 2);
+        let b3 = child(&b2, 2);
+
+        let mut chain = HeaderChain::new();
+        for header in [&gen, &a1, &a2, &b1, &b2]
+        {
+            chain.add(header.clone()).unwrap();
+        }
+
+        // the first branch seen wins a tie
+        assert_eq!(chain.tip(), Some(&a2));
+        assert_eq!(chain.confirmations(&a1.hash()), 2);
+        assert_eq!(chain.confirmations(&b1.hash()), 0);
+
+        chain.add(b3.clone()).unwrap();
+        assert_eq!(chain.tip(), Some(&b3));
+        assert_eq!(chain.len(), 4);
+        assert_eq!(chain.confirmations(&a1.hash()), 0);
+        assert_eq!(chain.confirmations(&b1.hash()), 3);
+        assert_eq!(chain.confirmations(&gen.hash()), 4);
+        assert_eq!(chain.fork_point(&a2.hash()), Some(0));
+
+        let locator = chain.locator();
+        assert_eq!(locator.first(), Some(&b3.hash()));
+        assert_eq!(locator.last(), Some(&gen.hash()));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::core::crypto::Sha256Hash;
+use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::core::{Address, Block, Transaction};
+use std::collections::HashMap;
+
+/// account state resulting from applying a chain of blocks
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ledger
+{
+    balances: HashMap<Address, u64>,
+
+    // number of confirmed transactions sent from an address
+    nonces: HashMap<Address, u64>,
+}
+
+impl Ledger
+{
+    pub fn new() -> Ledger
+    {
+        Ledger {
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+        }
+    }
+
+    pub fn balance(&self, addr: &Address) -> u64
+    {
+        *self.balances.get(addr).unwrap_or(&0)
+    }
+
+    pub fn balances(&self) -> &HashMap<Address, u64>
+    {
+        &self.balances
+    }
+
+    /// nonce the next transaction sent from `addr` has to carry
+    pub fn next_nonce(&self, addr: &Address) -> u64
+    {
+        *self.nonces.get(addr).unwrap_or(&0)
+    }
+
+    fn credit(&mut self, addr: &Address, value: u64) -> Result<(), String>
+    {
+        let balance = self.balances.entry(addr.clone()).or_insert(0);
+
+        match balance.checked_add(value)
+        {
+            Some(v) =>
+            {
+                *balance = v;
+                Ok(())
+            }
+            None => Err(String::from("balance overflow")),
+        }
+    }
+
+    /// move the value of a transaction from its sender to its recipients,
+    /// returns the fee paid to the miner
+    pub fn apply_transaction(&mut self, trx: &Transaction) -> Result<u64, String>
+    {
+        let inp = trx.input();
+        let expected = self.next_nonce(inp.get_addr());
+
+        if inp.get_nonce() != expected
+        {
+            return Err(format!(
+                "invalid nonce in transaction {}: expected {expected}, found {}",
+                trx.hash_str(),
+                inp.get_nonce()
+            ));
+        }
+
+        let balance = self.balance(inp.get_addr());
+
+        if balance < inp.get_value()
+        {
+            return Err(format!(
+                "insufficient balance for transaction {}: {balance} < {}",
+                trx.hash_str(),
+                inp.get_value()
+            ));
+        }
+
+        let spent = trx
+            .output()
+            .total_value()
+            .and_then(|out| out.checked_add(trx.fee()));
+
+        if spent != Some(inp.get_value())
+        {
+            return Err(format!(
+                "outputs and fee don't match the input of transaction {}",
+                trx.hash_str()
+            ));
+        }
+
+        self.balances
+            .insert(inp.get_addr().clone(), balance - inp.get_value());
+
+        for actor in trx.output().transactors()
+        {
+            self.credit(actor.get_addr(), actor.get_value())?;
+        }
+
+        self.nonces.insert(inp.get_addr().clone(), expected + 1);
+        Ok(trx.fee())
+    }
+
+    /// apply all transactions and the coinbase of a block, leaves the
+    /// ledger untouched if any of them is invalid. returns the collected fees,
+    /// checking the value of the coinbase is up to the caller
+    pub fn apply_block(&mut self, blk: &Block) -> Result<u64, String>
+    {
+        let mut next = self.clone();
+        let mut fees: u64 = 0;
+
+        for trx in blk.transactions()
+        {
+            let fee = next.apply_transaction(trx)?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or_else(|| String::from("fee overflow"))?;
+        }
+
+        let coinbase = blk.coinbase();
+        next.credit(coinbase.get_addr(), coinbase.get_value())?;
+
+        *self = next;
+        Ok(fees)
+    }
+}
+
+/// entries sorted by address, so equal ledgers are encoded equally
+fn encode_map(map: &HashMap<Address, u64>, buf: &mut Vec<u8>)
+{
+    let mut entries: Vec<(Vec<u8>, u64)> = map.iter().map(|(a, v)| (a.to_bytes(), *v)).collect();
+    entries.sort_unstable();
+
+    (entries.len() as u32).encode(buf);
+    for (addr, value) in entries
+    {
+        buf.extend_from_slice(&addr);
+        value.encode(buf);
+    }
+}
+
+fn decode_map(reader: &mut Reader) -> Result<HashMap<Address, u64>, DecodeError>
+{
+    Ok(Vec::<(Address, u64)>::decode(reader)?.into_iter().collect())
+}
+
+impl Encode for Ledger
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        encode_map(&self.balances, buf);
+        encode_map(&self.nonces, buf);
+    }
+}
+
+impl Decode for Ledger
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Ledger {
+            balances: decode_map(reader)?,
+            nonces: decode_map(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::core::{Input, Output};
+
+    fn transaction(addr: &Address, value: u64, nonce: u64) -> Transaction
+    {
+        let out = Output::with_addrs(vec![(Address::generate_random(), value)]).unwrap();
+        Transaction::new(Input::new(addr.clone(), value, nonce), out, 0)
+    }
+
+    const REWARD: u64 = 10;
+
+    fn funded(addr: &Address) -> Ledger
+    {
+        let mut blk = Block::new(addr.clone());
+        blk.set_reward(REWARD);
+
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&blk).unwrap();
+        ledger
+    }
+
+    #[test]
+    fn sequential_nonces()
+    {
+        let addr = Address::generate_random();
+        let mut ledger = funded(&addr);
+
+        assert_eq!(ledger.next_nonce(&addr), 0);
+        ledger.apply_transaction(&transaction(&addr, 1, 0)).unwrap();
+        ledger.apply_transaction(&transaction(&addr, 1, 1)).unwrap();
+        assert_eq!(ledger.next_nonce(&addr), 2);
+
+        // replay and gaps are rejected
+        ledger
+            .apply_transaction(&transaction(&addr, 1, 1))
+            .unwrap_err();
+        ledger
+            .apply_transaction(&transaction(&addr, 1, 3))
+            .unwrap_err();
+        assert_eq!(ledger.next_nonce(&addr), 2);
+    }
+
+    #[test]
+    fn balances()
+    {
+        let miner = Address::generate_random();
+        let mut ledger = funded(&miner);
+        assert_eq!(ledger.balance(&miner), REWARD);
+
+        let recipient = Address::generate_random();
+        let out = Output::with_addrs(vec![(recipient.clone(), 6)]).unwrap();
+        let trx = Transaction::new(Input::new(miner.clone(), 8, 0), out.clone(), 2);
+
+        assert_eq!(ledger.apply_transaction(&trx).unwrap(), 2);
+        assert_eq!(ledger.balance(&miner), REWARD - 8);
+        assert_eq!(ledger.balance(&recipient), 6);
+
+        // value not covered by outputs and fee is no implicit fee
+        let trx = Transaction::new(Input::new(miner.clone(), 1, 1), out, 0);
+        ledger.apply_transaction(&trx).unwrap_err();
+
+        // overspending
+        ledger
+            .apply_transaction(&transaction(&miner, REWARD, 1))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn apply_block_is_atomic()
+    {
+        let addr = Address::generate_random();
+        let ledger = funded(&addr);
+
+        // the second transaction overspends
+        let mut blk = Block::new(Address::generate_random());
+        blk.add_transaction(transaction(&addr, REWARD - 1, 0));
+        blk.add_transaction(transaction(&addr, 2, 1));
+
+        let mut next = ledger.clone();
+        next.apply_block(&blk).unwrap_err();
+        assert_eq!(next, ledger);
+    }
+
+    #[test]
+    fn fees_go_to_the_coinbase()
+    {
+        let sender = Address::generate_random();
+        let miner = Address::generate_random();
+        let ledger = funded(&sender);
+
+        let out = Output::with_addrs(vec![(Address::generate_random(), 5)]).unwrap();
+        let mut blk = Block::new(miner.clone());
+        blk.add_transaction(Transaction::new(Input::new(sender.clone(), 7, 0), out, 2));
+        blk.set_reward(2);
+
+        let mut next = ledger.clone();
+        assert_eq!(next.apply_block(&blk).unwrap(), 2);
+        assert_eq!(next.balance(&sender), REWARD - 7);
+        assert_eq!(next.balance(&miner), 2);
+    }
+}
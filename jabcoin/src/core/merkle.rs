@@ -0,0 +1,279 @@
+use crate::core::crypto::Sha256Hash;
+use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::core::{BlockHeader, Transaction};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// prefixes keeping leaves and inner nodes apart,
+// so an inner node can't be passed off as a transaction
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+fn hash_leaf(leaf: &[u8]) -> Vec<u8>
+{
+    let mut hasher = Sha256::new();
+
+    hasher.update([LEAF]);
+    hasher.update(leaf);
+    hasher.finalize().to_vec()
+}
+
+fn hash_node(left: &[u8], right: &[u8]) -> Vec<u8>
+{
+    let mut hasher = Sha256::new();
+
+    hasher.update([NODE]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+/// combine pairs of nodes into the next level of the tree,
+/// an odd node at the end is carried up unchanged
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>>
+{
+    level
+        .chunks(2)
+        .map(|pair| match pair
+        {
+            [left, right] => hash_node(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// root of the merkle tree over the given leaves, e.g. transaction hashes
+pub fn merkle_root(leaves: &[Vec<u8>]) -> Vec<u8>
+{
+    if leaves.is_empty()
+    {
+        return Sha256::new().finalize().to_vec();
+    }
+
+    let mut level: Vec<Vec<u8>> = leaves.iter().map(|l| hash_leaf(l)).collect();
+
+    while level.len() > 1
+    {
+        level = next_level(&level);
+    }
+
+    level.pop().unwrap()
+}
+
+/// sibling of a node on the path from a leaf to the root
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MerkleStep
+{
+    hash: Vec<u8>,
+
+    /// true if the sibling is the left node of the pair
+    left: bool,
+}
+
+/// proof that a leaf is part of a merkle tree
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MerkleProof
+{
+    steps: Vec<MerkleStep>,
+}
+
+impl MerkleProof
+{
+    /// build the proof for the leaf at `index`, None if out of range
+    pub fn new(leaves: &[Vec<u8>], mut index: usize) -> Option<MerkleProof>
+    {
+        if index >= leaves.len()
+        {
+            return None;
+        }
+
+        let mut steps = vec![];
+        let mut level: Vec<Vec<u8>> = leaves.iter().map(|l| hash_leaf(l)).collect();
+
+        while level.len() > 1
+        {
+            let sibling = index ^ 1;
+
+            // an odd node without sibling is carried up
+            if sibling < level.len()
+            {
+                steps.push(MerkleStep {
+                    hash: level[sibling].clone(),
+                    left: sibling < index,
+                });
+            }
+
+            level = next_level(&level);
+            index /= 2;
+        }
+
+        Some(MerkleProof { steps })
+    }
+
+    pub fn steps(&self) -> &Vec<MerkleStep>
+    {
+        &self.steps
+    }
+
+    /// compute the root the proof leads to, starting at `leaf`
+    pub fn root_from(&self, leaf: &[u8]) -> Vec<u8>
+    {
+        self.steps.iter().fold(hash_leaf(leaf), |acc, step| {
+            if step.left
+            {
+                hash_node(&step.hash, &acc)
+            }
+            else
+            {
+                hash_node(&acc, &step.hash)
+            }
+        })
+    }
+
+    /// check that `trx` is part of the tree with the given root
+    pub fn verify(&self, trx: &Transaction, root: &[u8]) -> bool
+    {
+        self.root_from(&trx.hash()) == root
+    }
+}
+
+impl Encode for MerkleStep
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.hash.encode(buf);
+        self.left.encode(buf);
+    }
+}
+
+impl Decode for MerkleStep
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(MerkleStep {
+            hash: Vec::decode(reader)?,
+            left: bool::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for MerkleProof
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.steps.encode(buf);
+    }
+}
+
+impl Decode for MerkleProof
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(MerkleProof {
+            steps: Vec::decode(reader)?,
+        })
+    }
+}
+
+/// a transaction together with the proof that a block contains it,
+/// lets light clients check it against the header of the block
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TransactionProof
+{
+    /// hash of the block containing the transaction
+    pub block: Vec<u8>,
+    pub transaction: Transaction,
+    pub proof: MerkleProof,
+}
+
+impl TransactionProof
+{
+    /// check the proof against the header of the block it names
+    pub fn verify(&self, header: &BlockHeader) -> bool
+    {
+        header.hash() == self.block && self.proof.verify(&self.transaction, header.merkle_root())
+    }
+}
+
+impl Encode for TransactionProof
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.block.encode(buf);
+        self.transaction.encode(buf);
+        self.proof.encode(buf);
+    }
+}
+
+impl Decode for TransactionProof
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(TransactionProof {
+            block: Vec::decode(reader)?,
+            transaction: Transaction::decode(reader)?,
+            proof: MerkleProof::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<Vec<u8>>
+    {
+        (0..count).map(|i| Sha256::digest([i]).to_vec()).collect()
+    }
+
+    #[test]
+    fn root()
+    {
+        let four = leaves(4);
+        let expected = hash_node(
+            &hash_node(&hash_leaf(&four[0]), &hash_leaf(&four[1])),
+            &hash_node(&hash_leaf(&four[2]), &hash_leaf(&four[3])),
+        );
+        assert_eq!(merkle_root(&four), expected);
+
+        // the odd leaf is carried up instead of being duplicated
+        let three = leaves(3);
+        let expected = hash_node(
+            &hash_node(&hash_leaf(&three[0]), &hash_leaf(&three[1])),
+            &hash_leaf(&three[2]),
+        );
+        assert_eq!(merkle_root(&three), expected);
+
+        assert_eq!(merkle_root(&leaves(1)), hash_leaf(&leaves(1)[0]));
+    }
+
+    #[test]
+    fn proofs()
+    {
+        for count in 1..12
+        {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+
+            for (i, leaf) in leaves.iter().enumerate()
+            {
+                let proof = MerkleProof::new(&leaves, i).unwrap();
+                assert_eq!(proof.root_from(leaf), root);
+
+                // the proof doesn't hold for any other leaf
+                let other = &leaves[(i + 1) % leaves.len()];
+                if other != leaf
+                {
+                    assert_ne!(proof.root_from(other), root);
+                }
+            }
+
+            assert!(MerkleProof::new(&leaves, leaves.len()).is_none());
+        }
+
+        let proof = MerkleProof::new(&leaves(7), 5).unwrap();
+        assert_eq!(MerkleProof::from_bytes(&proof.to_bytes()), Ok(proof));
+    }
+}
@@ -0,0 +1,84 @@
+/// consensus parameters shared by all nodes of a network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams
+{
+    /// value a miner may create with the coinbase of the first blocks
+    pub initial_subsidy: u64,
+
+    /// number of blocks after which the subsidy is halved
+    pub halving_interval: u64,
+
+    /// maximum number of bytes of a serialized block
+    pub max_block_size: usize,
+
+    /// compact target of the genesis block, also the easiest target allowed
+    pub initial_bits: u32,
+
+    /// number of blocks after which the target is adjusted, at least 2
+    pub retarget_interval: u64,
+
+    /// desired number of seconds between two blocks
+    pub target_block_time: u64,
+
+    /// number of seconds the timestamp of a block may be ahead of the local clock
+    pub max_future_drift: u64,
+}
+
+impl Default for ChainParams
+{
+    fn default() -> Self
+    {
+        ChainParams {
+            initial_subsidy: 10,
+            halving_interval: 10_000,
+            max_block_size: 1_000_000,
+
+            // hashes with 3 leading zero hex digits
+            initial_bits: 0x1f0f_ffff,
+            retarget_interval: 100,
+            target_block_time: 30,
+            max_future_drift: 2 * 60 * 60,
+        }
+    }
+}
+
+impl ChainParams
+{
+    /// newly created value a miner may claim at the given height
+    pub fn subsidy(&self, height: u64) -> u64
+    {
+        let halvings = height / self.halving_interval;
+
+        if halvings >= u64::BITS as u64
+        {
+            0
+        }
+        else
+        {
+            self.initial_subsidy >> halvings
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn halving()
+    {
+        let params = ChainParams {
+            initial_subsidy: 50,
+            halving_interval: 100,
+            ..Default::default()
+        };
+
+        assert_eq!(params.subsidy(0), 50);
+        assert_eq!(params.subsidy(99), 50);
+        assert_eq!(params.subsidy(100), 25);
+        assert_eq!(params.subsidy(250), 12);
+        assert_eq!(params.subsidy(100 * 64), 0);
+        assert_eq!(params.subsidy(u64::MAX), 0);
+    }
+}
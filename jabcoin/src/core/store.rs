@@ -0,0 +1,120 @@
+use crate::core::crypto::Sha256Hash;
+use crate::core::{Block, Ledger};
+use std::collections::HashMap;
+
+/// backend holding the blocks of a block tree, blocks are handed out by
+/// value so backends don't have to keep them in memory
+pub trait ChainStore
+{
+    fn get(&self, hash: &[u8]) -> Option<Block>;
+
+    fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.get(hash).is_some()
+    }
+
+    /// store a block, storing a known block again has no effect
+    fn insert(&mut self, block: Block) -> Result<(), String>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// all stored blocks in no particular order
+    fn blocks(&self) -> Box<dyn Iterator<Item = Block> + '_>;
+}
+
+/// backend holding the account state at the head of a chain
+pub trait StateStore
+{
+    fn ledger(&self) -> &Ledger;
+
+    /// hash of the block the state belongs to, empty without blocks
+    fn head(&self) -> &[u8];
+
+    /// replace the stored account state by the one after the block `head`
+    fn commit(&mut self, head: &[u8], ledger: Ledger) -> Result<(), String>;
+}
+
+/// keeps all blocks in memory only
+#[derive(Clone, Debug, Default)]
+pub struct MemoryChainStore
+{
+    blocks: HashMap<Vec<u8>, Block>,
+}
+
+impl MemoryChainStore
+{
+    pub fn new() -> MemoryChainStore
+    {
+        MemoryChainStore::default()
+    }
+}
+
+impl ChainStore for MemoryChainStore
+{
+    fn get(&self, hash: &[u8]) -> Option<Block>
+    {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.blocks.contains_key(hash)
+    }
+
+    fn insert(&mut self, block: Block) -> Result<(), String>
+    {
+        self.blocks.entry(block.hash()).or_insert(block);
+        Ok(())
+    }
+
+    fn len(&self) -> usize
+    {
+        self.blocks.len()
+    }
+
+    fn blocks(&self) -> Box<dyn Iterator<Item = Block> + '_>
+    {
+        Box::new(self.blocks.values().cloned())
+    }
+}
+
+/// keeps the account state in memory only
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStateStore
+{
+    head: Vec<u8>,
+    ledger: Ledger,
+}
+
+impl MemoryStateStore
+{
+    pub fn new() -> MemoryStateStore
+    {
+        MemoryStateStore::default()
+    }
+}
+
+impl StateStore for MemoryStateStore
+{
+    fn ledger(&self) -> &Ledger
+    {
+        &self.ledger
+    }
+
+    fn head(&self) -> &[u8]
+    {
+        &self.head
+    }
+
+    fn commit(&mut self, head: &[u8], ledger: Ledger) -> Result<(), String>
+    {
+        self.head = head.to_vec();
+        self.ledger = ledger;
+        Ok(())
+    }
+}
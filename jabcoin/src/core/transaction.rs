@@ -1,5 +1,6 @@
 use crate::core::address::Address;
 use crate::core::crypto::Sha256Hash;
+use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
@@ -32,15 +33,93 @@ impl Sha256Hash for Transactor
 {
     fn hash(&self) -> Vec<u8>
     {
-        let mut hasher = Sha256::new();
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
 
-        hasher.update(self.value.to_be_bytes());
-        hasher.update(self.addr.hash());
-        hasher.finalize().to_vec()
+impl Encode for Transactor
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.addr.encode(buf);
+        self.value.encode(buf);
     }
 }
 
-pub type Input = Transactor;
+impl Decode for Transactor
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Transactor::new(
+            Address::decode(reader)?,
+            u64::decode(reader)?,
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Input
+{
+    addr: Address,
+    value: u64,
+
+    /// position of the transaction among all transactions sent from `addr`,
+    /// starting at 0
+    nonce: u64,
+}
+
+impl Input
+{
+    pub fn new(addr: Address, value: u64, nonce: u64) -> Input
+    {
+        Input { addr, value, nonce }
+    }
+
+    pub fn get_addr(&self) -> &Address
+    {
+        &self.addr
+    }
+
+    pub fn get_value(&self) -> u64
+    {
+        self.value
+    }
+
+    pub fn get_nonce(&self) -> u64
+    {
+        self.nonce
+    }
+}
+
+impl Sha256Hash for Input
+{
+    fn hash(&self) -> Vec<u8>
+    {
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
+
+impl Encode for Input
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.addr.encode(buf);
+        self.value.encode(buf);
+        self.nonce.encode(buf);
+    }
+}
+
+impl Decode for Input
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Input::new(
+            Address::decode(reader)?,
+            u64::decode(reader)?,
+            u64::decode(reader)?,
+        ))
+    }
+}
 
 const MAX_OUT_ADDRESSES: usize = 100;
 
@@ -50,6 +129,14 @@ pub struct Output
     addrs: Vec<Transactor>,
 }
 
+impl Default for Output
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
 impl Output
 {
     pub fn new() -> Output
@@ -78,20 +165,43 @@ impl Output
     {
         &self.addrs
     }
+
+    /// sum of all output values, None on overflow
+    pub fn total_value(&self) -> Option<u64>
+    {
+        self.addrs
+            .iter()
+            .try_fold(0u64, |acc, trxactor| acc.checked_add(trxactor.value))
+    }
 }
 
 impl Sha256Hash for Output
 {
     fn hash(&self) -> Vec<u8>
     {
-        let mut hasher = Sha256::new();
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
+
+impl Encode for Output
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.addrs.encode(buf);
+    }
+}
+
+impl Decode for Output
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        let addrs = Vec::<Transactor>::decode(reader)?;
 
-        for i in &self.addrs
+        if addrs.len() > MAX_OUT_ADDRESSES
         {
-            hasher.update(&i.addr.hash()[..]);
-            hasher.update(i.value.to_be_bytes());
+            return Err(DecodeError::InvalidLength(addrs.len()));
         }
-        hasher.finalize().to_vec()
+        Ok(Output { addrs })
     }
 }
 
@@ -100,46 +210,46 @@ pub struct Transaction
 {
     input: Input,
     output: Output,
+
+    /// value paid to the miner, the input has to cover
+    /// exactly the outputs plus the fee
+    fee: u64,
     signature: Option<Vec<u8>>,
 }
 
 impl Transaction
 {
-    pub fn new(input: Input, output: Output) -> Transaction
+    pub fn new(input: Input, output: Output, fee: u64) -> Transaction
     {
         Transaction {
             input,
             output,
+            fee,
             signature: None,
         }
     }
 
-    pub fn with_signature(input: Input, output: Output, signature: Vec<u8>) -> Transaction
+    pub fn with_signature(input: Input, output: Output, fee: u64, signature: Vec<u8>)
+        -> Transaction
     {
         Transaction {
             input,
             output,
+            fee,
             signature: Some(signature),
         }
     }
 
     pub fn check_validity(&self) -> bool
     {
-        if let None = self.signature
+        if self.signature.is_none()
         {
             return false;
         }
-        if let Ok(_) = self
-            .input
+        self.input
             .get_addr()
-            .verify_data(&self.hash_ignore_sig(), &self.signature().unwrap())
-        {
-            true
-        }
-        else
-        {
-            false
-        }
+            .verify_data(&self.hash_ignore_sig(), self.signature().unwrap())
+            .is_ok()
     }
 
     /// returns a hash of the transaction but without
@@ -148,10 +258,23 @@ impl Transaction
     {
         // a bit inefficient because of the cloning,
         // should be fine for now though
-        let tmp = Transaction::new(self.input.clone(), self.output.clone());
+        let tmp = Transaction::new(self.input.clone(), self.output.clone(), self.fee);
         tmp.hash()
     }
 
+    /// number of bytes the transaction occupies in a block
+    pub fn size(&self) -> usize
+    {
+        self.to_bytes().len()
+    }
+
+    /// true if `self` pays a higher fee per byte than `other`
+    pub fn pays_more_than(&self, other: &Transaction) -> bool
+    {
+        // compare fee / size without rounding
+        self.fee as u128 * other.size() as u128 > other.fee as u128 * self.size() as u128
+    }
+
     pub fn input(&self) -> &Input
     {
         &self.input
@@ -162,6 +285,11 @@ impl Transaction
         &self.output
     }
 
+    pub fn fee(&self) -> u64
+    {
+        self.fee
+    }
+
     pub fn set_signature(&mut self, sig: Vec<u8>)
     {
         self.signature.replace(sig);
@@ -171,27 +299,121 @@ impl Transaction
     {
         match &self.signature
         {
-            Some(a) => Some(&a),
+            Some(a) => Some(a),
             None => None,
         }
     }
+
+    /// true if `addr` sends or receives value with the transaction
+    pub fn involves(&self, addr: &Address) -> bool
+    {
+        self.input.get_addr() == addr
+            || self
+                .output
+                .transactors()
+                .iter()
+                .any(|t| t.get_addr() == addr)
+    }
 }
 
 impl Sha256Hash for Transaction
 {
     fn hash(&self) -> Vec<u8>
     {
-        let mut hasher = Sha256::new();
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
 
-        hasher.update(&self.input.hash()[..]);
-        hasher.update(&self.output.hash()[..]);
+impl Encode for Transaction
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.input.encode(buf);
+        self.output.encode(buf);
+        self.fee.encode(buf);
+        self.signature.encode(buf);
+    }
+}
 
-        if let Some(sig) = &self.signature
-        {
-            hasher.update(&sig[..]);
+impl Decode for Transaction
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Transaction {
+            input: Input::decode(reader)?,
+            output: Output::decode(reader)?,
+            fee: u64::decode(reader)?,
+            signature: Option::decode(reader)?,
+        })
+    }
+}
+
+/// transaction creating new coins for the miner of a block,
+/// it has no input and therefore no signature
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Coinbase
+{
+    /// id of the block, keeps coinbases of the same miner distinct
+    height: u64,
+    output: Transactor,
+}
+
+impl Coinbase
+{
+    pub fn new(height: u64, miner: Address, value: u64) -> Coinbase
+    {
+        Coinbase {
+            height,
+            output: Transactor::new(miner, value),
         }
+    }
 
-        hasher.finalize().to_vec()
+    pub fn height(&self) -> u64
+    {
+        self.height
+    }
+
+    pub fn get_addr(&self) -> &Address
+    {
+        self.output.get_addr()
+    }
+
+    pub fn get_value(&self) -> u64
+    {
+        self.output.get_value()
+    }
+
+    pub fn set_value(&mut self, value: u64)
+    {
+        self.output.value = value;
+    }
+}
+
+impl Sha256Hash for Coinbase
+{
+    fn hash(&self) -> Vec<u8>
+    {
+        Sha256::digest(self.to_bytes()).to_vec()
+    }
+}
+
+impl Encode for Coinbase
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.height.encode(buf);
+        self.output.encode(buf);
+    }
+}
+
+impl Decode for Coinbase
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Coinbase {
+            height: u64::decode(reader)?,
+            output: Transactor::decode(reader)?,
+        })
     }
 }
 
@@ -205,7 +427,7 @@ mod tests
     fn hash_input()
     {
         let addr = Address::generate_random();
-        let input = Input::new(addr, 123);
+        let input = Input::new(addr, 123, 0);
 
         println!("{}", input.hash_str());
     }
@@ -228,7 +450,7 @@ mod tests
     fn hash_transaction()
     {
         let addr = Address::generate_random();
-        let input = Input::new(addr, 123);
+        let input = Input::new(addr, 123, 0);
 
         let mut v = vec![];
         for i in 0..1
@@ -238,7 +460,7 @@ mod tests
         }
         let output = Output::with_addrs(v).unwrap();
 
-        let trx = Transaction::new(input, output);
+        let trx = Transaction::new(input, output, 0);
         println!("{}", trx.hash_str());
     }
 
@@ -250,14 +472,14 @@ mod tests
 
         // generate input address
         let addr_input = Address::with_key(rsa.to_public_key());
-        let inp = Input::new(addr_input, 10);
+        let inp = Input::new(addr_input, 10, 0);
 
         // generate output addr
         let rsa2 = generate_random_rsa_pair();
         let addr_output = Address::with_key(rsa2.to_public_key());
         let out = Output::with_addrs(vec![(addr_output, 10)]).unwrap();
 
-        let mut trx = Transaction::new(inp, out);
+        let mut trx = Transaction::new(inp, out, 0);
 
         let hash = trx.hash();
 
@@ -267,7 +489,7 @@ mod tests
         );
         trx.input
             .addr
-            .verify_data(&hash, &trx.signature.as_ref().unwrap())
+            .verify_data(&hash, trx.signature.as_ref().unwrap())
             .unwrap();
 
         // manipulate transaction
@@ -278,7 +500,86 @@ mod tests
         // the hash
         trx.input
             .addr
-            .verify_data(&hash, &trx.signature.as_ref().unwrap())
+            .verify_data(&hash, trx.signature.as_ref().unwrap())
             .unwrap_err();
     }
+
+    #[test]
+    fn nonce_is_signed()
+    {
+        let addr = Address::generate_random();
+        let out = Output::with_addrs(vec![(Address::generate_random(), 10)]).unwrap();
+
+        let first = Transaction::new(Input::new(addr.clone(), 10, 0), out.clone(), 0);
+        let second = Transaction::new(Input::new(addr, 10, 1), out, 0);
+
+        assert_ne!(first.hash_ignore_sig(), second.hash_ignore_sig());
+    }
+
+    #[test]
+    fn fee_is_signed()
+    {
+        let addr = Address::generate_random();
+        let out = Output::with_addrs(vec![(Address::generate_random(), 8)]).unwrap();
+
+        let first = Transaction::new(Input::new(addr.clone(), 10, 0), out.clone(), 2);
+        let second = Transaction::new(Input::new(addr, 10, 0), out, 1);
+
+        assert_ne!(first.hash_ignore_sig(), second.hash_ignore_sig());
+        assert!(first.pays_more_than(&second));
+        assert!(!second.pays_more_than(&first));
+    }
+
+    #[test]
+    fn hash_coinbase()
+    {
+        let addr = Address::generate_random();
+        let mut first = Coinbase::new(1, addr.clone(), 10);
+        let second = Coinbase::new(2, addr, 10);
+        assert_ne!(first.hash(), second.hash());
+
+        let hash = first.hash();
+        first.set_value(5);
+        assert_ne!(first.hash(), hash);
+    }
+
+    #[test]
+    fn encode_roundtrip()
+    {
+        let data = std::fs::read_to_string("etc/mock/transaction.json").unwrap();
+        let trx = serde_json::from_str::<Transaction>(&data).unwrap();
+
+        let decoded = Transaction::from_bytes(&trx.to_bytes()).unwrap();
+        assert_eq!(decoded, trx);
+        assert!(decoded.check_validity());
+
+        let unsigned = Transaction::new(trx.input().clone(), trx.output().clone(), trx.fee());
+        assert_eq!(
+            Transaction::from_bytes(&unsigned.to_bytes()).unwrap(),
+            unsigned
+        );
+        assert_ne!(unsigned.to_bytes(), trx.to_bytes());
+
+        let coinbase = Coinbase::new(7, Address::generate_random(), 10);
+        assert_eq!(
+            Coinbase::from_bytes(&coinbase.to_bytes()).unwrap(),
+            coinbase
+        );
+    }
+
+    #[test]
+    fn output_limit()
+    {
+        let addr = Address::generate_random();
+        let mut buf = vec![];
+
+        // an output can't be decoded with more addresses than it can be built with
+        let addrs = vec![Transactor::new(addr, 1); MAX_OUT_ADDRESSES + 1];
+        addrs.encode(&mut buf);
+
+        assert_eq!(
+            Output::from_bytes(&buf),
+            Err(DecodeError::InvalidLength(MAX_OUT_ADDRESSES + 1))
+        );
+    }
 }
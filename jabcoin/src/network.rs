@@ -1,10 +1,37 @@
-pub use protocol::{Header, Message};
+pub use protocol::{
+    Header, Message, ProofRequest, MAGIC, MAX_BLOCKS, MAX_HEADERS, MAX_PROOFS,
+    MAX_WATCHED_ADDRESSES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 
 pub mod protocol
 {
+    use crate::core::encoding::{Decode, DecodeError, Encode, Reader};
+    use crate::core::Address;
     use serde::{Deserialize, Serialize};
 
-    #[derive(Clone, Debug, Deserialize, Serialize)]
+    /// marks every message of the network, nodes of other networks are rejected
+    pub const MAGIC: [u8; 4] = *b"JABN";
+
+    /// version of the protocol spoken by this implementation
+    pub const PROTOCOL_VERSION: u32 = 1;
+
+    /// oldest version of the protocol we still talk to
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+    /// maximum number of headers sent in response to `RequestHeaders`
+    pub const MAX_HEADERS: usize = 2000;
+
+    /// maximum number of blocks sent in response to `RequestBlock`
+    pub const MAX_BLOCKS: usize = 16;
+
+    /// number of proofs after which the response to `RequestProofs`
+    /// ends with the current block
+    pub const MAX_PROOFS: usize = 1000;
+
+    /// maximum number of addresses in a single `RequestProofs`
+    pub const MAX_WATCHED_ADDRESSES: usize = 64;
+
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
     pub enum Header
     {
         // broadcast a transaction
@@ -13,9 +40,19 @@ pub mod protocol
         // broadcast a block
         BroadcastBlock,
 
-        // request a block (which one?)
+        // request the headers following the first known hash of a locator,
+        // the body is the locator
+        RequestHeaders,
+
+        // up to MAX_HEADERS headers in response to RequestHeaders
+        Headers,
+
+        // request blocks, the body is the list of their hashes
         RequestBlock,
 
+        // up to MAX_BLOCKS blocks in response to RequestBlock
+        Blocks,
+
         // broadcast all peers
         BroadcastPeers,
 
@@ -33,31 +70,176 @@ pub mod protocol
 
         // generic ERR
         ERR,
+
+        // first message on every connection, the body describes the sender
+        Version,
+
+        // accepts the version of the other side
+        VerAck,
+
+        // keeps an idle connection alive, the body is a nonce
+        Ping,
+
+        // answers a ping, the body is the nonce of the ping
+        Pong,
+
+        // request proofs for the transactions of the best chain
+        // touching some addresses, the body is a ProofRequest
+        RequestProofs,
+
+        // transaction proofs in response to RequestProofs
+        Proofs,
+    }
+
+    impl Header
+    {
+        const ALL: [Header; 18] = [
+            Header::BroadcastTransaction,
+            Header::BroadcastBlock,
+            Header::RequestHeaders,
+            Header::Headers,
+            Header::RequestBlock,
+            Header::Blocks,
+            Header::BroadcastPeers,
+            Header::RequestPeers,
+            Header::Register,
+            Header::Deregister,
+            Header::OK,
+            Header::ERR,
+            Header::Version,
+            Header::VerAck,
+            Header::Ping,
+            Header::Pong,
+            Header::RequestProofs,
+            Header::Proofs,
+        ];
     }
 
-    #[derive(Clone, Debug, Deserialize, Serialize)]
+    /// position in the declaration above
+    impl Encode for Header
+    {
+        fn encode(&self, buf: &mut Vec<u8>)
+        {
+            (*self as u8).encode(buf);
+        }
+    }
+
+    impl Decode for Header
+    {
+        fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+        {
+            let tag = u8::decode(reader)?;
+
+            Header::ALL
+                .get(tag as usize)
+                .copied()
+                .ok_or(DecodeError::InvalidTag(tag))
+        }
+    }
+
+    /// transactions a light client is interested in
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct ProofRequest
+    {
+        /// transactions sent from or to one of these
+        pub addresses: Vec<Address>,
+
+        /// height of the first block to look at
+        pub from: u64,
+    }
+
+    impl Encode for ProofRequest
+    {
+        fn encode(&self, buf: &mut Vec<u8>)
+        {
+            self.addresses.encode(buf);
+            self.from.encode(buf);
+        }
+    }
+
+    impl Decode for ProofRequest
+    {
+        fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+        {
+            let addresses: Vec<Address> = Vec::decode(reader)?;
+
+            if addresses.len() > MAX_WATCHED_ADDRESSES
+            {
+                return Err(DecodeError::InvalidLength(addresses.len()));
+            }
+
+            Ok(ProofRequest {
+                addresses,
+                from: u64::decode(reader)?,
+            })
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
     pub struct Message
     {
         pub header: Header,
 
-        // serde_json format, not efficient but simple
-        pub body: String,
+        // encoded payload, its type depends on the header
+        pub body: Vec<u8>,
+    }
+
+    impl Default for Message
+    {
+        fn default() -> Self
+        {
+            Self::new()
+        }
     }
 
     impl Message
     {
         pub fn new() -> Message
         {
-            Message::with_data(Header::OK, "")
+            Message::with_data(Header::OK, &[])
+        }
+
+        pub fn with_data(header: Header, body: &[u8]) -> Message
+        {
+            Message {
+                header,
+                body: body.to_vec(),
+            }
         }
 
-        pub fn with_data(header: Header, body: &str) -> Message
+        pub fn with_payload<T: Encode + ?Sized>(header: Header, payload: &T) -> Message
         {
             Message {
                 header,
-                body: String::from(body),
+                body: payload.to_bytes(),
             }
         }
+
+        /// decode the body
+        pub fn payload<T: Decode>(&self) -> Result<T, DecodeError>
+        {
+            T::from_bytes(&self.body)
+        }
+    }
+
+    impl Encode for Message
+    {
+        fn encode(&self, buf: &mut Vec<u8>)
+        {
+            self.header.encode(buf);
+            self.body.encode(buf);
+        }
+    }
+
+    impl Decode for Message
+    {
+        fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+        {
+            Ok(Message {
+                header: Header::decode(reader)?,
+                body: Vec::decode(reader)?,
+            })
+        }
     }
 
     #[cfg(test)]
@@ -79,16 +261,25 @@ pub mod protocol
         {
             let miner = read_mock_address();
             let blk = Block::new(miner);
-            let s = serde_json::to_string(&blk).unwrap();
+            let msg = Message::with_payload(Header::BroadcastBlock, &blk);
 
-            assert_eq!(blk, serde_json::from_str(&s).unwrap());
+            let decoded = Message::from_bytes(&msg.to_bytes()).unwrap();
+            assert_eq!(decoded, msg);
+            assert_eq!(decoded.payload::<Block>().unwrap(), blk);
+
+            for header in Header::ALL
+            {
+                assert_eq!(Header::from_bytes(&header.to_bytes()), Ok(header));
+            }
+            assert!(Header::from_bytes(&[Header::ALL.len() as u8]).is_err());
         }
 
         #[test]
         fn deserialize()
         {
             let s = fs::read_to_string("etc/mock/network/broadcast_block.json").unwrap();
-            let _blk = serde_json::from_str::<Message>(&s).unwrap();
+            let msg = serde_json::from_str::<Message>(&s).unwrap();
+            let _blk = msg.payload::<Block>().unwrap();
         }
     }
 }
@@ -1,5 +0,0 @@
-#[test]
-fn broadcast_nodes()
-{
-    todo!();
-}
@@ -26,7 +26,7 @@ pub fn setup_mock_block(
     {
         let rsa_pair = generate_random_rsa_pair();
         let pubaddr = Address::with_key(rsa_pair.to_public_key());
-        transactors.push((rsa_pair, pubaddr));
+        transactors.push((rsa_pair, pubaddr, 0));
     }
 
     for _ in 0..count_transactions
@@ -37,12 +37,17 @@ pub fn setup_mock_block(
         // let's allow addresses to transact with themselves
         let recipient: usize = r.gen_range(0..count_distinct_transactors) as usize;
 
-        let input = Input::new(transactors[initiator].1.clone(), r.gen_range(1..=10000));
+        let input = Input::new(
+            transactors[initiator].1.clone(),
+            r.gen_range(1..=10000),
+            transactors[initiator].2,
+        );
+        transactors[initiator].2 += 1;
         let output =
             Output::with_addrs(vec![(transactors[recipient].1.clone(), input.get_value())])
                 .unwrap();
 
-        let mut trx = Transaction::new(input, output);
+        let mut trx = Transaction::new(input, output, 0);
 
         let padding = rsa::PaddingScheme::new_pkcs1v15_sign(None);
 
@@ -1,8 +1,29 @@
+use clap::Parser;
 use jabnode::node::{Config, Node};
+use jabnode::settings::{Settings, SettingsError};
 use jabnode::KillToken;
+use log::LevelFilter;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-fn init_logger()
+/// read when no config file is given
+const DEFAULT_CONFIG: &str = "etc/full-node.toml";
+
+/// a full node of the jabcoin network. settings given on the command line
+/// override those of the environment, which override the config file
+#[derive(Parser)]
+#[command(version)]
+struct Cli
+{
+    /// toml file with the settings of the node
+    #[arg(short, long, env = "JABNODE_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+fn init_logger(level: Option<LevelFilter>)
 {
     #[cfg(debug_assertions)]
     let env = env_logger::Env::default()
@@ -14,15 +35,49 @@ fn init_logger()
         .filter_or("RUST_LOG", "info")
         .write_style_or("RUST_LOG_STYLE", "always");
 
-    env_logger::init_from_env(env);
+    let mut builder = env_logger::Builder::from_env(env);
+
+    if let Some(level) = level
+    {
+        builder.filter_level(level);
+    }
+
+    builder.init();
 }
 
-fn main()
+/// the config of the node and its log level
+fn load(cli: Cli) -> Result<(Config, Option<LevelFilter>), SettingsError>
 {
-    init_logger();
+    // only the default config file may be missing
+    let file = match cli.config
+    {
+        Some(path) => Settings::from_file(path)?,
+        None if Path::new(DEFAULT_CONFIG).exists() => Settings::from_file(DEFAULT_CONFIG)?,
+        None => Settings::default(),
+    };
+
+    let settings = cli.settings.or(file);
+    let level = settings.log_level()?;
 
     let mut cfg = Config::with_default();
-    cfg.blkpath = "etc/mock/blocks".into();
+    settings.apply(&mut cfg)?;
+
+    Ok((cfg, level))
+}
+
+fn main()
+{
+    let (cfg, level) = match load(Cli::parse())
+    {
+        Ok(res) => res,
+        Err(e) =>
+        {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    init_logger(level);
 
     let kt = Arc::new(KillToken::new());
 
@@ -33,5 +88,9 @@ fn main()
     })
     .expect("failed to set ctrl-c handler!");
 
-    node.start();
+    if let Err(e) = node.start()
+    {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
 }
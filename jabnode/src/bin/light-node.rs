@@ -1,11 +1,64 @@
-use jabcoin::core::{crypto::Sha256Hash, Block, Transaction};
-use jabcoin::network::{Header, Message};
+use clap::Parser;
+use jabcoin::core::{crypto::Sha256Hash, Address};
+use jabnode::network::{peer::resolve, DEFAULT_PORT};
+use jabnode::spv::LightClient;
+use jabnode::wallet::Wallet;
+use jabnode::KillToken;
+use log::{info, LevelFilter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use jabnode::network::Connection;
+/// a light client of the jabcoin network. it keeps only the block headers
+/// and asks a full node to prove the transactions of its wallet
+#[derive(Parser)]
+#[command(version)]
+struct Cli
+{
+    /// full node as ip address or host name with an optional port
+    #[arg(
+        long,
+        env = "JABNODE_PEER",
+        value_name = "HOST",
+        default_value = "127.0.0.1"
+    )]
+    peer: String,
+
+    /// json file with the private key of the wallet, created if missing
+    #[arg(
+        long,
+        env = "JABNODE_WALLET",
+        value_name = "FILE",
+        default_value = "etc/wallet.json"
+    )]
+    wallet: PathBuf,
+
+    /// write the address of the wallet to a json file, usable as the miner of a full node
+    #[arg(long, value_name = "FILE")]
+    export_address: Option<PathBuf>,
+
+    /// json file with the address to pay
+    #[arg(long, value_name = "FILE", requires = "amount")]
+    pay: Option<PathBuf>,
+
+    /// value sent with `--pay`
+    #[arg(long, value_name = "N", requires = "pay")]
+    amount: Option<u64>,
+
+    /// fee offered to the miner of the payment
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    fee: u64,
 
-use log::info;
+    /// keep following the chain until interrupted
+    #[arg(long)]
+    watch: bool,
 
-fn init_logger()
+    /// off, error, warn, info, debug or trace
+    #[arg(long, env = "JABNODE_LOG_LEVEL", value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+}
+
+fn init_logger(level: Option<LevelFilter>)
 {
     #[cfg(debug_assertions)]
     let env = env_logger::Env::default()
@@ -17,35 +70,116 @@ fn init_logger()
         .filter_or("RUST_LOG", "info")
         .write_style_or("RUST_LOG_STYLE", "always");
 
-    env_logger::init_from_env(env);
+    let mut builder = env_logger::Builder::from_env(env);
+
+    if let Some(level) = level
+    {
+        builder.filter_level(level);
+    }
+
+    builder.init();
 }
 
-fn main()
+/// the wallet of the file, a new one is created if it doesn't exist
+fn load_wallet(path: &Path) -> std::io::Result<Wallet>
+{
+    if path.exists()
+    {
+        return Wallet::load(path);
+    }
+
+    info!("creating wallet {}.", path.display());
+
+    let wallet = Wallet::generate_random();
+    wallet.save(path)?;
+    Ok(wallet)
+}
+
+fn read_address(path: &Path) -> Result<Address, String>
+{
+    let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    serde_json::from_str(&s).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn print_history(client: &LightClient)
 {
-    init_logger();
+    for (trx, confirmations) in client.history()
+    {
+        let direction = match trx.input().get_addr() == client.address()
+        {
+            true => "sent",
+            false => "received",
+        };
 
-    info!("starting light-node");
-    info!("setting up mock trx");
+        info!(
+            "{:<30} {} with {confirmations} confirmation(s).",
+            format!("{direction} transaction"),
+            trx.hash_str()
+        );
+    }
+}
 
-    let s_trx = std::fs::read_to_string("../jabcoin/etc/mock/transaction.json").unwrap();
-    let trx = serde_json::from_str::<Transaction>(&s_trx).unwrap();
-    let msg = Message::with_data(Header::BroadcastTransaction, &s_trx);
+fn run(cli: Cli) -> Result<(), String>
+{
+    let wallet = load_wallet(&cli.wallet).map_err(|e| format!("{}: {e}", cli.wallet.display()))?;
 
-    let s_blk = std::fs::read_to_string("../jabcoin/etc/mock/block.json").unwrap();
-    let blk = serde_json::from_str::<Block>(&s_blk).unwrap();
-    let msg2 = Message::with_data(Header::BroadcastBlock, &s_blk);
+    if let Some(path) = &cli.export_address
+    {
+        let s = serde_json::to_string(&wallet.address()).map_err(|e| e.to_string())?;
+        std::fs::write(path, s).map_err(|e| format!("{}: {e}", path.display()))?;
+    }
+
+    let to = cli.pay.as_deref().map(read_address).transpose()?;
 
-    info!("sending trx: {}", trx.hash_str());
-    info!("sending blk: {}", blk.hash_str());
+    let peers = resolve(&cli.peer, DEFAULT_PORT).map_err(|e| format!("{}: {e}", cli.peer))?;
+    let mut client = LightClient::connect(&peers, wallet).map_err(|e| e.to_string())?;
+    client.sync().map_err(|e| e.to_string())?;
+    print_history(&client);
 
-    let tcpstream = std::net::TcpStream::connect("127.0.0.1:27182").unwrap();
-    let mut connection = Connection::new(tcpstream);
+    if let (Some(to), Some(amount)) = (to, cli.amount)
+    {
+        client
+            .submit(vec![(to, amount)], cli.fee)
+            .map_err(|e| e.to_string())?;
+    }
+
+    if cli.watch
+    {
+        let kt = Arc::new(KillToken::new());
+        let cpy = Arc::clone(&kt);
+
+        ctrlc::set_handler(move || {
+            cpy.activate();
+        })
+        .expect("failed to set ctrl-c handler!");
+
+        while !kt.wait_timeout(Duration::ZERO)
+        {
+            match client.poll(Duration::from_secs(1))
+            {
+                Ok(true) =>
+                {
+                    info!("new tip at height {}.", client.headers().len());
+                    print_history(&client);
+                }
+                Ok(false) => (),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    client.close();
+    Ok(())
+}
+
+fn main()
+{
+    let cli = Cli::parse();
+    init_logger(cli.log_level);
 
-    for _ in 0..25
+    if let Err(e) = run(cli)
     {
-        connection.write_msg(&msg).unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(10));
-        connection.write_msg(&msg2).unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        eprintln!("error: {e}");
+        std::process::exit(1);
     }
 }
@@ -1,9 +1,13 @@
 pub mod network;
 pub mod node;
+pub mod settings;
+pub mod spv;
+pub mod store;
 pub mod threadpool;
 pub mod wallet;
 
 use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
 pub struct KillToken
 {
@@ -11,6 +15,14 @@ pub struct KillToken
     kill: Mutex<bool>,
 }
 
+impl Default for KillToken
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
 impl KillToken
 {
     pub fn new() -> KillToken
@@ -23,16 +35,31 @@ impl KillToken
 
     pub fn wait_on(&self) -> bool
     {
-        match self.cvar.wait(self.kill.lock().unwrap())
+        // returns right away if the token has been activated before
+        match self
+            .cvar
+            .wait_while(self.kill.lock().unwrap(), |kill| !*kill)
         {
             Ok(b) => *b,
             Err(e) => panic!("{e}"),
         }
     }
 
+    /// wait at most `timeout`, returns true if the token has been activated
+    pub fn wait_timeout(&self, timeout: Duration) -> bool
+    {
+        match self
+            .cvar
+            .wait_timeout_while(self.kill.lock().unwrap(), timeout, |kill| !*kill)
+        {
+            Ok((b, _)) => *b,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
     pub fn activate(&self)
     {
         *self.kill.lock().unwrap() = true;
-        self.cvar.notify_one();
+        self.cvar.notify_all();
     }
 }
@@ -1,5 +1,14 @@
+pub mod addrman;
 pub mod connection;
 pub mod peer;
+pub mod session;
+pub mod version;
 
-pub use connection::Connection;
+pub use addrman::{AddrMan, Misbehaviour};
+pub use connection::{Connection, FrameError};
 pub use peer::{Peer, PeerType};
+pub use session::{Session, SessionError};
+pub use version::{HandshakeError, Version};
+
+/// port of the main network, assumed for peers given without one
+pub const DEFAULT_PORT: u16 = 27182;
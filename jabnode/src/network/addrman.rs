@@ -0,0 +1,633 @@
+use jabcoin::core::crypto::{Digest, Sha256};
+use jabcoin::core::encoding::{Decode, DecodeError, Encode, Reader};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// buckets of addresses we only heard of
+const NEW_BUCKETS: usize = 64;
+
+/// buckets a single source can put addresses into,
+/// so one peer can't fill the whole new table
+const BUCKETS_PER_SOURCE: usize = 4;
+
+/// buckets of addresses we connected to before
+const TRIED_BUCKETS: usize = 16;
+
+const BUCKET_SIZE: usize = 32;
+
+/// most addresses accepted in a single message
+pub const MAX_ADDRS: usize = 1000;
+
+/// misbehaviour score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// seconds before retrying an address, doubled with every failed attempt
+const RETRY_DELAY: u64 = 5;
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+
+/// things a peer can do wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehaviour
+{
+    /// a block breaking the consensus rules
+    InvalidBlock,
+
+    /// a message which can't be decoded or isn't expected
+    MalformedMessage,
+
+    /// more messages or addresses than allowed
+    Spam,
+}
+
+impl Misbehaviour
+{
+    pub fn score(self) -> u32
+    {
+        match self
+        {
+            Misbehaviour::InvalidBlock => 100,
+            Misbehaviour::MalformedMessage => 20,
+            Misbehaviour::Spam => 20,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Misbehaviour::InvalidBlock => write!(f, "invalid block"),
+            Misbehaviour::MalformedMessage => write!(f, "malformed message"),
+            Misbehaviour::Spam => write!(f, "spam"),
+        }
+    }
+}
+
+/// seconds since the epoch
+fn now() -> u64
+{
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// what we know about an address
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct AddrInfo
+{
+    /// peer which told us about the address
+    source: IpAddr,
+    tried: bool,
+
+    /// failed connection attempts since the last success
+    attempts: u32,
+    last_try: u64,
+    last_success: u64,
+}
+
+impl AddrInfo
+{
+    fn new(source: IpAddr) -> AddrInfo
+    {
+        AddrInfo {
+            source,
+            tried: false,
+            attempts: 0,
+            last_try: 0,
+            last_success: 0,
+        }
+    }
+
+    /// earliest time for the next connection attempt
+    fn retry_at(&self) -> u64
+    {
+        if self.last_try == 0
+        {
+            return 0;
+        }
+
+        let delay = RETRY_DELAY << self.attempts.min(16);
+        self.last_try + delay.min(MAX_RETRY_DELAY)
+    }
+}
+
+/// the /16 network of an ipv4 address or the /32 network of an
+/// ipv6 address, addresses of one operator tend to share it
+fn group(ip: &IpAddr) -> Vec<u8>
+{
+    match ip
+    {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => ip.octets()[..4].to_vec(),
+    }
+}
+
+/// addresses of other nodes, split into addresses we connected to
+/// successfully (tried) and addresses we only heard of (new), together
+/// with the misbehaviour scores and bans of the peers
+pub struct AddrMan
+{
+    /// secret mixed into the bucket positions
+    key: u64,
+    addrs: HashMap<SocketAddr, AddrInfo>,
+    new: Vec<Vec<SocketAddr>>,
+    tried: Vec<Vec<SocketAddr>>,
+
+    /// scores and bans apply to all ports of an ip,
+    /// scores are forgotten on restart, bans are not
+    scores: HashMap<IpAddr, u32>,
+
+    /// end of each ban in seconds since the epoch
+    bans: HashMap<IpAddr, u64>,
+}
+
+impl Default for AddrMan
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl AddrMan
+{
+    pub fn new() -> AddrMan
+    {
+        AddrMan::with_key(rand::random())
+    }
+
+    fn with_key(key: u64) -> AddrMan
+    {
+        AddrMan {
+            key,
+            addrs: HashMap::new(),
+            new: vec![vec![]; NEW_BUCKETS],
+            tried: vec![vec![]; TRIED_BUCKETS],
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// read the addresses written by `save`, a missing file yields an empty manager
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<AddrMan>
+    {
+        match std::fs::read(path)
+        {
+            Ok(data) => AddrMan::from_bytes(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(AddrMan::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()>
+    {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+
+        file.write_all(&self.to_bytes())?;
+        file.sync_all()?;
+
+        std::fs::rename(&tmp, path)
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.addrs.is_empty()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool
+    {
+        self.addrs.contains_key(addr)
+    }
+
+    fn bucket(&self, data: &[&[u8]], count: usize) -> usize
+    {
+        let mut hasher = Sha256::new();
+        hasher.update(self.key.to_be_bytes());
+
+        for i in data
+        {
+            hasher.update(i);
+        }
+
+        let digest = hasher.finalize();
+        u64::from_be_bytes(digest[..8].try_into().unwrap()) as usize % count
+    }
+
+    fn new_bucket(&self, addr: &SocketAddr, source: &IpAddr) -> usize
+    {
+        let (addr, source) = (group(&addr.ip()), group(source));
+        let slot = self.bucket(&[&source, &addr], BUCKETS_PER_SOURCE) as u8;
+
+        self.bucket(&[&source, &[slot]], NEW_BUCKETS)
+    }
+
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize
+    {
+        self.bucket(&[&addr.to_bytes()], TRIED_BUCKETS)
+    }
+
+    fn insert(&mut self, addr: SocketAddr, info: AddrInfo)
+    {
+        match info.tried
+        {
+            true => self.insert_tried(addr, info),
+            false => self.insert_new(addr, info),
+        }
+    }
+
+    fn insert_new(&mut self, addr: SocketAddr, mut info: AddrInfo)
+    {
+        let b = self.new_bucket(&addr, &info.source);
+
+        if self.new[b].len() >= BUCKET_SIZE
+        {
+            // drop the address which failed most often, the newest one among
+            // equals, so a flood of addresses can't push out the known ones
+            let worst = *self.new[b]
+                .iter()
+                .max_by_key(|a| self.addrs[a].attempts)
+                .unwrap();
+            self.remove(&worst);
+        }
+
+        info.tried = false;
+        self.new[b].push(addr);
+        self.addrs.insert(addr, info);
+    }
+
+    fn insert_tried(&mut self, addr: SocketAddr, mut info: AddrInfo)
+    {
+        let b = self.tried_bucket(&addr);
+
+        if self.tried[b].len() >= BUCKET_SIZE
+        {
+            // the address which worked longest ago goes back to the new table
+            let oldest = *self.tried[b]
+                .iter()
+                .min_by_key(|a| self.addrs[a].last_success)
+                .unwrap();
+            let old = self.remove(&oldest).unwrap();
+            self.insert_new(oldest, old);
+        }
+
+        info.tried = true;
+        self.tried[b].push(addr);
+        self.addrs.insert(addr, info);
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) -> Option<AddrInfo>
+    {
+        let info = self.addrs.remove(addr)?;
+
+        if info.tried
+        {
+            let b = self.tried_bucket(addr);
+            self.tried[b].retain(|a| a != addr);
+        }
+        else
+        {
+            let b = self.new_bucket(addr, &info.source);
+            self.new[b].retain(|a| a != addr);
+        }
+
+        Some(info)
+    }
+
+    /// remember an address we heard of from `source`, returns true if it was unknown
+    pub fn add(&mut self, addr: SocketAddr, source: IpAddr) -> bool
+    {
+        let ip = addr.ip();
+        let broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+
+        if ip.is_unspecified() || ip.is_multicast() || broadcast || addr.port() == 0
+        {
+            return false;
+        }
+
+        if self.contains(&addr) || self.is_banned(&ip)
+        {
+            return false;
+        }
+
+        self.insert_new(addr, AddrInfo::new(source));
+        true
+    }
+
+    /// a connection attempt to `addr` is about to be made
+    pub fn attempt(&mut self, addr: &SocketAddr)
+    {
+        if let Some(info) = self.addrs.get_mut(addr)
+        {
+            info.attempts += 1;
+            info.last_try = now();
+        }
+    }
+
+    /// a connection to `addr` succeeded, moves it to the tried table
+    pub fn good(&mut self, addr: SocketAddr)
+    {
+        let mut info = self
+            .remove(&addr)
+            .unwrap_or_else(|| AddrInfo::new(addr.ip()));
+
+        info.attempts = 0;
+        info.last_success = now();
+        self.insert_tried(addr, info);
+    }
+
+    /// pick a random address to connect to, preferring tried ones, skipping
+    /// addresses `exclude` returns true for and addresses waiting for a retry
+    pub fn select<F: Fn(&SocketAddr) -> bool>(&self, exclude: F) -> Option<SocketAddr>
+    {
+        let now = now();
+
+        let candidates = |tried: bool| -> Vec<SocketAddr> {
+            self.addrs
+                .iter()
+                .filter(|(a, i)| i.tried == tried && i.retry_at() <= now && !exclude(a))
+                .map(|(a, _)| *a)
+                .collect()
+        };
+
+        [true, false]
+            .into_iter()
+            .map(candidates)
+            .find(|c| !c.is_empty())
+            .and_then(|c| c.choose(&mut rand::thread_rng()).copied())
+    }
+
+    /// addresses to share with other peers, tried and reliable ones first
+    pub fn sample(&self, max: usize) -> Vec<SocketAddr>
+    {
+        let mut addrs: Vec<_> = self
+            .addrs
+            .iter()
+            .map(|(a, i)| (!i.tried, i.attempts, *a))
+            .collect();
+        addrs.sort();
+
+        addrs.into_iter().take(max).map(|(_, _, a)| a).collect()
+    }
+
+    /// add to the misbehaviour score of `ip`, returns true if it got banned
+    pub fn misbehaving(&mut self, ip: IpAddr, what: Misbehaviour) -> bool
+    {
+        let score = self.scores.entry(ip).or_insert(0);
+        *score += what.score();
+
+        if *score < BAN_THRESHOLD
+        {
+            return false;
+        }
+
+        self.ban(ip, BAN_DURATION);
+        true
+    }
+
+    /// refuse connections from and to `ip` and forget its addresses for `duration`
+    pub fn ban(&mut self, ip: IpAddr, duration: Duration)
+    {
+        let addrs: Vec<SocketAddr> = self
+            .addrs
+            .keys()
+            .filter(|a| a.ip() == ip)
+            .copied()
+            .collect();
+
+        for addr in addrs
+        {
+            self.remove(&addr);
+        }
+
+        self.scores.remove(&ip);
+        self.bans.insert(ip, now() + duration.as_secs());
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool
+    {
+        self.bans.get(ip).is_some_and(|until| *until > now())
+    }
+}
+
+impl Encode for AddrInfo
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.source.encode(buf);
+        self.tried.encode(buf);
+        self.attempts.encode(buf);
+        self.last_try.encode(buf);
+        self.last_success.encode(buf);
+    }
+}
+
+impl Decode for AddrInfo
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(AddrInfo {
+            source: IpAddr::decode(reader)?,
+            tried: bool::decode(reader)?,
+            attempts: u32::decode(reader)?,
+            last_try: u64::decode(reader)?,
+            last_success: u64::decode(reader)?,
+        })
+    }
+}
+
+/// the key, the addresses and the active bans, each sorted by address.
+/// the buckets are rebuilt when reading
+impl Encode for AddrMan
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.key.encode(buf);
+
+        let mut addrs: Vec<(SocketAddr, AddrInfo)> =
+            self.addrs.iter().map(|(a, i)| (*a, i.clone())).collect();
+        addrs.sort_by_key(|(a, _)| *a);
+        addrs.encode(buf);
+
+        let now = now();
+        let mut bans: Vec<(IpAddr, u64)> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (*ip, *until))
+            .collect();
+        bans.sort();
+        bans.encode(buf);
+    }
+}
+
+impl Decode for AddrMan
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        let mut addrman = AddrMan::with_key(u64::decode(reader)?);
+
+        for (addr, info) in Vec::<(SocketAddr, AddrInfo)>::decode(reader)?
+        {
+            if addrman.contains(&addr)
+            {
+                return Err(DecodeError::InvalidValue(format!(
+                    "duplicate address {addr}"
+                )));
+            }
+            addrman.insert(addr, info);
+        }
+
+        addrman.bans = Vec::<(IpAddr, u64)>::decode(reader)?.into_iter().collect();
+
+        Ok(addrman)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(i: u32) -> SocketAddr
+    {
+        SocketAddr::from((Ipv4Addr::from(0x0a00_0000 + i), 27182))
+    }
+
+    fn ip(i: u32) -> IpAddr
+    {
+        addr(i).ip()
+    }
+
+    #[test]
+    fn buckets()
+    {
+        let mut addrman = AddrMan::with_key(7);
+        let honest: SocketAddr = "192.168.0.1:27182".parse().unwrap();
+        assert!(addrman.add(honest, honest.ip()));
+        assert!(!addrman.add(honest, honest.ip()));
+        assert!(!addrman.add("0.0.0.0:27182".parse().unwrap(), honest.ip()));
+        assert!(!addrman.add("192.168.0.2:0".parse().unwrap(), honest.ip()));
+
+        // a single source only reaches a few buckets, however many addresses it sends
+        let spammer = "172.16.0.1".parse().unwrap();
+        for i in 0..(MAX_ADDRS as u32)
+        {
+            addrman.add(SocketAddr::from((Ipv4Addr::from(i << 12), 27182)), spammer);
+            addrman.add(
+                SocketAddr::from(([0x2001, i as u16, 0, 0, 0, 0, 0, 1], 1)),
+                spammer,
+            );
+        }
+
+        assert!(addrman.len() <= 1 + BUCKETS_PER_SOURCE * BUCKET_SIZE);
+        assert!(addrman.contains(&honest));
+
+        // tried addresses are preferred
+        addrman.good(honest);
+        assert_eq!(addrman.select(|_| false), Some(honest));
+        assert_eq!(addrman.sample(1), vec![honest]);
+        assert_ne!(addrman.select(|a| a == &honest), Some(honest));
+    }
+
+    #[test]
+    fn retry_backoff()
+    {
+        let mut addrman = AddrMan::with_key(7);
+        addrman.add(addr(1), ip(1));
+        assert_eq!(addrman.select(|_| false), Some(addr(1)));
+
+        // failed addresses are skipped until their retry delay passed
+        addrman.attempt(&addr(1));
+        assert_eq!(addrman.select(|_| false), None);
+
+        let info = addrman.addrs.get_mut(&addr(1)).unwrap();
+        info.attempts = 3;
+        info.last_try = now() - (RETRY_DELAY << 3);
+        assert_eq!(addrman.select(|_| false), Some(addr(1)));
+
+        addrman.good(addr(1));
+        assert_eq!(addrman.addrs[&addr(1)].attempts, 0);
+        assert!(addrman.addrs[&addr(1)].tried);
+    }
+
+    #[test]
+    fn bans()
+    {
+        let mut addrman = AddrMan::with_key(7);
+        let other_port = SocketAddr::new(ip(1), 1);
+        addrman.good(addr(1));
+        addrman.add(other_port, ip(2));
+
+        for _ in 1..(BAN_THRESHOLD / Misbehaviour::MalformedMessage.score())
+        {
+            assert!(!addrman.misbehaving(ip(1), Misbehaviour::MalformedMessage));
+        }
+        assert!(!addrman.is_banned(&ip(1)));
+
+        // bans cover every port of the ip
+        assert!(addrman.misbehaving(ip(1), Misbehaviour::MalformedMessage));
+        assert!(addrman.is_banned(&ip(1)));
+        assert!(addrman.is_empty());
+        assert!(!addrman.add(addr(1), ip(2)));
+
+        // a single invalid block is enough
+        assert!(addrman.misbehaving(ip(2), Misbehaviour::InvalidBlock));
+
+        // bans run out
+        addrman.bans.insert(ip(1), now() - 1);
+        assert!(!addrman.is_banned(&ip(1)));
+        assert!(addrman.add(addr(1), ip(2)));
+    }
+
+    #[test]
+    fn persistence()
+    {
+        let dir = std::env::temp_dir().join(format!("jabnode-addrman-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peers.dat");
+
+        assert!(AddrMan::load(&path).unwrap().is_empty());
+
+        let mut addrman = AddrMan::new();
+        for i in 0..20
+        {
+            addrman.add(addr(i), ip(100));
+        }
+        addrman.add("[2001:db8::1]:27182".parse().unwrap(), ip(100));
+        addrman.good(addr(3));
+        addrman.ban(ip(4), BAN_DURATION);
+        addrman.save(&path).unwrap();
+
+        let loaded = AddrMan::load(&path).unwrap();
+        assert_eq!(loaded.addrs, addrman.addrs);
+        assert_eq!(loaded.new, addrman.new);
+        assert_eq!(loaded.tried, addrman.tried);
+        assert!(loaded.is_banned(&ip(4)));
+
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(AddrMan::load(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,204 @@
-use serde::Deserialize;
-use serde_json::Deserializer;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
 
-use std::io::{BufReader, BufWriter, Result, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use jabcoin::core::crypto::{Digest, Sha256};
+use jabcoin::core::{Decode, Encode};
+use jabcoin::network::{Header, Message, MAGIC};
 
-use jabcoin::network::Message;
+use crate::network::peer::canonical;
+use crate::network::{HandshakeError, Version};
+
+/// largest payload accepted in a single frame, enough for
+/// `MAX_BLOCKS` blocks of the maximum size
+pub const MAX_PAYLOAD: usize = 32 * 1024 * 1024;
+
+/// largest payload accepted before the handshake is complete,
+/// version messages are far smaller
+const MAX_HANDSHAKE_PAYLOAD: usize = 1024;
+
+/// magic, message type, payload length and checksum
+const FRAME_HEADER_LEN: usize = 4 + 1 + 4 + 4;
+
+/// time the other side has to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// time the other side has to complete the whole handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// reasons for dropping a connection while reading or writing frames
+#[derive(Debug)]
+pub enum FrameError
+{
+    Io(io::Error),
+
+    /// the frame belongs to another network or the stream is out of sync
+    InvalidMagic([u8; 4]),
+
+    /// the message type is unknown
+    InvalidType(u8),
+
+    /// the payload exceeds the limit of the frame
+    TooLarge
+    {
+        size: usize,
+        limit: usize,
+    },
+
+    /// the payload doesn't match its checksum
+    InvalidChecksum,
+
+    /// the connection was closed in the middle of a frame
+    Truncated,
+}
+
+impl FrameError
+{
+    /// true if the peer closed the connection between two frames
+    pub fn is_eof(&self) -> bool
+    {
+        matches!(self, FrameError::Io(e) if e.kind() == ErrorKind::UnexpectedEof)
+    }
+}
+
+impl fmt::Display for FrameError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            FrameError::Io(e) => write!(f, "i/o error: {e}"),
+            FrameError::InvalidMagic(m) => write!(f, "invalid magic {m:02x?}"),
+            FrameError::InvalidType(t) => write!(f, "invalid message type {t}"),
+            FrameError::TooLarge { size, limit } =>
+            {
+                write!(f, "payload of {size} bytes exceeds the limit of {limit}")
+            }
+            FrameError::InvalidChecksum => write!(f, "invalid checksum"),
+            FrameError::Truncated => write!(f, "connection closed within a frame"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError
+{
+    fn from(e: io::Error) -> Self
+    {
+        FrameError::Io(e)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4]
+{
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// write a message as a single frame
+pub fn write_frame<W: Write>(writer: &mut W, msg: &Message) -> Result<(), FrameError>
+{
+    if msg.body.len() > MAX_PAYLOAD
+    {
+        return Err(FrameError::TooLarge {
+            size: msg.body.len(),
+            limit: MAX_PAYLOAD,
+        });
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + msg.body.len());
+    frame.extend_from_slice(&MAGIC);
+    msg.header.encode(&mut frame);
+    (msg.body.len() as u32).encode(&mut frame);
+    frame.extend_from_slice(&checksum(&msg.body));
+    frame.extend_from_slice(&msg.body);
+
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_within_frame<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), FrameError>
+{
+    reader.read_exact(buf).map_err(|e| match e.kind()
+    {
+        ErrorKind::UnexpectedEof => FrameError::Truncated,
+        _ => FrameError::Io(e),
+    })
+}
+
+/// read the next frame, the header is checked before the payload is read
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Message, FrameError>
+{
+    read_frame_limited(reader, MAX_PAYLOAD)
+}
+
+/// read the next frame with a payload of at most `limit` bytes
+fn read_frame_limited<R: Read>(reader: &mut R, limit: usize) -> Result<Message, FrameError>
+{
+    // running out of data before the first byte is a regular end of the stream
+    let mut prefix = [0; FRAME_HEADER_LEN];
+    reader.read_exact(&mut prefix[..1])?;
+    read_within_frame(reader, &mut prefix[1..])?;
+
+    let magic: [u8; 4] = prefix[..4].try_into().unwrap();
+    if magic != MAGIC
+    {
+        return Err(FrameError::InvalidMagic(magic));
+    }
+
+    let header =
+        Header::from_bytes(&prefix[4..5]).map_err(|_| FrameError::InvalidType(prefix[4]))?;
+
+    let size = u32::from_bytes(&prefix[5..9]).unwrap() as usize;
+    if size > limit
+    {
+        return Err(FrameError::TooLarge { size, limit });
+    }
+
+    // the buffer grows with the data actually received,
+    // announcing a large payload doesn't reserve any memory
+    let mut body = vec![];
+    reader.take(size as u64).read_to_end(&mut body)?;
+
+    if body.len() < size
+    {
+        return Err(FrameError::Truncated);
+    }
+
+    if prefix[9..] != checksum(&body)
+    {
+        return Err(FrameError::InvalidChecksum);
+    }
+
+    Ok(Message { header, body })
+}
+
+/// reader failing once the deadline has passed,
+/// no matter how slowly the data trickles in
+struct DeadlineReader<'a, R>
+{
+    stream: &'a TcpStream,
+    reader: &'a mut R,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for DeadlineReader<'_, R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero()
+        {
+            return Err(io::Error::new(ErrorKind::TimedOut, "deadline passed"));
+        }
+
+        self.stream.set_read_timeout(Some(left))?;
+        self.reader.read(buf)
+    }
+}
 
 pub struct Connection
 {
@@ -27,14 +221,10 @@ impl Connection
         }
     }
 
-    pub fn new_try_peer_addr(peer_addr: Ipv4Addr, port: u16) -> Result<Connection>
+    pub fn new_try_peer_addr(peer_addr: SocketAddr) -> io::Result<Connection>
     {
-        let socket_addr = SocketAddrV4::new(peer_addr, port);
-        match TcpStream::connect(socket_addr)
-        {
-            Ok(stream) => Ok(Connection::new(stream)),
-            Err(e) => Err(e),
-        }
+        let stream = TcpStream::connect_timeout(&peer_addr, CONNECT_TIMEOUT)?;
+        Ok(Connection::new(stream))
     }
 
     pub fn get_stream(&self) -> &TcpStream
@@ -42,27 +232,265 @@ impl Connection
         &self.stream
     }
 
-    pub fn write_msg(&mut self, msg: &Message) -> serde_json::Result<()>
+    pub fn write_msg(&mut self, msg: &Message) -> Result<(), FrameError>
+    {
+        write_frame(&mut self.writer, msg)
+    }
+
+    /// read the next message, any error leaves the stream
+    /// in an unknown state and the connection should be closed
+    pub fn read_msg(&mut self) -> Result<Message, FrameError>
+    {
+        read_frame(&mut self.reader)
+    }
+
+    /// exchange versions with the other side, which has to do the same
+    /// before sending anything else. returns the version of the other side
+    pub fn handshake(&mut self, local: &Version) -> Result<Version, HandshakeError>
+    {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let remote = self.exchange_versions(local, deadline);
+
+        self.stream.set_read_timeout(None).map_err(FrameError::Io)?;
+        remote
+    }
+
+    /// read a message of the handshake, the other side isn't trusted yet
+    fn read_handshake_msg(&mut self, deadline: Instant) -> Result<Message, FrameError>
+    {
+        let mut reader = DeadlineReader {
+            stream: &self.stream,
+            reader: &mut self.reader,
+            deadline,
+        };
+        read_frame_limited(&mut reader, MAX_HANDSHAKE_PAYLOAD)
+    }
+
+    fn exchange_versions(
+        &mut self,
+        local: &Version,
+        deadline: Instant,
+    ) -> Result<Version, HandshakeError>
+    {
+        self.write_msg(&Message::with_payload(Header::Version, local))?;
+
+        let msg = self.read_handshake_msg(deadline)?;
+        if msg.header != Header::Version
+        {
+            return Err(HandshakeError::UnexpectedMessage(msg.header));
+        }
+
+        let remote = msg
+            .payload::<Version>()
+            .map_err(HandshakeError::InvalidVersion)?;
+
+        local
+            .check_compatible(&remote)
+            .map_err(HandshakeError::Incompatible)?;
+
+        self.write_msg(&Message::with_data(Header::VerAck, &[]))?;
+
+        let msg = self.read_handshake_msg(deadline)?;
+        if msg.header != Header::VerAck
+        {
+            return Err(HandshakeError::UnexpectedMessage(msg.header));
+        }
+
+        Ok(remote)
+    }
+
+    /// take the connection apart to read and write from different threads
+    pub fn split(self) -> (TcpStream, BufReader<TcpStream>, BufWriter<TcpStream>)
+    {
+        (self.stream, self.reader, self.writer)
+    }
+
+    pub fn get_peer_addr(&self) -> io::Result<SocketAddr>
+    {
+        self.stream.peer_addr().map(canonical)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn frame(msg: &Message) -> Vec<u8>
+    {
+        let mut buf = vec![];
+        write_frame(&mut buf, msg).unwrap();
+        buf
+    }
+
+    #[test]
+    fn roundtrip()
+    {
+        let msgs = [
+            Message::with_data(Header::RequestHeaders, &[1, 2, 3]),
+            Message::with_data(Header::OK, &[]),
+        ];
+
+        let mut stream = vec![];
+        for msg in &msgs
+        {
+            stream.extend(frame(msg));
+        }
+
+        let mut reader = stream.as_slice();
+        for msg in &msgs
+        {
+            assert_eq!(&read_frame(&mut reader).unwrap(), msg);
+        }
+        assert!(read_frame(&mut reader).unwrap_err().is_eof());
+    }
+
+    fn connected_pair() -> (Connection, Connection)
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        (Connection::new(client), Connection::new(server))
+    }
+
+    #[test]
+    fn handshake()
     {
-        serde_json::to_writer(&mut self.writer, msg)?;
-        self.writer.flush().unwrap();
-        Ok(())
+        use crate::network::PeerType;
+
+        let (mut client, mut server) = connected_pair();
+        let a = Version::new(vec![1; 32], 3, PeerType::LightNode, 0, 1);
+        let b = Version::new(vec![1; 32], 5, PeerType::FullNode, 27182, 2);
+
+        let (a2, b2) = (a.clone(), b.clone());
+        let thd = std::thread::spawn(move || server.handshake(&b2).unwrap());
+        assert_eq!(client.handshake(&a2).unwrap(), b);
+        assert_eq!(thd.join().unwrap(), a);
+
+        // a peer on another chain is dropped before any other message
+        let (mut client, mut server) = connected_pair();
+        let other = Version::new(vec![2; 32], 5, PeerType::FullNode, 27182, 3);
+
+        let thd = std::thread::spawn(move || {
+            let res = server.handshake(&other);
+            let _ = server.write_msg(&Message::with_data(Header::OK, &[]));
+            res
+        });
+        assert!(matches!(
+            client.handshake(&a),
+            Err(HandshakeError::Incompatible(_))
+        ));
+        assert!(matches!(
+            thd.join().unwrap(),
+            Err(HandshakeError::Incompatible(_))
+        ));
+
+        // messages before the version are rejected
+        let (mut client, mut server) = connected_pair();
+        client
+            .write_msg(&Message::with_data(Header::RequestPeers, &[]))
+            .unwrap();
+        assert!(matches!(
+            server.handshake(&b),
+            Err(HandshakeError::UnexpectedMessage(Header::RequestPeers))
+        ));
+
+        // so are large frames of a peer which hasn't introduced itself
+        let (mut client, mut server) = connected_pair();
+        client
+            .write_msg(&Message::with_data(
+                Header::Version,
+                &[0; MAX_HANDSHAKE_PAYLOAD + 1],
+            ))
+            .unwrap();
+        assert!(matches!(
+            server.handshake(&b),
+            Err(HandshakeError::Frame(FrameError::TooLarge { .. }))
+        ));
     }
 
-    pub fn read_msg(&mut self) -> serde_json::Result<Message>
+    #[test]
+    fn handshake_deadline()
     {
-        let mut de = Deserializer::from_reader(&mut self.reader);
-        let msg = Message::deserialize(&mut de)?;
+        let (mut client, server) = connected_pair();
+        client
+            .write_msg(&Message::with_data(Header::VerAck, &[]))
+            .unwrap();
 
-        Ok(msg)
+        // the data is there, but the time is up
+        let mut reader = BufReader::new(server.get_stream().try_clone().unwrap());
+        let mut reader = DeadlineReader {
+            stream: server.get_stream(),
+            reader: &mut reader,
+            deadline: Instant::now(),
+        };
+        let err = read_frame(&mut reader).unwrap_err();
+        assert!(matches!(err, FrameError::Io(e) if e.kind() == ErrorKind::TimedOut));
     }
 
-    pub fn get_peer_addr(&self) -> Ipv4Addr
+    #[test]
+    fn reject_invalid_frames()
     {
-        match self.stream.peer_addr().unwrap().ip()
+        let good = frame(&Message::with_data(Header::Blocks, &[7; 64]));
+
+        let mut bad = good.clone();
+        bad[0] ^= 1;
+        assert!(matches!(
+            read_frame(&mut bad.as_slice()),
+            Err(FrameError::InvalidMagic(_))
+        ));
+
+        let mut bad = good.clone();
+        bad[4] = 0xff;
+        assert!(matches!(
+            read_frame(&mut bad.as_slice()),
+            Err(FrameError::InvalidType(0xff))
+        ));
+
+        let mut bad = good.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            read_frame(&mut bad.as_slice()),
+            Err(FrameError::InvalidChecksum)
+        ));
+
+        // the announced size is rejected before reading the payload
+        let mut bad = good.clone();
+        bad[5..9].copy_from_slice(&(MAX_PAYLOAD as u32 + 1).to_be_bytes());
+        assert!(matches!(
+            read_frame(&mut bad.as_slice()),
+            Err(FrameError::TooLarge { .. })
+        ));
+        assert!(matches!(
+            read_frame_limited(&mut good.as_slice(), 63),
+            Err(FrameError::TooLarge {
+                size: 64,
+                limit: 63
+            })
+        ));
+
+        // a payload shorter than announced
+        let mut bad = good.clone();
+        bad[5..9].copy_from_slice(&(MAX_PAYLOAD as u32).to_be_bytes());
+        assert!(matches!(
+            read_frame(&mut bad.as_slice()),
+            Err(FrameError::Truncated)
+        ));
+
+        // a frame cut short is not a clean end of the stream
+        for len in [1, good.len() - 1]
         {
-            std::net::IpAddr::V4(ip) => ip,
-            std::net::IpAddr::V6(_) => panic!("not ipv4"),
+            assert!(matches!(
+                read_frame(&mut &good[..len]),
+                Err(FrameError::Truncated)
+            ));
         }
+
+        let huge = Message::with_data(Header::Blocks, &vec![0; MAX_PAYLOAD + 1]);
+        assert!(matches!(
+            write_frame(&mut vec![], &huge),
+            Err(FrameError::TooLarge { .. })
+        ));
     }
 }
@@ -1,5 +1,7 @@
+use jabcoin::core::encoding::{Decode, DecodeError, Encode, Reader};
 use serde::{Deserialize, Serialize};
-use std::net::Ipv4Addr;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum PeerType
@@ -13,13 +15,13 @@ pub struct Peer
 {
     id: u64,
     ptype: PeerType,
-    address: Ipv4Addr,
-    connected_peers: Vec<(u64, Ipv4Addr)>,
+    address: SocketAddr,
+    connected_peers: Vec<(u64, SocketAddr)>,
 }
 
 impl Peer
 {
-    pub fn new(id: u64, ptype: PeerType, address: Ipv4Addr) -> Peer
+    pub fn new(id: u64, ptype: PeerType, address: SocketAddr) -> Peer
     {
         Peer {
             id,
@@ -32,8 +34,8 @@ impl Peer
     pub fn with_nodes(
         id: u64,
         ptype: PeerType,
-        address: Ipv4Addr,
-        nodes: Vec<(u64, Ipv4Addr)>,
+        address: SocketAddr,
+        nodes: Vec<(u64, SocketAddr)>,
     ) -> Peer
     {
         Peer {
@@ -54,7 +56,7 @@ impl Peer
         self.id
     }
 
-    pub fn set_address(&mut self, new_addr: Ipv4Addr)
+    pub fn set_address(&mut self, new_addr: SocketAddr)
     {
         self.address = new_addr;
     }
@@ -64,13 +66,113 @@ impl Peer
         self.ptype
     }
 
-    pub fn address(&self) -> &Ipv4Addr
+    pub fn address(&self) -> &SocketAddr
     {
         &self.address
     }
 
-    pub fn connected_nodes(&self) -> &Vec<(u64, Ipv4Addr)>
+    pub fn connected_nodes(&self) -> &Vec<(u64, SocketAddr)>
     {
         &self.connected_peers
     }
 }
+
+impl Encode for PeerType
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        (*self as u8).encode(buf);
+    }
+}
+
+impl Decode for PeerType
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        match u8::decode(reader)?
+        {
+            0 => Ok(PeerType::FullNode),
+            1 => Ok(PeerType::LightNode),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for Peer
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.id.encode(buf);
+        self.ptype.encode(buf);
+        self.address.encode(buf);
+        self.connected_peers.encode(buf);
+    }
+}
+
+impl Decode for Peer
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Peer::with_nodes(
+            u64::decode(reader)?,
+            PeerType::decode(reader)?,
+            SocketAddr::decode(reader)?,
+            Vec::decode(reader)?,
+        ))
+    }
+}
+
+/// the address as a single family, ipv4 peers of a
+/// dual-stack socket show up as ipv4-mapped ipv6 addresses
+pub fn canonical(addr: SocketAddr) -> SocketAddr
+{
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// resolve an ip address or a host name, each with an optional port
+pub fn resolve(host: &str, default_port: u16) -> io::Result<Vec<SocketAddr>>
+{
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        return Ok(vec![SocketAddr::new(ip, default_port)]);
+    }
+
+    let addrs = match host.rsplit_once(':')
+    {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_socket_addrs()?,
+        _ => (host, default_port).to_socket_addrs()?,
+    };
+
+    Ok(addrs.map(canonical).collect())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn resolve_hosts()
+    {
+        let v4: SocketAddr = "10.0.0.1:27182".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:27182".parse().unwrap();
+
+        assert_eq!(resolve("10.0.0.1", 27182).unwrap(), vec![v4]);
+        assert_eq!(resolve("10.0.0.1:27182", 1).unwrap(), vec![v4]);
+        assert_eq!(resolve("2001:db8::1", 27182).unwrap(), vec![v6]);
+        assert_eq!(resolve("[2001:db8::1]", 27182).unwrap(), vec![v6]);
+        assert_eq!(resolve("[2001:db8::1]:27182", 1).unwrap(), vec![v6]);
+
+        let local = resolve("localhost", 27182).unwrap();
+        assert!(!local.is_empty());
+        assert!(local
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 27182));
+
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:27182".parse().unwrap();
+        assert_eq!(canonical(mapped), v4);
+    }
+}
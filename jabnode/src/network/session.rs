@@ -0,0 +1,429 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use jabcoin::core::Encode;
+use jabcoin::network::{Header, Message};
+use log::trace;
+
+use crate::network::connection::{read_frame, write_frame};
+use crate::network::{Connection, FrameError, Version};
+
+/// a ping is sent after this long without sending anything
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// a session is closed after this long without receiving anything
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// time a single write may block before the peer is considered stuck
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// most messages waiting to be sent to a single peer
+pub const MAX_QUEUED: usize = 1024;
+
+/// most messages accepted from a peer within `RATE_WINDOW`
+pub const MAX_MESSAGES: u32 = 1000;
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// reasons for closing a session
+#[derive(Debug)]
+pub enum SessionError
+{
+    Frame(FrameError),
+
+    /// nothing was received for `IDLE_TIMEOUT`
+    Idle,
+
+    /// the peer doesn't read its messages fast enough
+    QueueFull,
+
+    /// the peer sent more than `MAX_MESSAGES` within `RATE_WINDOW`
+    Flooding,
+}
+
+impl fmt::Display for SessionError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            SessionError::Frame(e) => write!(f, "{e}"),
+            SessionError::Idle => write!(f, "idle for {}s", IDLE_TIMEOUT.as_secs()),
+            SessionError::QueueFull => write!(f, "more than {MAX_QUEUED} queued messages"),
+            SessionError::Flooding => write!(
+                f,
+                "more than {MAX_MESSAGES} messages within {}s",
+                RATE_WINDOW.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<FrameError> for SessionError
+{
+    fn from(e: FrameError) -> Self
+    {
+        match e
+        {
+            FrameError::Io(e)
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                SessionError::Idle
+            }
+            e => SessionError::Frame(e),
+        }
+    }
+}
+
+/// a long-lived connection to a peer after the handshake, used in both
+/// directions. a reader thread hands received messages to a handler and
+/// answers pings, a writer thread sends the queued messages
+pub struct Session
+{
+    /// the listening address of the peer, if it accepts connections
+    peer: SocketAddr,
+    version: Version,
+    inbound: bool,
+    stream: TcpStream,
+
+    queue: Mutex<VecDeque<Message>>,
+    cvar: Condvar,
+    closed: AtomicBool,
+    error: Mutex<Option<SessionError>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+
+    ping_interval: Duration,
+}
+
+impl Session
+{
+    /// start the threads of an established connection, `handler` is
+    /// called with every received message except pings and pongs
+    pub fn start<F>(
+        conn: Connection,
+        peer: SocketAddr,
+        version: Version,
+        inbound: bool,
+        handler: F,
+    ) -> Arc<Session>
+    where
+        F: Fn(Message) + Send + 'static,
+    {
+        Session::with_timeouts(
+            conn,
+            peer,
+            version,
+            inbound,
+            handler,
+            PING_INTERVAL,
+            IDLE_TIMEOUT,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn with_timeouts<F>(
+        conn: Connection,
+        peer: SocketAddr,
+        version: Version,
+        inbound: bool,
+        handler: F,
+        ping_interval: Duration,
+        idle_timeout: Duration,
+    ) -> Arc<Session>
+    where
+        F: Fn(Message) + Send + 'static,
+    {
+        let (stream, mut reader, mut writer) = conn.split();
+
+        // timeouts apply to all handles of the socket
+        let _ = stream.set_read_timeout(Some(idle_timeout));
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+
+        let session = Arc::new(Session {
+            peer,
+            version,
+            inbound,
+            stream,
+            queue: Mutex::new(VecDeque::new()),
+            cvar: Condvar::new(),
+            closed: AtomicBool::new(false),
+            error: Mutex::new(None),
+            threads: Mutex::new(vec![]),
+            ping_interval,
+        });
+
+        let slf = Arc::clone(&session);
+        let reader_thd = std::thread::spawn(move || slf.read_loop(&mut reader, handler));
+
+        let slf = Arc::clone(&session);
+        let writer_thd = std::thread::spawn(move || slf.write_loop(&mut writer));
+
+        *session.threads.lock().unwrap() = vec![reader_thd, writer_thd];
+        session
+    }
+
+    pub fn peer(&self) -> &SocketAddr
+    {
+        &self.peer
+    }
+
+    /// version the peer announced in the handshake
+    pub fn version(&self) -> &Version
+    {
+        &self.version
+    }
+
+    /// true if the peer opened the connection
+    pub fn is_inbound(&self) -> bool
+    {
+        self.inbound
+    }
+
+    pub fn is_closed(&self) -> bool
+    {
+        self.closed.load(Relaxed)
+    }
+
+    /// queue a message for the peer, returns false if the session is closed
+    pub fn send(&self, msg: Message) -> bool
+    {
+        if self.is_closed()
+        {
+            return false;
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_QUEUED
+        {
+            drop(queue);
+            self.fail(SessionError::QueueFull);
+            return false;
+        }
+
+        queue.push_back(msg);
+        self.cvar.notify_one();
+        true
+    }
+
+    /// stop reading, the messages queued so far are still sent
+    pub fn close(&self)
+    {
+        self.closed.store(true, Relaxed);
+        self.cvar.notify_all();
+    }
+
+    /// close the session for good, only the first error is kept
+    fn fail(&self, e: SessionError)
+    {
+        if !self.is_closed()
+        {
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+
+        self.queue.lock().unwrap().clear();
+        self.close();
+    }
+
+    /// the reason the session closed itself, if any
+    pub fn take_error(&self) -> Option<SessionError>
+    {
+        self.error.lock().unwrap().take()
+    }
+
+    /// wait until both threads finished, has to be
+    /// called after `close` and not from the handler
+    pub fn join(&self)
+    {
+        for thd in self.threads.lock().unwrap().drain(..)
+        {
+            let _ = thd.join();
+        }
+    }
+
+    fn read_loop<R: Read, F: Fn(Message)>(&self, reader: &mut R, handler: F)
+    {
+        let mut window = (Instant::now(), 0);
+
+        loop
+        {
+            let msg = match read_frame(reader)
+            {
+                Ok(msg) => msg,
+                Err(e) =>
+                {
+                    self.fail(e.into());
+                    break;
+                }
+            };
+
+            if self.is_closed()
+            {
+                break;
+            }
+
+            if window.0.elapsed() > RATE_WINDOW
+            {
+                window = (Instant::now(), 0);
+            }
+
+            window.1 += 1;
+            if window.1 > MAX_MESSAGES
+            {
+                self.fail(SessionError::Flooding);
+                break;
+            }
+
+            match msg.header
+            {
+                Header::Ping =>
+                {
+                    trace!("{}: received ping.", self.peer);
+                    self.send(Message::with_data(Header::Pong, &msg.body));
+                }
+                Header::Pong => trace!("{}: received pong.", self.peer),
+                _ => handler(msg),
+            }
+        }
+    }
+
+    fn write_loop<W: Write>(&self, writer: &mut W)
+    {
+        loop
+        {
+            let (msgs, timed_out) = {
+                let (mut queue, res) = self
+                    .cvar
+                    .wait_timeout_while(self.queue.lock().unwrap(), self.ping_interval, |q| {
+                        q.is_empty() && !self.is_closed()
+                    })
+                    .unwrap();
+
+                (queue.drain(..).collect::<Vec<_>>(), res.timed_out())
+            };
+
+            // the queue is flushed before the connection is shut down
+            if msgs.is_empty() && self.is_closed()
+            {
+                break;
+            }
+
+            let msgs = match timed_out
+            {
+                true => vec![Message::with_data(
+                    Header::Ping,
+                    &rand::random::<u64>().to_bytes(),
+                )],
+                false => msgs,
+            };
+
+            if let Some(e) = msgs.iter().find_map(|msg| write_frame(writer, msg).err())
+            {
+                self.fail(e.into());
+                break;
+            }
+        }
+
+        // wakes up the reader
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::network::PeerType;
+    use std::sync::mpsc;
+
+    fn connected_pair() -> (Connection, Connection)
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        (Connection::new(client), Connection::new(server))
+    }
+
+    fn start(
+        conn: Connection,
+        ping: Duration,
+        idle: Duration,
+    ) -> (Arc<Session>, mpsc::Receiver<Message>)
+    {
+        let version = Version::new(vec![], 0, PeerType::FullNode, 27182, 0);
+        let (tx, rx) = mpsc::channel();
+        let handler = move |msg| tx.send(msg).unwrap();
+
+        let peer = conn.get_peer_addr().unwrap();
+        let session = Session::with_timeouts(conn, peer, version, false, handler, ping, idle);
+        (session, rx)
+    }
+
+    #[test]
+    fn keepalive()
+    {
+        let (a, b) = connected_pair();
+        let (ping, idle) = (Duration::from_millis(20), Duration::from_millis(200));
+        let (a, a_rx) = start(a, ping, idle);
+        let (b, b_rx) = start(b, ping, idle);
+
+        // pings keep the idle sessions open and never reach the handler
+        std::thread::sleep(idle * 3);
+        assert!(!a.is_closed() && !b.is_closed());
+
+        // both directions share the connection
+        let msg = Message::with_data(Header::OK, &[1]);
+        assert!(a.send(msg.clone()));
+        assert!(b.send(msg.clone()));
+        assert_eq!(b_rx.recv_timeout(idle).unwrap(), msg);
+        assert_eq!(a_rx.recv_timeout(idle).unwrap(), msg);
+
+        // queued messages are flushed before closing
+        a.send(Message::with_data(Header::ERR, &[]));
+        a.close();
+        a.join();
+        assert_eq!(b_rx.recv_timeout(idle).unwrap().header, Header::ERR);
+
+        b.join();
+        assert!(b.is_closed());
+        assert!(matches!(b.take_error(), Some(SessionError::Frame(e)) if e.is_eof()));
+    }
+
+    #[test]
+    fn idle_timeout()
+    {
+        let (a, _silent) = connected_pair();
+        let (a, _rx) = start(a, Duration::from_secs(10), Duration::from_millis(50));
+
+        a.join();
+        assert!(matches!(a.take_error(), Some(SessionError::Idle)));
+        assert!(!a.send(Message::with_data(Header::OK, &[])));
+    }
+
+    #[test]
+    fn flooding()
+    {
+        let (a, mut flood) = connected_pair();
+        let (a, rx) = start(a, Duration::from_secs(10), Duration::from_secs(10));
+
+        let msg = Message::with_data(Header::OK, &[]);
+        for _ in 0..=MAX_MESSAGES
+        {
+            if flood.write_msg(&msg).is_err()
+            {
+                break;
+            }
+        }
+
+        a.join();
+        assert!(matches!(a.take_error(), Some(SessionError::Flooding)));
+        assert_eq!(rx.try_iter().count(), MAX_MESSAGES as usize);
+    }
+}
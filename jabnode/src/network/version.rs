@@ -0,0 +1,189 @@
+use crate::network::{FrameError, PeerType};
+use jabcoin::core::encoding::{Decode, DecodeError, Encode, Reader};
+use jabcoin::network::{Header, MAGIC, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use std::fmt;
+
+/// what a node announces about itself at the start of every connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version
+{
+    version: u32,
+    network: [u8; 4],
+
+    /// hash of the first block, empty while the node has no blocks
+    genesis: Vec<u8>,
+    height: u64,
+    ptype: PeerType,
+
+    /// port the node accepts connections on
+    port: u16,
+
+    /// random number identifying the node, used to detect
+    /// connections to ourselves and duplicate connections
+    nonce: u64,
+}
+
+impl Version
+{
+    pub fn new(genesis: Vec<u8>, height: u64, ptype: PeerType, port: u16, nonce: u64) -> Version
+    {
+        Version {
+            version: PROTOCOL_VERSION,
+            network: MAGIC,
+            genesis,
+            height,
+            ptype,
+            port,
+            nonce,
+        }
+    }
+
+    pub fn version(&self) -> u32
+    {
+        self.version
+    }
+
+    pub fn genesis(&self) -> &Vec<u8>
+    {
+        &self.genesis
+    }
+
+    pub fn height(&self) -> u64
+    {
+        self.height
+    }
+
+    pub fn ptype(&self) -> PeerType
+    {
+        self.ptype
+    }
+
+    pub fn port(&self) -> u16
+    {
+        self.port
+    }
+
+    pub fn nonce(&self) -> u64
+    {
+        self.nonce
+    }
+
+    /// check whether we can talk to a node announcing `other`
+    pub fn check_compatible(&self, other: &Version) -> Result<(), String>
+    {
+        if other.network != self.network
+        {
+            return Err(format!("different network {:02x?}", other.network));
+        }
+
+        if other.version < MIN_PROTOCOL_VERSION
+        {
+            return Err(format!("outdated protocol version {}", other.version));
+        }
+
+        // a node without blocks can still join either chain
+        if !self.genesis.is_empty() && !other.genesis.is_empty() && self.genesis != other.genesis
+        {
+            return Err(String::from("different genesis block"));
+        }
+        Ok(())
+    }
+}
+
+impl Encode for Version
+{
+    fn encode(&self, buf: &mut Vec<u8>)
+    {
+        self.version.encode(buf);
+        buf.extend_from_slice(&self.network);
+        self.genesis.encode(buf);
+        self.height.encode(buf);
+        self.ptype.encode(buf);
+        self.port.encode(buf);
+        self.nonce.encode(buf);
+    }
+}
+
+impl Decode for Version
+{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>
+    {
+        Ok(Version {
+            version: u32::decode(reader)?,
+            network: reader.take(4)?.try_into().unwrap(),
+            genesis: Vec::decode(reader)?,
+            height: u64::decode(reader)?,
+            ptype: PeerType::decode(reader)?,
+            port: u16::decode(reader)?,
+            nonce: u64::decode(reader)?,
+        })
+    }
+}
+
+/// reasons for dropping a connection during the handshake
+#[derive(Debug)]
+pub enum HandshakeError
+{
+    Frame(FrameError),
+
+    /// the other side sent something else than the expected message
+    UnexpectedMessage(Header),
+
+    /// the version message couldn't be decoded
+    InvalidVersion(DecodeError),
+
+    /// the other side speaks a protocol or follows a chain we don't
+    Incompatible(String),
+}
+
+impl fmt::Display for HandshakeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            HandshakeError::Frame(e) => write!(f, "{e}"),
+            HandshakeError::UnexpectedMessage(h) => write!(f, "unexpected message {h:?}"),
+            HandshakeError::InvalidVersion(e) => write!(f, "invalid version: {e}"),
+            HandshakeError::Incompatible(e) => write!(f, "incompatible peer: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<FrameError> for HandshakeError
+{
+    fn from(e: FrameError) -> Self
+    {
+        HandshakeError::Frame(e)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn compatibility()
+    {
+        let local = Version::new(vec![1; 32], 10, PeerType::FullNode, 27182, 1);
+        assert_eq!(Version::from_bytes(&local.to_bytes()).unwrap(), local);
+
+        let fresh = Version::new(vec![], 0, PeerType::LightNode, 0, 2);
+        assert!(local.check_compatible(&fresh).is_ok());
+        assert!(fresh.check_compatible(&local).is_ok());
+
+        let fork = Version::new(vec![2; 32], 10, PeerType::FullNode, 27182, 1);
+        assert!(local.check_compatible(&fork).is_err());
+
+        let mut foreign = local.clone();
+        foreign.network = *b"XXXX";
+        assert!(local.check_compatible(&foreign).is_err());
+
+        let mut outdated = local.clone();
+        outdated.version = MIN_PROTOCOL_VERSION - 1;
+        assert!(local.check_compatible(&outdated).is_err());
+    }
+}
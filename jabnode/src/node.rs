@@ -1,22 +1,40 @@
 mod communication;
+mod download;
+mod mempool;
+mod miner;
 
-use crate::network::{Peer, PeerType};
+use crate::network::{Misbehaviour, Peer, PeerType, DEFAULT_PORT};
+use crate::store::{BlockStore, DiskChainStore, DiskStateStore, FsyncPolicy};
 use crate::KillToken;
 use communication::Communication;
 use communication::Job;
-use jabcoin::core::{crypto::Sha256Hash, Address, Block, Blockchain, Transaction};
-use jabcoin::network::{Header, Message};
+use download::Download;
+pub use download::{Phase, SyncStatus};
+use jabcoin::core::{
+    crypto::Sha256Hash, Address, Block, BlockHeader, Blockchain, ChainError, ChainParams,
+    ChainStore, Ledger, Transaction, TransactionProof,
+};
+use jabcoin::network::{Header, Message, ProofRequest, MAX_BLOCKS, MAX_HEADERS, MAX_PROOFS};
 use log::{error, info, warn};
-use std::collections::{HashMap, VecDeque};
-use std::net::Ipv4Addr;
+use mempool::{Mempool, MempoolError};
+use miner::Miner;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::ops::DerefMut;
+use std::path::Path;
 use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 pub struct Config
 {
     /// path to the block disk store
     pub blkpath: String,
 
+    /// when blocks written to the disk store are flushed
+    pub fsync: FsyncPolicy,
+
+    /// consensus parameters of the network
+    pub params: ChainParams,
+
     /// cache transactions inside the blockchain
     pub build_cache: bool,
 
@@ -24,55 +42,104 @@ pub struct Config
     pub mine: bool,
     pub count_chain_workers: usize,
 
+    /// beneficiary of mined blocks, required for mining
+    pub miner: Option<Address>,
+
+    /// minimum fee per 1000 bytes for a transaction to be queued and relayed
+    pub min_relay_fee: u64,
+
+    /// most bytes of unconfirmed transactions kept, those paying
+    /// the least per byte are evicted first
+    pub mempool_size: usize,
+
+    /// time after which unconfirmed transactions are dropped
+    pub mempool_expiry: Duration,
+
     /// listen and process network communication
     pub listen_communication: bool,
+
+    /// address to accept connections on, `[::]` accepts ipv4 connections as well
+    pub listen: SocketAddr,
     pub count_comm_workers: usize,
 
-    /// vector of known peers
-    pub peers: Vec<Peer>,
+    /// number of outbound connections the node tries to keep
+    pub target_outbound: usize,
+
+    /// most connections accepted from other nodes
+    pub max_inbound: usize,
+
+    /// seed peers as ip addresses or host names with an optional port,
+    /// added to the known addresses on start
+    pub seeds: Vec<String>,
 
     pub peer: Peer,
 }
 
 impl Config
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         blkpath: String,
+        fsync: FsyncPolicy,
+        params: ChainParams,
         build_cache: bool,
         mine: bool,
         count_chain_workers: usize,
+        miner: Option<Address>,
+        min_relay_fee: u64,
+        mempool_size: usize,
+        mempool_expiry: Duration,
         listen_communication: bool,
+        listen: SocketAddr,
         count_comm_workers: usize,
-        peers: Vec<Peer>,
+        target_outbound: usize,
+        max_inbound: usize,
+        seeds: Vec<String>,
         peer: Peer,
     ) -> Config
     {
         Config {
             blkpath,
+            fsync,
+            params,
             build_cache,
             mine,
             listen_communication,
+            listen,
             count_comm_workers,
+            target_outbound,
+            max_inbound,
             count_chain_workers,
-            peers,
+            miner,
+            min_relay_fee,
+            mempool_size,
+            mempool_expiry,
+            seeds,
             peer,
         }
     }
 
     pub fn with_default() -> Config
     {
-        let slf = Peer::new(0, PeerType::FullNode, [127, 0, 0, 1].into());
-        let p = Peer::new(1, PeerType::FullNode, [192, 168, 208, 115].into());
-        let peers = vec![p];
+        let slf = Peer::new(0, PeerType::FullNode, ([127, 0, 0, 1], DEFAULT_PORT).into());
 
         Config {
             blkpath: String::from("etc/blocks/"),
+            fsync: FsyncPolicy::Always,
+            params: ChainParams::default(),
             build_cache: true,
             mine: true,
             listen_communication: true,
+            listen: (Ipv6Addr::UNSPECIFIED, DEFAULT_PORT).into(),
             count_comm_workers: 4,
+            target_outbound: 8,
+            max_inbound: 32,
             count_chain_workers: 1,
-            peers,
+            miner: None,
+            min_relay_fee: 1,
+            mempool_size: 32_000_000,
+            mempool_expiry: Duration::from_secs(24 * 60 * 60),
+            seeds: vec![],
             peer: slf,
         }
     }
@@ -82,19 +149,21 @@ struct State
 {
     /// a cache of the state generated by  
     /// the blockchain
-    economy: HashMap<Address, u64>,
+    economy: Ledger,
 
-    /// queue of freestanding transactions
-    trx_queue: VecDeque<Transaction>,
+    /// freestanding transactions waiting to be mined
+    mempool: Mempool,
 
     chain: Blockchain,
+
     peer: Peer,
-    peers: Vec<Peer>,
 }
 
 pub struct Node
 {
     communication: Arc<Communication>,
+    download: Download,
+    miner: Arc<Miner>,
     state: Mutex<State>,
     cfg: Mutex<Config>,
     cvar: Condvar,
@@ -105,19 +174,21 @@ impl Node
 {
     pub fn new(cfg: Config, killtoken: Arc<KillToken>) -> Arc<Node>
     {
-        let peers = cfg.peers.clone();
-        let peer = cfg.peer.clone();
+        // other nodes connect to the port we listen on
+        let mut peer = cfg.peer.clone();
+        peer.set_address(SocketAddr::new(peer.address().ip(), cfg.listen.port()));
 
         let state = State {
-            economy: HashMap::new(),
-            trx_queue: VecDeque::new(),
-            chain: Blockchain::new(),
-            peers,
+            economy: Ledger::new(),
+            mempool: Mempool::new(cfg.mempool_size, cfg.mempool_expiry),
+            chain: Blockchain::with_params(cfg.params.clone()),
             peer,
         };
 
         Arc::new_cyclic(move |wk| Node {
             communication: Arc::new(Communication::new(wk.clone())),
+            download: Download::new(wk.clone()),
+            miner: Arc::new(Miner::new(wk.clone())),
             state: Mutex::new(state),
             cfg: Mutex::new(cfg),
             cvar: Condvar::new(),
@@ -129,39 +200,63 @@ impl Node
     {
         info!("received new transaction: {}.", trx.hash_str());
 
+        if self.download.is_syncing()
+        {
+            info!(
+                "ignoring transaction {} while synchronising.",
+                trx.hash_str()
+            );
+            return;
+        }
+
         if trx.check_validity()
         {
             let mut lg = self.state.lock().unwrap();
             let state = lg.deref_mut();
-            let q = &mut state.trx_queue;
 
-            self.cvar.notify_all();
-            if let Some(_) = q.iter().find(|t| **t == trx)
+            let min_relay_fee = self.cfg.lock().unwrap().min_relay_fee;
+
+            if (trx.fee() as u128) * 1000 < min_relay_fee as u128 * trx.size() as u128
             {
-                info!("transaction {} already queued.", trx.hash_str());
+                warn!(
+                    "{:<30} {} of {} bytes.",
+                    "rejected transaction paying fee",
+                    trx.fee(),
+                    trx.size()
+                );
+                return;
             }
-            else
+
+            state.mempool.expire(Instant::now());
+
+            match state.mempool.add(trx.clone(), &state.chain)
             {
-                q.push_back(trx.clone());
+                Ok(evicted) =>
+                {
+                    self.cvar.notify_all();
 
-                info!(
-                    "queued transaction {} at position {}.",
-                    trx.hash_str(),
-                    q.len()
-                );
+                    info!(
+                        "added transaction {} to the mempool, {} pending with {} bytes.",
+                        trx.hash_str(),
+                        state.mempool.len(),
+                        state.mempool.size()
+                    );
 
-                info!("broadcasting transaction to connected full-nodes.");
+                    for old in evicted
+                    {
+                        info!("{:<30} {}.", "evicted transaction", old.hash_str());
+                    }
 
-                let msg = Message::with_data(
-                    Header::BroadcastTransaction,
-                    &serde_json::to_string(&trx).unwrap(),
-                );
+                    info!("broadcasting transaction to connected full-nodes.");
 
-                for i in &state.peers
+                    let msg = Message::with_payload(Header::BroadcastTransaction, &trx);
+                    self.communication.broadcast(&msg, PeerType::FullNode, None);
+                }
+                Err(MempoolError::Known) =>
                 {
-                    self.communication
-                        .queue_job(Job::new(*i.address(), msg.clone()));
+                    info!("transaction {} already in the mempool.", trx.hash_str());
                 }
+                Err(e) => warn!("{:<30} {e}.", "rejected transaction with error"),
             }
         }
         else
@@ -170,109 +265,466 @@ impl Node
         }
     }
 
-    fn handle_new_block(&self, blk: Block, peer: &Ipv4Addr)
+    fn handle_new_block(&self, blk: Block, peer: &SocketAddr)
     {
         info!("{peer}: {:<30} {}.", "received new block", blk.hash_str());
+
+        let mut lg = self.state.lock().unwrap();
+        let state = lg.deref_mut();
+
+        if state.chain.contains(&blk.hash())
+        {
+            info!("block {} already known.", blk.hash_str());
+            return;
+        }
+
+        match self.add_block(state, blk.clone())
+        {
+            Ok(()) => self.broadcast_block(&blk, Some(peer)),
+            Err(ChainError::UnknownPredecessor) if !self.download.is_syncing() =>
+            {
+                // we are missing blocks, catch up with the peer
+                info!(
+                    "{peer}: {:<30} {}.",
+                    "unknown predecessor of block",
+                    blk.hash_str()
+                );
+                self.request_headers(state, *peer);
+            }
+            Err(e) => self.reject_block(e, peer),
+        }
     }
 
-    fn build_blockchain(&self)
+    /// ask a peer for the headers following our best chain
+    fn request_headers(&self, state: &State, peer: SocketAddr)
     {
-        info!("building blockchain from disk.");
+        let locator = state.chain.locator();
+        let msg = Message::with_payload(Header::RequestHeaders, &locator);
 
-        let mut blks = vec![];
+        self.communication.queue_job(Job::new(peer, msg));
+    }
 
-        if let Ok(it) = std::fs::read_dir(&self.cfg.lock().unwrap().blkpath)
+    fn handle_request_headers(&self, locator: Vec<Vec<u8>>, peer: &SocketAddr)
+    {
+        let headers = {
+            let state = self.state.lock().unwrap();
+            state.chain.headers_after(&locator, MAX_HEADERS)
+        };
+
+        info!("{peer}: {:<30} {}.", "sending headers", headers.len());
+
+        let msg = Message::with_payload(Header::Headers, &headers);
+        self.communication.queue_job(Job::new(*peer, msg));
+    }
+
+    fn handle_headers(&self, headers: Vec<BlockHeader>, peer: &SocketAddr)
+    {
+        info!("{peer}: {:<30} {}.", "received headers", headers.len());
+
+        if self.download.is_syncing()
         {
-            for i in it
+            self.download.handle_headers(headers, peer);
+            return;
+        }
+
+        let state = self.state.lock().unwrap();
+
+        let missing = match state.chain.missing_blocks(&headers)
+        {
+            Ok(missing) => missing,
+            Err(e) =>
             {
-                let contents = std::fs::read_to_string(i.unwrap().path()).unwrap();
+                warn!("{peer}: {:<30} {e}.", "rejected headers with error");
+                return;
+            }
+        };
 
-                if let Ok(blk) = serde_json::from_str::<Block>(&contents)
+        if !missing.is_empty()
+        {
+            // request the bodies batch by batch, the next batch
+            // is requested once this one is connected
+            let batch = &missing[..missing.len().min(MAX_BLOCKS)];
+            info!("{peer}: {:<30} {}.", "requesting blocks", batch.len());
+
+            let msg = Message::with_payload(Header::RequestBlock, batch);
+            self.communication.queue_job(Job::new(*peer, msg));
+        }
+        else if headers.len() == MAX_HEADERS
+        {
+            // all of them are known already, continue after the last one
+            let locator = vec![headers.last().unwrap().hash()];
+            let msg = Message::with_payload(Header::RequestHeaders, &locator);
+            self.communication.queue_job(Job::new(*peer, msg));
+        }
+        else
+        {
+            info!("{peer}: synchronised at height {}.", state.chain.len());
+        }
+    }
+
+    /// prove the transactions of the best chain touching the requested
+    /// addresses, stopping at the end of the block reaching `MAX_PROOFS`
+    fn handle_request_proofs(&self, req: ProofRequest, peer: &SocketAddr)
+    {
+        let proofs: Vec<TransactionProof> = {
+            let state = self.state.lock().unwrap();
+            let mut proofs = vec![];
+
+            let chain = state.chain.best_headers();
+
+            for header in chain.into_iter().skip(req.from as usize)
+            {
+                if proofs.len() >= MAX_PROOFS
                 {
-                    info!("processing block {}.", blk.hash_str());
-                    blks.push(blk);
+                    break;
                 }
-                else
+
+                let blk = match state.chain.get_block(&header.hash())
                 {
-                    todo!();
-                }
+                    Some(blk) => blk,
+                    None => break,
+                };
+
+                proofs.extend(
+                    blk.transactions()
+                        .iter()
+                        .filter(|t| req.addresses.iter().any(|a| t.involves(a)))
+                        .filter_map(|t| blk.transaction_proof(t)),
+                );
             }
+            proofs
+        };
+
+        info!("{peer}: {:<30} {}.", "sending proofs", proofs.len());
+
+        let msg = Message::with_payload(Header::Proofs, &proofs);
+        self.communication.queue_job(Job::new(*peer, msg));
+    }
+
+    fn handle_request_block(&self, hashes: Vec<Vec<u8>>, peer: &SocketAddr)
+    {
+        let blks: Vec<Block> = {
+            let state = self.state.lock().unwrap();
+            hashes
+                .iter()
+                .take(MAX_BLOCKS)
+                .filter_map(|h| state.chain.get_block(h))
+                .collect()
+        };
+
+        info!("{peer}: {:<30} {}.", "sending blocks", blks.len());
+
+        let msg = Message::with_payload(Header::Blocks, &blks);
+        self.communication.queue_job(Job::new(*peer, msg));
+    }
+
+    fn handle_blocks(&self, blks: Vec<Block>, peer: &SocketAddr)
+    {
+        info!("{peer}: {:<30} {}.", "received blocks", blks.len());
+
+        if self.download.is_syncing()
+        {
+            self.download.handle_blocks(blks, peer);
+            return;
         }
-        else
+
+        let mut lg = self.state.lock().unwrap();
+        let state = lg.deref_mut();
+        let mut connected = 0;
+
+        for blk in blks
         {
-            todo!();
+            if state.chain.contains(&blk.hash())
+            {
+                continue;
+            }
+
+            if let Err(e) = self.add_block(state, blk)
+            {
+                self.reject_block(e, peer);
+                return;
+            }
+            connected += 1;
         }
 
-        self.state.lock().unwrap().chain = Blockchain::try_from(blks).unwrap();
+        // continue with the next batch
+        if connected > 0
+        {
+            self.request_headers(state, *peer);
+        }
     }
 
-    fn build_cache(&self)
+    fn handle_mined_block(&self, blk: Block)
     {
-        let mut guard = self.state.lock().unwrap();
-        let state = guard.deref_mut();
+        info!("{:<30} {}.", "mined new block", blk.hash_str());
+
+        let mut lg = self.state.lock().unwrap();
+        let state = lg.deref_mut();
+
+        if let Err(e) = self.add_block(state, blk.clone())
+        {
+            warn!("{:<30} {e}.", "discarding mined block with error");
+            return;
+        }
 
-        let chain = &state.chain;
-        let cache = &mut state.economy;
+        self.broadcast_block(&blk, None);
+    }
+
+    /// insert a block into the chain and update everything depending on the best chain
+    fn add_block(&self, state: &mut State, blk: Block) -> Result<(), ChainError>
+    {
+        let reorg = state.chain.append_block(blk.clone())?;
+
+        let cfg = self.cfg.lock().unwrap();
+
+        if reorg.is_empty()
+        {
+            info!("stored block {} on a side branch.", blk.hash_str());
+            return Ok(());
+        }
+
+        self.miner.notify_new_head();
+
+        if !reorg.disconnected.is_empty()
+        {
+            warn!(
+                "reorganisation: disconnecting {} and connecting {} block(s).",
+                reorg.disconnected.len(),
+                reorg.connected.len()
+            );
+        }
+
+        for new in &reorg.connected
+        {
+            // transactions included in the block are no longer freestanding
+            state.mempool.remove_confirmed(new);
+        }
 
-        for (_, blk) in chain.get_blocks()
+        if !reorg.disconnected.is_empty()
         {
-            *cache.entry(blk.get_miner().clone()).or_insert(0) += 10;
+            // transactions of abandoned blocks are freestanding again
+            let count = state
+                .mempool
+                .readd_disconnected(&reorg.disconnected, &state.chain);
+            info!(
+                "returned {count} transaction(s) of {} block(s) to the mempool.",
+                reorg.disconnected.len()
+            );
+        }
 
-            for trx in blk.transactions()
+        if cfg.build_cache
+        {
+            state.economy = state.chain.ledger().clone();
+        }
+
+        // drop transactions which are no longer valid on top of the new best chain
+        let dropped =
+            state.mempool.revalidate(&state.chain).len() + state.mempool.expire(Instant::now());
+
+        if dropped > 0
+        {
+            info!("dropped {dropped} invalid or stale transaction(s) from the mempool.");
+        }
+
+        info!(
+            "new head {} at height {}.",
+            state.chain.head().unwrap().hash_str(),
+            state.chain.len()
+        );
+
+        Ok(())
+    }
+
+    /// peers sending blocks which break the rules get punished
+    fn reject_block(&self, e: ChainError, peer: &SocketAddr)
+    {
+        warn!("{peer}: {:<30} {e}.", "rejected block with error");
+
+        if e.is_invalid()
+        {
+            self.communication
+                .misbehaving(*peer, Misbehaviour::InvalidBlock);
+        }
+    }
+
+    fn broadcast_block(&self, blk: &Block, origin: Option<&SocketAddr>)
+    {
+        info!("broadcasting block to connected full-nodes.");
+
+        let msg = Message::with_payload(Header::BroadcastBlock, blk);
+        self.communication
+            .broadcast(&msg, PeerType::FullNode, origin);
+
+        // light clients follow the chain by its headers
+        let msg = Message::with_payload(Header::Headers, &vec![blk.header().clone()]);
+        self.communication
+            .broadcast(&msg, PeerType::LightNode, origin);
+    }
+
+    /// read the blocks of the legacy store, one json file per block
+    fn read_json_blocks(blkpath: &str) -> Vec<Block>
+    {
+        let mut blks = vec![];
+
+        let it = match std::fs::read_dir(blkpath)
+        {
+            Ok(it) => it,
+            Err(e) =>
             {
-                let inp = trx.input();
-                let out = trx.output();
+                error!("failed to read block directory {blkpath} with error {e}.");
+                return blks;
+            }
+        };
 
-                *cache.get_mut(inp.get_addr()).expect("wtf??") -= inp.get_value();
-                let mut change = inp.get_value();
+        for path in it.filter_map(|i| i.ok()).map(|i| i.path())
+        {
+            if path.extension().is_none_or(|ext| ext != "json")
+            {
+                continue;
+            }
 
-                for actor in out.transactors()
-                {
-                    change -= actor.get_value();
-                    *cache.entry(actor.get_addr().clone()).or_insert(0) += actor.get_value();
-                }
+            let blk = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str::<Block>(&s).map_err(|e| e.to_string()));
 
-                *cache.get_mut(blk.get_miner()).unwrap() += change;
+            match blk
+            {
+                Ok(blk) => blks.push(blk),
+                Err(e) => warn!("skipping block file {} with error {e}.", path.display()),
             }
         }
+
+        blks
     }
 
-    pub fn start(self: Arc<Self>)
+    /// load the chain of the block and state store in the data directory
+    fn build_blockchain(&self) -> Result<(), String>
     {
-        self.build_blockchain();
+        info!("building blockchain from disk.");
+
+        let (blkpath, fsync, params) = {
+            let cfg = self.cfg.lock().unwrap();
+            (cfg.blkpath.clone(), cfg.fsync, cfg.params.clone())
+        };
+
+        let store = BlockStore::open(&blkpath, fsync)
+            .map_err(|e| format!("failed to open block store in {blkpath} with error {e}"))?;
+
+        let blocks = DiskChainStore::new(store);
+
+        let path = Path::new(&blkpath).join("ledger.dat");
+        let ledger = DiskStateStore::open(&path, fsync).map_err(|e| {
+            format!(
+                "failed to open state store {} with error {e}",
+                path.display()
+            )
+        })?;
+
+        info!("loading {} block(s) from the block store.", blocks.len());
+
+        let mut chain = Blockchain::with_stores(params, Box::new(blocks), Box::new(ledger))
+            .map_err(|e| format!("failed to load the block store with error {e}"))?;
+
+        // blocks of the legacy store haven't been verified yet
+        if chain.is_empty()
+        {
+            let blks = Node::read_json_blocks(&blkpath);
+
+            if !blks.is_empty()
+            {
+                info!(
+                    "importing {} json block(s) into the block store.",
+                    blks.len()
+                );
+            }
+
+            if let Err(e) = chain.insert_blocks(blks)
+            {
+                error!("failed to import json blocks with error {e}.");
+            }
+        }
+
+        self.state.lock().unwrap().chain = chain;
+        Ok(())
+    }
+
+    fn build_cache(&self)
+    {
+        let mut guard = self.state.lock().unwrap();
+        let state = guard.deref_mut();
+
+        state.economy = state.chain.ledger().clone();
+    }
+
+    /// progress of the initial block download
+    pub fn sync_status(&self) -> SyncStatus
+    {
+        self.download.status()
+    }
+
+    /// addresses of the peers we have a session with
+    pub fn peers(&self) -> Vec<SocketAddr>
+    {
+        self.communication.peers()
+    }
+
+    /// run the node until its kill token is activated, fails if
+    /// the chain can't be loaded from the data directory
+    pub fn start(self: Arc<Self>) -> Result<(), String>
+    {
+        self.build_blockchain()?;
 
         if self.cfg.lock().unwrap().build_cache
         {
             self.build_cache();
         }
 
-        let peers = {
-            let state = self.state.lock().unwrap();
-            state.peers.clone()
-        };
+        // connect to our peers before anything else
+        self.communication.load_peers();
+        let peers = self.communication.connect_outbound();
 
-        self.state.lock().unwrap().peers = peers
-            .into_iter()
-            .filter(|peer| {
-                if let Err(_) = self.communication.register_to_peer(peer.address().clone())
+        let com_arc = Arc::clone(&self.communication);
+        let com_thread = std::thread::spawn(move || com_arc.start());
+
+        // catch up with our peers before mining
+        let synced = self.download.run(peers, &self.killtoken);
+
+        let miner_thread = {
+            let cfg = self.cfg.lock().unwrap();
+
+            match (synced && cfg.mine, cfg.miner.clone())
+            {
+                (true, Some(miner)) =>
+                {
+                    let miner_arc = Arc::clone(&self.miner);
+                    let count_workers = cfg.count_chain_workers;
+                    Some(std::thread::spawn(move || {
+                        miner_arc.start(miner, count_workers)
+                    }))
+                }
+                (true, None) =>
                 {
-                    error!("failed to connect to root node.");
-                    return false;
+                    error!("not mining without an address receiving the rewards.");
+                    None
                 }
-                true
-            })
-            .collect();
+                (false, _) => None,
+            }
+        };
 
-        let com_arc = Arc::clone(&self.communication);
-        let com_thread = std::thread::spawn(move || com_arc.start());
+        if synced
+        {
+            self.killtoken.wait_on();
+        }
 
-        while !self.killtoken.wait_on()
+        if let Some(miner_thread) = miner_thread
         {
-            continue;
+            self.miner.request_stop();
+            miner_thread.join().unwrap();
         }
 
         self.communication.request_stop();
         com_thread.join().unwrap();
+        Ok(())
     }
 }
 
@@ -1,33 +1,44 @@
-use crate::network::{Connection, Peer};
+use crate::network::addrman::MAX_ADDRS;
+use crate::network::peer::{canonical, resolve};
+use crate::network::{
+    AddrMan, Connection, FrameError, Misbehaviour, Peer, PeerType, Session, SessionError, Version,
+    DEFAULT_PORT,
+};
 use crate::node::Node;
 use crate::threadpool::ThreadPool;
 use jabcoin::{
-    core::{Block, Transaction},
-    network::{Header, Message},
+    core::{crypto::Sha256Hash, Block, BlockHeader, DecodeError, Transaction},
+    network::{Header, Message, ProofRequest},
 };
 use log::{debug, error, info, trace, warn};
-use serde_json::error::Category;
-use std::collections::VecDeque;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, Ordering::Relaxed},
     Arc, Condvar, Mutex, Weak,
 };
 use std::time::{Duration, Instant};
 
-const PORT: u16 = 27182;
+/// how often closed sessions are cleaned up and missing
+/// outbound connections are opened
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how often the address manager is written to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Job
 {
     schedule: Option<(Instant, Duration)>,
-    peer_addr: Ipv4Addr,
+    peer_addr: SocketAddr,
     msg: Message,
 }
 
 impl Job
 {
-    pub fn new(peer_addr: Ipv4Addr, msg: Message) -> Job
+    pub fn new(peer_addr: SocketAddr, msg: Message) -> Job
     {
         Job {
             schedule: None,
@@ -36,7 +47,8 @@ impl Job
         }
     }
 
-    pub fn _with_schedule(schedule: (Instant, Duration), peer_addr: Ipv4Addr, msg: Message) -> Job
+    pub fn _with_schedule(schedule: (Instant, Duration), peer_addr: SocketAddr, msg: Message)
+        -> Job
     {
         Job {
             schedule: Some(schedule),
@@ -56,11 +68,25 @@ enum ComMessage
 #[derive(PartialEq, Eq)]
 enum Status
 {
+    /// the communication thread hasn't been started
+    Idle,
     Running,
     ShuttingDown,
     Shutdown,
 }
 
+#[derive(Default)]
+struct Peers
+{
+    /// one session per peer, used in both directions. outbound sessions
+    /// are keyed by the address we connected to, inbound ones by the
+    /// address of the socket
+    sessions: HashMap<SocketAddr, Arc<Session>>,
+
+    /// outbound connections being set up and the messages waiting for them
+    connecting: HashMap<SocketAddr, Vec<Message>>,
+}
+
 pub struct Communication
 {
     node: Weak<Node>,
@@ -68,6 +94,14 @@ pub struct Communication
     cvar: Arc<Condvar>,
     shutdown: AtomicBool,
     status: Mutex<Status>,
+
+    /// signalled once the communication thread has shut down
+    stopped: Condvar,
+    peers: Mutex<Peers>,
+    addrman: Mutex<AddrMan>,
+
+    /// identifies us in the handshake
+    nonce: u64,
 }
 
 impl Communication
@@ -79,146 +113,626 @@ impl Communication
             work_queue: Mutex::new(VecDeque::new()),
             cvar: Arc::new(Condvar::new()),
             shutdown: AtomicBool::new(false),
-            status: Mutex::new(Status::Running),
+            status: Mutex::new(Status::Idle),
+            stopped: Condvar::new(),
+            peers: Mutex::new(Peers::default()),
+            addrman: Mutex::new(AddrMan::new()),
+            nonce: rand::random(),
         }
     }
 
     pub fn start(self: Arc<Self>)
     {
-        let listener_arc = Arc::clone(&self);
-        let listener_thd = std::thread::spawn(move || {
-            listener_arc.listen_communication();
-        });
+        *self.status.lock().unwrap() = Status::Running;
 
-        let tp = ThreadPool::new(3);
-
-        let execute = |job: Job| match Connection::new_try_peer_addr(job.peer_addr, PORT)
-        {
-            Ok(mut conn) =>
-            {
-                if let Err(e) = conn.write_msg(&job.msg)
-                {
-                    warn!(
-                        "failed to write message to {} with error {e}.",
-                        job.peer_addr
-                    );
-                }
-                else
-                {
-                    debug!(
-                        "wrote message with header {:?} to {}.",
-                        job.msg.header, job.peer_addr
-                    );
-                }
-            }
-            Err(e) =>
-            {
-                warn!("failed to connect to {} with error {e}.", job.peer_addr);
-            }
+        let listen = {
+            let node = self.node.upgrade().unwrap();
+            let cfg = node.cfg.lock().unwrap();
+            cfg.listen_communication.then_some(cfg.listen)
         };
 
-        while !Arc::clone(&self).shutdown.load(Relaxed)
+        let listeners = listen.map(Communication::bind).unwrap_or_default();
+
+        // connecting to a listener wakes it up for shutting down
+        let wake: Vec<SocketAddr> = listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .map(|a| match a.ip()
+            {
+                IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), a.port()),
+                IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), a.port()),
+            })
+            .collect();
+
+        let listener_thds: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| {
+                let listener_arc = Arc::clone(&self);
+                std::thread::spawn(move || listener_arc.listen_communication(listener))
+            })
+            .collect();
+
+        let mut saved = Instant::now();
+
+        while !self.shutdown.load(Relaxed)
         {
-            trace!("waiting on cvar.");
+            // take all queued messages at once, jobs queued
+            // before we started waiting are processed right away
+            let cmsgs: Vec<ComMessage> = self
+                .cvar
+                .wait_timeout_while(self.work_queue.lock().unwrap(), MAINTENANCE_INTERVAL, |q| {
+                    q.is_empty() && !self.shutdown.load(Relaxed)
+                })
+                .unwrap()
+                .0
+                .drain(..)
+                .collect();
+
+            for cmsg in cmsgs
             {
-                // scope to kill lockguard, we just do this for the cvar wait
-                let _q_guard = self.cvar.wait(self.work_queue.lock().unwrap()).unwrap();
-            }
-            trace!("woke up.");
-
-            let cp = Arc::clone(&self);
-            tp.execute(move || {
-                while let Some(cmsg) = cp.work_queue.lock().unwrap().pop_back()
+                match cmsg
                 {
-                    match cmsg
+                    ComMessage::Work(job) =>
                     {
-                        ComMessage::Work(job) =>
+                        if let Some((instant, duration)) = job.schedule
                         {
-                            if let Some((instant, duration)) = job.schedule
+                            if instant.elapsed() > duration
                             {
-                                if instant.elapsed() > duration
-                                {
-                                    execute(job);
-                                }
-                                else
-                                {
-                                    cp.queue_job(job);
-                                }
+                                self.dispatch(job);
                             }
                             else
                             {
-                                execute(job);
+                                self.queue_job(job);
                             }
                         }
-                        ComMessage::Terminate =>
+                        else
                         {
-                            cp.shutdown.store(true, Relaxed);
-                            *cp.status.lock().unwrap() = Status::ShuttingDown;
+                            self.dispatch(job);
                         }
                     }
+                    ComMessage::Terminate =>
+                    {
+                        self.shutdown.store(true, Relaxed);
+                        *self.status.lock().unwrap() = Status::ShuttingDown;
+                    }
                 }
-            });
+            }
+
+            if !self.shutdown.load(Relaxed)
+            {
+                self.maintain();
+            }
+
+            if saved.elapsed() > SAVE_INTERVAL
+            {
+                self.save_peers();
+                saved = Instant::now();
+            }
         }
 
-        debug!("trying to shut down listener thread ..");
+        debug!("trying to shut down listener threads ..");
         self.shutdown.store(true, Relaxed);
 
-        match Connection::new_try_peer_addr(Ipv4Addr::new(127, 0, 0, 1), PORT)
+        // result doesn't matter, either way the listener should have received the shutdown
+        // signal
+        for addr in wake
         {
-            // result doesn't matter, either way the listener should have received the shutdown
-            // signal
-            _ => (),
+            let _ = Connection::new_try_peer_addr(addr);
         }
 
-        listener_thd.join().unwrap();
-        debug!("listener thread shutdown.");
+        for thd in listener_thds
+        {
+            thd.join().unwrap();
+        }
+        debug!("listener threads shutdown.");
+
+        let sessions: Vec<Arc<Session>> = {
+            let mut peers = self.peers.lock().unwrap();
+            peers.connecting.clear();
+            peers.sessions.drain().map(|(_, s)| s).collect()
+        };
+
+        // the deregister messages are still sent before closing
+        for session in &sessions
+        {
+            session.close();
+        }
+
+        for session in sessions
+        {
+            session.join();
+        }
+        debug!("closed all sessions.");
+
+        self.save_peers();
         *self.status.lock().unwrap() = Status::Shutdown;
+        self.stopped.notify_all();
+    }
+
+    fn peers_path(&self) -> PathBuf
+    {
+        let node = self.node.upgrade().unwrap();
+        let cfg = node.cfg.lock().unwrap();
+        Path::new(&cfg.blkpath).join("peers.dat")
     }
 
-    pub fn register_to_peer(&self, peer: Ipv4Addr) -> Result<(), ()>
+    /// read the known addresses from disk and add the seed peers
+    pub fn load_peers(&self)
     {
-        info!("trying to register to {peer}.");
-        let slf_str = {
-            let state_grd = self.node.upgrade().unwrap();
-            let state = state_grd.state.lock().unwrap();
-            serde_json::to_string(&state.peer).unwrap()
+        let path = self.peers_path();
+        let mut addrman = match AddrMan::load(&path)
+        {
+            Ok(addrman) => addrman,
+            Err(e) =>
+            {
+                error!(
+                    "failed to read peers file {} with error {e}.",
+                    path.display()
+                );
+                AddrMan::new()
+            }
         };
 
-        let msg = Message::with_data(Header::Register, &slf_str);
-        if let Ok(mut conn) = Connection::new_try_peer_addr(peer, PORT)
+        let seeds = self
+            .node
+            .upgrade()
+            .unwrap()
+            .cfg
+            .lock()
+            .unwrap()
+            .seeds
+            .clone();
+        for seed in seeds
         {
-            if let Ok(_) = conn.write_msg(&msg)
+            match resolve(&seed, DEFAULT_PORT)
             {
-                info!("registered to {peer}.");
-                Ok(())
+                Ok(addrs) =>
+                {
+                    for addr in addrs
+                    {
+                        addrman.add(addr, addr.ip());
+                    }
+                }
+                Err(e) => warn!("failed to resolve seed {seed} with error {e}."),
             }
-            else
+        }
+
+        info!("loaded {} known peer address(es).", addrman.len());
+        *self.addrman.lock().unwrap() = addrman;
+    }
+
+    fn save_peers(&self)
+    {
+        let path = self.peers_path();
+
+        if let Err(e) = self.addrman.lock().unwrap().save(&path)
+        {
+            error!(
+                "failed to write peers file {} with error {e}.",
+                path.display()
+            );
+        }
+    }
+
+    /// what we announce about ourselves at the start of every connection
+    fn local_version(&self) -> Version
+    {
+        let node = self.node.upgrade().unwrap();
+
+        // a port of 0 tells the other side not to connect to us
+        let port = {
+            let cfg = node.cfg.lock().unwrap();
+            match cfg.listen_communication
             {
-                warn!("failed to write message to {peer}.");
+                true => cfg.listen.port(),
+                false => 0,
+            }
+        };
+
+        let state = node.state.lock().unwrap();
+        let genesis = state.chain.genesis().map(|g| g.hash()).unwrap_or_default();
+        Version::new(
+            genesis,
+            state.chain.len(),
+            state.peer.ptype(),
+            port,
+            self.nonce,
+        )
+    }
+
+    /// open a connection to `peer` and complete the handshake
+    fn connect(&self, peer: SocketAddr) -> Result<(Connection, Version), ()>
+    {
+        let mut conn = match Connection::new_try_peer_addr(peer)
+        {
+            Ok(conn) => conn,
+            Err(e) =>
+            {
+                warn!("failed to connect to {peer} with error {e}.");
+                return Err(());
+            }
+        };
+
+        match conn.handshake(&self.local_version())
+        {
+            Ok(version) if version.nonce() == self.nonce =>
+            {
+                debug!("{peer}: connected to ourselves.");
+                Err(())
+            }
+            Ok(version) =>
+            {
+                trace!("{peer}: completed handshake, {version:?}.");
+                Ok((conn, version))
+            }
+            Err(e) =>
+            {
+                warn!("{peer}: {:<30} {e}.", "handshake failed with error");
                 Err(())
             }
         }
-        else
+    }
+
+    /// start a session on an established connection, unless
+    /// we have another session with the same peer already
+    fn add_session(
+        self: &Arc<Self>,
+        peers: &mut Peers,
+        conn: Connection,
+        peer: SocketAddr,
+        version: Version,
+        inbound: bool,
+    ) -> Option<Arc<Session>>
+    {
+        if self.shutdown.load(Relaxed)
+        {
+            return None;
+        }
+
+        // the same node is recognized by its nonce, a session
+        // of another node is never replaced
+        let old = peers.sessions.iter().find(|(p, s)| {
+            p.ip() == peer.ip() && s.version().nonce() == version.nonce() && !s.is_closed()
+        });
+
+        if let Some((_, old)) = old
+        {
+            // both sides may have connected to each other at the same time,
+            // each of them keeps the connection opened by the lower nonce
+            let initiator = |inbound: bool, version: &Version| match inbound
+            {
+                true => version.nonce(),
+                false => self.nonce,
+            };
+
+            if initiator(inbound, &version) > initiator(old.is_inbound(), old.version())
+            {
+                debug!("{peer}: dropping duplicate connection.");
+                return None;
+            }
+
+            debug!("{peer}: replacing duplicate connection.");
+            old.close();
+        }
+
+        let com = Arc::downgrade(self);
+        let session = Session::start(conn, peer, version, inbound, move |msg| {
+            if let Some(com) = com.upgrade()
+            {
+                com.parse_msg(msg, &peer);
+            }
+        });
+
+        info!(
+            "{peer}: {:<30} {}.",
+            "started session",
+            if inbound { "inbound" } else { "outbound" }
+        );
+
+        peers.sessions.insert(peer, Arc::clone(&session));
+        Some(session)
+    }
+
+    /// note an outbound connection attempt, returns false if one is underway already
+    fn begin_connect(&self, peer: SocketAddr, pending: Vec<Message>) -> bool
+    {
+        let mut peers = self.peers.lock().unwrap();
+
+        if let Some(msgs) = peers.connecting.get_mut(&peer)
+        {
+            msgs.extend(pending);
+            return false;
+        }
+
+        peers.connecting.insert(peer, pending);
+        self.addrman.lock().unwrap().attempt(&peer);
+        true
+    }
+
+    /// connect to `peer` and hand it the messages queued in the meantime,
+    /// returns true if a session has been started
+    fn finish_connect(self: &Arc<Self>, peer: SocketAddr) -> bool
+    {
+        let conn = self.connect(peer);
+        let slf = self
+            .node
+            .upgrade()
+            .unwrap()
+            .state
+            .lock()
+            .unwrap()
+            .peer
+            .clone();
+
+        let mut lg = self.peers.lock().unwrap();
+        let peers = &mut *lg;
+        let pending = peers.connecting.remove(&peer).unwrap_or_default();
+
+        let session = conn
+            .ok()
+            .and_then(|(conn, version)| self.add_session(peers, conn, peer, version, false));
+
+        match session
+        {
+            Some(session) =>
+            {
+                self.addrman.lock().unwrap().good(peer);
+
+                // the peer answers with the addresses it knows
+                session.send(Message::with_payload(Header::Register, &slf));
+
+                for msg in pending
+                {
+                    session.send(msg);
+                }
+                true
+            }
+            None =>
+            {
+                if !pending.is_empty()
+                {
+                    warn!("{peer}: dropping {} queued message(s).", pending.len());
+                }
+                false
+            }
+        }
+    }
+
+    /// connect to up to `target_outbound` peers and wait for the
+    /// handshakes, returns the peers we are connected to
+    pub fn connect_outbound(self: &Arc<Self>) -> Vec<SocketAddr>
+    {
+        let target = self
+            .node
+            .upgrade()
+            .unwrap()
+            .cfg
+            .lock()
+            .unwrap()
+            .target_outbound;
+        let mut tried = HashSet::new();
+        let mut connected = vec![];
+
+        while connected.len() < target
         {
-            warn!("failed to connect to {peer}.");
-            Err(())
+            let peer = match self.addrman.lock().unwrap().select(|a| tried.contains(a))
+            {
+                Some(peer) => peer,
+                None => break,
+            };
+
+            tried.insert(peer);
+
+            if self.begin_connect(peer, vec![]) && self.finish_connect(peer)
+            {
+                connected.push(peer);
+            }
         }
+
+        info!("connected to {} peer(s).", connected.len());
+        connected
     }
 
-    pub fn request_peers(&self, peer_addr: Ipv4Addr)
+    /// send the message of a job, connecting to the peer first if necessary
+    fn dispatch(self: &Arc<Self>, job: Job)
     {
-        info!("requesting peers from {peer_addr}.");
-        let slf_str = {
-            let state_grd = self.node.upgrade().unwrap();
-            let state = state_grd.state.lock().unwrap();
-            serde_json::to_string(&state.peer).unwrap()
+        let peer = job.peer_addr;
+
+        if let Some(session) = self
+            .peers
+            .lock()
+            .unwrap()
+            .sessions
+            .get(&peer)
+            .filter(|s| !s.is_closed())
+        {
+            if session.send(job.msg)
+            {
+                trace!("{peer}: queued message.");
+            }
+            return;
+        }
+
+        if self.addrman.lock().unwrap().is_banned(&peer.ip())
+        {
+            debug!("{peer}: not connecting to banned peer.");
+            return;
+        }
+
+        if self.begin_connect(peer, vec![job.msg])
+        {
+            let com = Arc::clone(self);
+            std::thread::spawn(move || com.finish_connect(peer));
+        }
+    }
+
+    /// forget closed sessions and open outbound connections
+    /// until we have `target_outbound` of them again
+    fn maintain(self: &Arc<Self>)
+    {
+        let closed: Vec<Arc<Session>> = {
+            let mut peers = self.peers.lock().unwrap();
+            let closed = peers
+                .sessions
+                .values()
+                .filter(|s| s.is_closed())
+                .cloned()
+                .collect();
+
+            peers.sessions.retain(|_, s| !s.is_closed());
+            closed
+        };
+
+        for session in closed
+        {
+            let peer = *session.peer();
+
+            match session.take_error()
+            {
+                Some(SessionError::Frame(e)) if e.is_eof() => info!("{peer}: closed session."),
+                Some(SessionError::Frame(e @ FrameError::Io(_)))
+                | Some(SessionError::Frame(e @ FrameError::Truncated)) =>
+                {
+                    debug!("{peer}: {:<30} {e}.", "closed session with error");
+                }
+                Some(e @ SessionError::Frame(_)) =>
+                {
+                    // the rest of the stream couldn't be trusted anymore
+                    warn!("{peer}: {:<30} {e}.", "closed session with error");
+                    self.misbehaving(peer, Misbehaviour::MalformedMessage);
+                }
+                Some(e @ SessionError::Flooding) =>
+                {
+                    warn!("{peer}: {:<30} {e}.", "closed session with error");
+                    self.misbehaving(peer, Misbehaviour::Spam);
+                }
+                Some(e) => info!("{peer}: {:<30} {e}.", "closed session with error"),
+                None => info!("{peer}: closed session."),
+            }
+        }
+
+        let target = self
+            .node
+            .upgrade()
+            .unwrap()
+            .cfg
+            .lock()
+            .unwrap()
+            .target_outbound;
+
+        let (outbound, mut busy) = {
+            let peers = self.peers.lock().unwrap();
+            let outbound = peers.sessions.values().filter(|s| !s.is_inbound()).count()
+                + peers.connecting.len();
+            let busy: HashSet<SocketAddr> = peers
+                .sessions
+                .iter()
+                .map(|(p, s)| Communication::listen_addr(p, s))
+                .chain(peers.connecting.keys().copied())
+                .collect();
+
+            (outbound, busy)
         };
 
-        let msg = Message::with_data(Header::RequestPeers, &slf_str);
-        self.queue_job(Job::new(peer_addr, msg));
+        for _ in outbound..target
+        {
+            let peer = match self.addrman.lock().unwrap().select(|a| busy.contains(a))
+            {
+                Some(peer) => peer,
+                None => break,
+            };
+
+            busy.insert(peer);
+
+            if self.begin_connect(peer, vec![])
+            {
+                let com = Arc::clone(self);
+                std::thread::spawn(move || com.finish_connect(peer));
+            }
+        }
+    }
+
+    /// addresses of the peers we have a session with,
+    /// full nodes are listed with the address they listen on
+    pub fn peers(&self) -> Vec<SocketAddr>
+    {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .sessions
+            .iter()
+            .filter(|(_, s)| !s.is_closed())
+            .map(|(p, s)| Communication::listen_addr(p, s))
+            .collect()
+    }
+
+    /// the address a peer accepts connections on, if it advertises any
+    fn listen_addr(peer: &SocketAddr, session: &Session) -> SocketAddr
+    {
+        match (session.is_inbound(), session.version().port())
+        {
+            (true, port) if port != 0 => SocketAddr::new(peer.ip(), port),
+            _ => *peer,
+        }
+    }
+
+    /// send a message to all connected peers of the given type except `origin`
+    pub fn broadcast(&self, msg: &Message, ptype: PeerType, origin: Option<&SocketAddr>)
+    {
+        let peers = self.peers.lock().unwrap();
+
+        for (_, session) in peers
+            .sessions
+            .iter()
+            .filter(|(p, s)| Some(*p) != origin && s.version().ptype() == ptype)
+        {
+            session.send(msg.clone());
+        }
+    }
+
+    /// punish a peer, all sessions with a banned ip are disconnected
+    pub fn misbehaving(&self, peer: SocketAddr, what: Misbehaviour)
+    {
+        warn!("{peer}: {:<30} {what}.", "misbehaving peer sent");
+
+        if self.addrman.lock().unwrap().misbehaving(peer.ip(), what)
+        {
+            warn!("{}: banned.", peer.ip());
+
+            let peers = self.peers.lock().unwrap();
+            for (_, session) in peers.sessions.iter().filter(|(p, _)| p.ip() == peer.ip())
+            {
+                session.close();
+            }
+        }
+    }
+
+    fn malformed(&self, peer: &SocketAddr, what: &str, e: DecodeError)
+    {
+        warn!(
+            "{peer}: {:<30} {e}.",
+            format!("failed to parse {what} with error")
+        );
+        self.misbehaving(*peer, Misbehaviour::MalformedMessage);
+    }
+
+    /// the addresses we share with other peers
+    fn peers_msg(&self, exclude: &SocketAddr) -> Message
+    {
+        let peers: Vec<Peer> = self
+            .addrman
+            .lock()
+            .unwrap()
+            .sample(MAX_ADDRS + 1)
+            .into_iter()
+            .filter(|a| a != exclude)
+            .take(MAX_ADDRS)
+            .map(|a| Peer::new(0, PeerType::FullNode, a))
+            .collect();
+
+        Message::with_payload(Header::BroadcastPeers, &peers)
     }
 
-    fn parse_msg(&self, msg: Message, peer_addr: &Ipv4Addr)
+    fn parse_msg(&self, msg: Message, peer_addr: &SocketAddr)
     {
         let node = &self.node.upgrade().unwrap();
         match msg.header
@@ -226,180 +740,232 @@ impl Communication
             Header::Register =>
             {
                 info!("{peer_addr}: received a register request.");
-                let peer = match serde_json::from_str::<Peer>(&msg.body)
-                {
-                    Ok(p) => Some(p),
-                    Err(e) =>
-                    {
-                        warn!(
-                            "{peer_addr}: {:<30} {e}.",
-                            "failed to parse request with error"
-                        );
-                        None
-                    }
-                };
 
-                if let Some(mut peer) = peer
+                match msg.payload::<Peer>()
                 {
-                    let mut state = node.state.lock().unwrap();
-                    peer.set_address(peer_addr.clone());
-                    if !state.peers.contains(&peer)
+                    Ok(peer) =>
                     {
-                        let peers_str = serde_json::to_string(&state.peers).unwrap();
-
-                        state.peers.push(peer);
+                        // the peer is reachable on the port it advertises
+                        if peer.ptype() == PeerType::FullNode && peer.address().port() != 0
+                        {
+                            let addr = SocketAddr::new(peer_addr.ip(), peer.address().port());
+                            self.addrman.lock().unwrap().add(addr, peer_addr.ip());
+                        }
 
-                        info!("registered {peer_addr}.");
-                        let msg = Message::with_data(Header::BroadcastPeers, &peers_str);
                         info!("sharing peers with {peer_addr}.");
-
-                        self.queue_job(Job::new(*peer_addr, msg));
-                    }
-                    else
-                    {
-                        info!("{peer_addr}: already registered.");
+                        self.queue_job(Job::new(*peer_addr, self.peers_msg(peer_addr)));
                     }
+                    Err(e) => self.malformed(peer_addr, "request", e),
                 }
             }
             Header::BroadcastPeers =>
             {
                 info!("{peer_addr}: received new peers.");
-                let mut new_peers = serde_json::from_str::<Vec<Peer>>(&msg.body).unwrap();
-
+                let new_peers = match msg.payload::<Vec<Peer>>()
                 {
-                    // only hold the mutex lock in this scope
-                    let state = node.state.lock().unwrap();
-                    new_peers = new_peers
-                        .into_iter()
-                        .filter(|p| !state.peers.contains(p))
-                        .collect();
-                }
-
-                let mut good_peers = vec![];
+                    Ok(peers) => peers,
+                    Err(e) => return self.malformed(peer_addr, "peers", e),
+                };
 
-                for i in new_peers
+                if new_peers.len() > MAX_ADDRS
                 {
-                    if let Ok(_) = self.register_to_peer(i.address().clone())
-                    {
-                        self.request_peers(*i.address());
-                        good_peers.push(i);
-                    }
+                    warn!(
+                        "{peer_addr}: {:<30} {}.",
+                        "sent too many peers",
+                        new_peers.len()
+                    );
+                    self.misbehaving(*peer_addr, Misbehaviour::Spam);
+                    return;
                 }
 
-                let mut state = node.state.lock().unwrap();
+                let mut addrman = self.addrman.lock().unwrap();
+                let added = new_peers
+                    .iter()
+                    .filter(|p| p.ptype() == PeerType::FullNode)
+                    .filter(|p| addrman.add(*p.address(), peer_addr.ip()))
+                    .count();
 
-                for i in good_peers
-                {
-                    state.peers.push(i);
-                }
+                info!("{peer_addr}: {:<30} {added}.", "learned new addresses");
             }
             Header::RequestPeers =>
             {
                 info!("{peer_addr}: received request nodes request.");
-
-                let state = node.state.lock().unwrap();
-                let peers = serde_json::to_string::<Vec<Peer>>(&state.peers).unwrap();
-
-                let msg = Message::with_data(Header::BroadcastPeers, &peers);
-                self.queue_job(Job::new(*peer_addr, msg));
+                self.queue_job(Job::new(*peer_addr, self.peers_msg(peer_addr)));
             }
             Header::Deregister =>
             {
                 info!("{peer_addr}: received deregister request.");
 
-                let mut state = node.state.lock().unwrap();
-
-                let idx = state.peers.iter().position(|p| p.address() == peer_addr);
-
-                if let Some(idx) = idx
+                if let Some(session) = self.peers.lock().unwrap().sessions.get(peer_addr)
                 {
-                    state.peers.swap_remove(idx);
-                    info!("removed peer {peer_addr}.");
-                }
-                else
-                {
-                    warn!("Did not find {peer_addr} in memory.");
+                    session.close();
                 }
             }
-            Header::BroadcastTransaction =>
+            Header::BroadcastTransaction => match msg.payload::<Transaction>()
             {
-                match serde_json::from_str::<Transaction>(&msg.body)
-                {
-                    Ok(t) =>
-                    {
-                        self.node.upgrade().unwrap().handle_new_transaction(t);
-                    }
-                    Err(e) =>
-                    {
-                        warn!("{peer_addr}: {:<30} {e}.", "failed to parse trx with error");
-                    }
-                };
-            }
-            Header::BroadcastBlock =>
+                Ok(t) => node.clone().handle_new_transaction(t),
+                Err(e) => self.malformed(peer_addr, "trx", e),
+            },
+            Header::BroadcastBlock => match msg.payload::<Block>()
             {
-                match serde_json::from_str::<Block>(&msg.body)
-                {
-                    Ok(b) =>
-                    {
-                        node.handle_new_block(b, peer_addr);
-                    }
-                    Err(e) =>
-                    {
-                        warn!(
-                            "{peer_addr}: {:<30} {e}.",
-                            "failed to parse block with error"
-                        );
-                    }
-                };
+                Ok(b) => node.handle_new_block(b, peer_addr),
+                Err(e) => self.malformed(peer_addr, "block", e),
+            },
+            Header::RequestHeaders => match msg.payload::<Vec<Vec<u8>>>()
+            {
+                Ok(locator) => node.handle_request_headers(locator, peer_addr),
+                Err(e) => self.malformed(peer_addr, "locator", e),
+            },
+            Header::Headers => match msg.payload::<Vec<BlockHeader>>()
+            {
+                Ok(headers) => node.handle_headers(headers, peer_addr),
+                Err(e) => self.malformed(peer_addr, "headers", e),
+            },
+            Header::RequestBlock => match msg.payload::<Vec<Vec<u8>>>()
+            {
+                Ok(hashes) => node.handle_request_block(hashes, peer_addr),
+                Err(e) => self.malformed(peer_addr, "hashes", e),
+            },
+            Header::Blocks => match msg.payload::<Vec<Block>>()
+            {
+                Ok(blks) => node.handle_blocks(blks, peer_addr),
+                Err(e) => self.malformed(peer_addr, "blocks", e),
+            },
+            Header::RequestProofs => match msg.payload::<ProofRequest>()
+            {
+                Ok(req) => node.handle_request_proofs(req, peer_addr),
+                Err(e) => self.malformed(peer_addr, "proof request", e),
+            },
+            header =>
+            {
+                warn!("{peer_addr}: {:<30} {header:?}.", "unexpected message");
+                self.misbehaving(*peer_addr, Misbehaviour::MalformedMessage);
             }
-            _ => todo!(),
         }
     }
 
-    fn handle_connection(&self, mut conn: Connection)
+    fn handle_connection(self: &Arc<Self>, mut conn: Connection)
     {
-        let peer = conn.get_peer_addr();
+        let peer = match conn.get_peer_addr()
+        {
+            Ok(peer) => peer,
+            Err(e) =>
+            {
+                debug!("failed to get address of new connection with error {e}.");
+                return;
+            }
+        };
         debug!("{peer}: new connection.");
 
-        loop
+        // nothing is processed before both sides agreed to talk
+        let version = match conn.handshake(&self.local_version())
+        {
+            Ok(version) if version.nonce() == self.nonce =>
+            {
+                debug!("{peer}: connected to ourselves.");
+                return;
+            }
+            Ok(version) => version,
+            Err(e) =>
+            {
+                warn!("{peer}: {:<30} {e}.", "handshake failed with error");
+                return;
+            }
+        };
+
+        trace!("{peer}: completed handshake, {version:?}.");
+
+        let mut peers = self.peers.lock().unwrap();
+        self.add_session(&mut peers, conn, peer, version, true);
+    }
+
+    /// listen on `listen`, the unspecified ipv6 address
+    /// stands for all interfaces of both ipv6 and ipv4
+    fn bind(listen: SocketAddr) -> Vec<TcpListener>
+    {
+        let mut addrs = vec![listen];
+        if listen.ip() == Ipv6Addr::UNSPECIFIED
+        {
+            addrs.push(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), listen.port()));
+        }
+
+        let mut listeners = vec![];
+
+        for addr in addrs
         {
-            // check msg validity
-            match conn.read_msg()
+            match TcpListener::bind(addr)
             {
-                Ok(m) => self.parse_msg(m, &peer),
-                Err(e) => match e.classify()
+                Ok(listener) =>
                 {
-                    Category::Eof =>
-                    {
-                        break;
-                    }
-                    Category::Io =>
-                    {
-                        error!("{peer}: failed to read msg with i/o error: {e}.");
-                    }
-                    Category::Syntax | Category::Data =>
-                    {
-                        warn!("{peer}: received invalid data with error: {e}.");
-                    }
-                },
+                    info!("listening on {addr}.");
+                    listeners.push(listener);
+                }
+                // a dual-stack socket accepts ipv4 connections already
+                Err(e) if e.kind() == ErrorKind::AddrInUse && !listeners.is_empty() => (),
+                Err(e) => warn!("failed to listen on {addr} with error {e}."),
             }
         }
+
+        if listeners.is_empty()
+        {
+            error!("not accepting any connections.");
+        }
+
+        listeners
     }
 
-    pub fn listen_communication(self: Arc<Self>)
+    pub fn listen_communication(self: Arc<Self>, listener: TcpListener)
     {
-        let listener = TcpListener::bind(SocketAddrV4::new([0, 0, 0, 0].into(), PORT)).unwrap();
-        let pool = ThreadPool::new(2);
+        let (handshakes, max_inbound) = {
+            let node = self.node.upgrade().unwrap();
+            let cfg = node.cfg.lock().unwrap();
+            (cfg.count_comm_workers, cfg.max_inbound)
+        };
+
+        // bounds the number of handshakes in progress
+        let pool = ThreadPool::new(handshakes);
 
         for stream in listener.incoming()
         {
-            if self.shutdown.load(Relaxed) == true
+            if self.shutdown.load(Relaxed)
             {
                 debug!("shutting down listener.");
                 break;
             }
 
-            let stream = stream.unwrap();
+            let stream = match stream
+            {
+                Ok(stream) => stream,
+                Err(e) =>
+                {
+                    warn!("failed to accept connection with error {e}.");
+                    continue;
+                }
+            };
+
+            let peer = match stream.peer_addr()
+            {
+                Ok(addr) => canonical(addr).ip(),
+                Err(_) => continue,
+            };
+
+            if self.addrman.lock().unwrap().is_banned(&peer)
+            {
+                debug!("{peer}: refusing connection of banned peer.");
+                continue;
+            }
+
+            let inbound = {
+                let peers = self.peers.lock().unwrap();
+                peers.sessions.values().filter(|s| s.is_inbound()).count()
+            };
+
+            if inbound >= max_inbound
+            {
+                debug!("{peer}: refusing connection, {inbound} inbound sessions.");
+                continue;
+            }
+
             let cpy = Arc::clone(&self);
 
             pool.execute(move || {
@@ -421,11 +987,9 @@ impl Communication
     {
         self.work_queue.lock().unwrap().clear();
 
-        for peer in &self.node.upgrade().unwrap().state.lock().unwrap().peers
+        for session in self.peers.lock().unwrap().sessions.values()
         {
-            let msg = Message::with_data(Header::Deregister, "");
-            let job = Job::new(*peer.address(), msg);
-            self.queue_job(job);
+            session.send(Message::with_data(Header::Deregister, &[]));
         }
 
         self.work_queue
@@ -434,10 +998,13 @@ impl Communication
             .push_back(ComMessage::Terminate);
         self.cvar.notify_one();
 
-        while *self.status.lock().unwrap() != Status::Shutdown
-        {
-            self.cvar.notify_one();
-        }
+        drop(
+            self.stopped
+                .wait_while(self.status.lock().unwrap(), |s| {
+                    matches!(s, Status::Running | Status::ShuttingDown)
+                })
+                .unwrap(),
+        );
     }
 }
 
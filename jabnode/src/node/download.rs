@@ -0,0 +1,578 @@
+use crate::node::communication::Job;
+use crate::node::Node;
+use crate::KillToken;
+use jabcoin::core::{crypto::Sha256Hash, Block, BlockHeader, ChainError};
+use jabcoin::network::{Header, Message, MAX_BLOCKS, MAX_HEADERS};
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::ops::DerefMut;
+use std::sync::{
+    atomic::{AtomicBool, Ordering::Relaxed},
+    Mutex, Weak,
+};
+use std::time::{Duration, Instant};
+
+/// time after which an unanswered request is sent to another peer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// number of batches of blocks requested from a single peer at once
+const MAX_IN_FLIGHT: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase
+{
+    /// collecting the header chain of the peer with the best tip
+    Headers,
+
+    /// downloading and connecting the blocks of the header chain
+    Blocks,
+
+    /// synchronised, the node mines and relays transactions
+    Done,
+}
+
+/// progress of the initial block download
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncStatus
+{
+    pub phase: Phase,
+    pub height: u64,
+    pub target_height: u64,
+}
+
+/// blocks requested from a peer
+struct Request
+{
+    peer: SocketAddr,
+    hashes: Vec<Vec<u8>>,
+    since: Instant,
+}
+
+struct Progress
+{
+    phase: Phase,
+
+    /// tip height advertised by each peer taking part in the download,
+    /// u64::MAX if the peer sent the maximum number of headers
+    peers: HashMap<SocketAddr, u64>,
+
+    /// first header responses, the best one is followed
+    candidates: HashMap<SocketAddr, Vec<BlockHeader>>,
+
+    /// peers whose headers we are waiting for
+    awaiting: Vec<SocketAddr>,
+    headers_since: Instant,
+
+    /// peer whose header chain is downloaded
+    best: Option<SocketAddr>,
+
+    /// last header of the header chain downloaded so far
+    last: Option<BlockHeader>,
+
+    /// hash and height of the blocks to download in chain order
+    queue: Vec<(Vec<u8>, u64)>,
+
+    /// position in `queue` of the next block to request
+    next_request: usize,
+
+    /// position in `queue` of the next block to connect
+    next_connect: usize,
+
+    /// batches to request again after a timeout or an invalid block
+    retry: VecDeque<Vec<Vec<u8>>>,
+    in_flight: Vec<Request>,
+
+    /// downloaded blocks waiting for their predecessor, with the sending peer
+    received: HashMap<Vec<u8>, (Block, SocketAddr)>,
+}
+
+impl Progress
+{
+    fn new() -> Progress
+    {
+        Progress {
+            phase: Phase::Done,
+            peers: HashMap::new(),
+            candidates: HashMap::new(),
+            awaiting: vec![],
+            headers_since: Instant::now(),
+            best: None,
+            last: None,
+            queue: vec![],
+            next_request: 0,
+            next_connect: 0,
+            retry: VecDeque::new(),
+            in_flight: vec![],
+            received: HashMap::new(),
+        }
+    }
+
+    fn target_height(&self) -> Option<u64>
+    {
+        self.last.as_ref().map(|h| h.height() + 1)
+    }
+
+    /// stop downloading from a peer, its outstanding requests go to the others
+    fn drop_peer(&mut self, peer: &SocketAddr)
+    {
+        self.peers.remove(peer);
+        self.awaiting.retain(|p| p != peer);
+
+        let (lost, kept) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|r| &r.peer == peer);
+
+        self.in_flight = kept;
+        self.retry
+            .extend(lost.into_iter().map(|r: Request| r.hashes));
+    }
+}
+
+/// initial block download, brings a freshly started node
+/// up to the best chain of its peers before it mines
+pub struct Download
+{
+    node: Weak<Node>,
+    progress: Mutex<Progress>,
+    syncing: AtomicBool,
+}
+
+impl Download
+{
+    pub fn new(node: Weak<Node>) -> Download
+    {
+        Download {
+            node,
+            progress: Mutex::new(Progress::new()),
+            syncing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_syncing(&self) -> bool
+    {
+        self.syncing.load(Relaxed)
+    }
+
+    pub fn status(&self) -> SyncStatus
+    {
+        let progress = self.progress.lock().unwrap();
+        let height = self
+            .node
+            .upgrade()
+            .unwrap()
+            .state
+            .lock()
+            .unwrap()
+            .chain
+            .len();
+
+        SyncStatus {
+            phase: progress.phase,
+            height,
+            target_height: progress.target_height().unwrap_or(height).max(height),
+        }
+    }
+
+    /// ask all peers for their headers and wait until the download finished,
+    /// returns false if the kill token has been activated in the meantime
+    pub fn run(&self, peers: Vec<SocketAddr>, killtoken: &KillToken) -> bool
+    {
+        if peers.is_empty()
+        {
+            info!("no peers to download blocks from, skipping initial block download.");
+            return true;
+        }
+
+        {
+            let mut progress = self.progress.lock().unwrap();
+            *progress = Progress::new();
+            progress.phase = Phase::Headers;
+            progress.awaiting = peers.clone();
+            progress.peers = peers.iter().map(|p| (*p, 0)).collect();
+            self.syncing.store(true, Relaxed);
+        }
+
+        info!(
+            "starting initial block download from {} peer(s).",
+            peers.len()
+        );
+
+        let node = self.node.upgrade().unwrap();
+        for peer in peers
+        {
+            node.request_headers(&node.state.lock().unwrap(), peer);
+        }
+
+        while self.is_syncing()
+        {
+            if killtoken.wait_timeout(Duration::from_secs(1))
+            {
+                return false;
+            }
+            self.tick();
+        }
+
+        true
+    }
+
+    fn finish(&self, progress: &mut Progress)
+    {
+        let height = self
+            .node
+            .upgrade()
+            .unwrap()
+            .state
+            .lock()
+            .unwrap()
+            .chain
+            .len();
+
+        progress.phase = Phase::Done;
+        progress.in_flight.clear();
+        progress.received.clear();
+        self.syncing.store(false, Relaxed);
+
+        info!("initial block download finished at height {height}.");
+    }
+
+    /// handle timed out requests
+    fn tick(&self)
+    {
+        let mut guard = self.progress.lock().unwrap();
+        let progress = guard.deref_mut();
+
+        match progress.phase
+        {
+            Phase::Headers =>
+            {
+                if progress.headers_since.elapsed() < REQUEST_TIMEOUT
+                {
+                    return;
+                }
+
+                for peer in progress.awaiting.clone()
+                {
+                    warn!("{peer}: {:<30}", "timed out sending headers.");
+                    progress.drop_peer(&peer);
+                }
+
+                if progress.best.is_none()
+                {
+                    self.follow_best(progress);
+                }
+                else
+                {
+                    // continue with the header chain we have so far
+                    self.start_blocks(progress);
+                }
+            }
+            Phase::Blocks =>
+            {
+                let timed_out: Vec<SocketAddr> = progress
+                    .in_flight
+                    .iter()
+                    .filter(|r| r.since.elapsed() > REQUEST_TIMEOUT)
+                    .map(|r| r.peer)
+                    .collect();
+
+                for peer in timed_out
+                {
+                    warn!("{peer}: {:<30}", "timed out sending blocks.");
+                    progress.drop_peer(&peer);
+                }
+
+                self.request_blocks(progress);
+            }
+            Phase::Done => (),
+        }
+    }
+
+    pub fn handle_headers(&self, headers: Vec<BlockHeader>, peer: &SocketAddr)
+    {
+        let mut guard = self.progress.lock().unwrap();
+        let progress = guard.deref_mut();
+
+        if progress.phase != Phase::Headers || !progress.awaiting.contains(peer)
+        {
+            debug!("{peer}: ignoring unrequested headers.");
+            return;
+        }
+        progress.awaiting.retain(|p| p != peer);
+
+        let node = self.node.upgrade().unwrap();
+        let checked = {
+            let state = node.state.lock().unwrap();
+            state
+                .chain
+                .missing_blocks_after(progress.last.as_ref(), &headers)
+        };
+
+        if let Err(e) = checked
+        {
+            warn!("{peer}: {:<30} {e}.", "rejected headers with error");
+            progress.drop_peer(peer);
+        }
+        else if progress.best.is_none()
+        {
+            // first round, collect the tips of all peers
+            let tip = match headers.last()
+            {
+                _ if headers.len() == MAX_HEADERS => u64::MAX,
+                Some(last) => last.height(),
+                None => 0,
+            };
+            progress.peers.insert(*peer, tip);
+            progress.candidates.insert(*peer, headers);
+        }
+        else
+        {
+            self.extend_headers(progress, headers);
+            return;
+        }
+
+        if progress.awaiting.is_empty() && progress.best.is_none()
+        {
+            self.follow_best(progress);
+        }
+    }
+
+    /// pick the peer with the best advertised tip and follow its headers
+    fn follow_best(&self, progress: &mut Progress)
+    {
+        let best = progress
+            .candidates
+            .iter()
+            .filter(|(peer, headers)| progress.peers.contains_key(peer) && !headers.is_empty())
+            .max_by_key(|(peer, _)| progress.peers[peer])
+            .map(|(peer, _)| *peer);
+
+        let best = match best
+        {
+            Some(best) => best,
+            None =>
+            {
+                info!("no peer knows blocks we are missing.");
+                self.finish(progress);
+                return;
+            }
+        };
+
+        info!("{best}: {:<30}", "following the best advertised tip.");
+
+        progress.best = Some(best);
+        let headers = progress.candidates.remove(&best).unwrap();
+        progress.candidates.clear();
+        self.extend_headers(progress, headers);
+    }
+
+    /// append headers of the best peer to the header chain
+    fn extend_headers(&self, progress: &mut Progress, headers: Vec<BlockHeader>)
+    {
+        let node = self.node.upgrade().unwrap();
+        let best = progress.best.unwrap();
+
+        let missing = {
+            let state = node.state.lock().unwrap();
+            state
+                .chain
+                .missing_blocks_after(progress.last.as_ref(), &headers)
+        };
+
+        let missing = match missing
+        {
+            Ok(missing) => missing,
+            Err(e) =>
+            {
+                warn!("{best}: {:<30} {e}.", "rejected headers with error");
+                progress.drop_peer(&best);
+                self.start_blocks(progress);
+                return;
+            }
+        };
+
+        for header in &headers
+        {
+            let hash = header.hash();
+            if missing.contains(&hash)
+            {
+                progress.queue.push((hash, header.height()));
+            }
+        }
+
+        let full = headers.len() == MAX_HEADERS;
+        if let Some(last) = headers.into_iter().last()
+        {
+            progress.last = Some(last);
+        }
+
+        info!(
+            "downloaded headers up to height {}.",
+            progress.target_height().unwrap_or(0)
+        );
+
+        if full
+        {
+            // the best peer knows more headers
+            let locator = vec![progress.last.as_ref().unwrap().hash()];
+            let msg = Message::with_payload(Header::RequestHeaders, &locator);
+
+            progress.awaiting = vec![best];
+            progress.headers_since = Instant::now();
+            node.communication.queue_job(Job::new(best, msg));
+        }
+        else
+        {
+            self.start_blocks(progress);
+        }
+    }
+
+    fn start_blocks(&self, progress: &mut Progress)
+    {
+        progress.awaiting.clear();
+
+        if progress.queue.is_empty()
+        {
+            self.finish(progress);
+            return;
+        }
+
+        info!("downloading {} block(s).", progress.queue.len());
+        progress.phase = Phase::Blocks;
+        self.request_blocks(progress);
+    }
+
+    /// hand out batches of blocks to all peers which have them
+    fn request_blocks(&self, progress: &mut Progress)
+    {
+        let node = self.node.upgrade().unwrap();
+
+        let mut peers: Vec<(SocketAddr, u64)> =
+            progress.peers.iter().map(|(p, t)| (*p, *t)).collect();
+        peers.sort();
+
+        if peers.is_empty()
+        {
+            warn!("no peers left to download blocks from.");
+            self.finish(progress);
+            return;
+        }
+
+        for (peer, tip) in peers
+        {
+            while progress.in_flight.iter().filter(|r| r.peer == peer).count() < MAX_IN_FLIGHT
+            {
+                let batch = match progress.retry.pop_front()
+                {
+                    Some(batch) => batch,
+                    None =>
+                    {
+                        let end = (progress.next_request + MAX_BLOCKS).min(progress.queue.len());
+                        let batch = &progress.queue[progress.next_request..end];
+
+                        // the peer doesn't know these blocks
+                        if batch.is_empty() || batch.iter().any(|(_, h)| *h > tip)
+                        {
+                            break;
+                        }
+
+                        let batch = batch.iter().map(|(hash, _)| hash.clone()).collect();
+                        progress.next_request = end;
+                        batch
+                    }
+                };
+
+                debug!("{peer}: {:<30} {}.", "requesting blocks", batch.len());
+
+                let msg = Message::with_payload(Header::RequestBlock, &batch);
+                node.communication.queue_job(Job::new(peer, msg));
+
+                progress.in_flight.push(Request {
+                    peer,
+                    hashes: batch,
+                    since: Instant::now(),
+                });
+            }
+        }
+
+        if progress.in_flight.is_empty()
+        {
+            warn!("no peer has the remaining blocks.");
+            self.finish(progress);
+        }
+    }
+
+    pub fn handle_blocks(&self, blks: Vec<Block>, peer: &SocketAddr)
+    {
+        let mut guard = self.progress.lock().unwrap();
+        let progress = guard.deref_mut();
+
+        if progress.phase != Phase::Blocks
+        {
+            debug!("{peer}: ignoring unrequested blocks.");
+            return;
+        }
+
+        for blk in blks
+        {
+            let hash = blk.hash();
+
+            let request = progress
+                .in_flight
+                .iter_mut()
+                .find(|r| &r.peer == peer && r.hashes.contains(&hash));
+
+            if let Some(request) = request
+            {
+                request.hashes.retain(|h| h != &hash);
+                progress.received.insert(hash, (blk, *peer));
+            }
+        }
+        progress.in_flight.retain(|r| !r.hashes.is_empty());
+
+        self.connect_blocks(progress);
+
+        if progress.phase == Phase::Blocks
+        {
+            self.request_blocks(progress);
+        }
+    }
+
+    /// connect downloaded blocks in chain order
+    fn connect_blocks(&self, progress: &mut Progress)
+    {
+        let node = self.node.upgrade().unwrap();
+        let mut lg = node.state.lock().unwrap();
+        let state = lg.deref_mut();
+
+        while let Some((hash, _)) = progress.queue.get(progress.next_connect)
+        {
+            let (blk, sender) = match progress.received.remove(hash)
+            {
+                Some(received) => received,
+                None => break,
+            };
+
+            match node.add_block(state, blk)
+            {
+                Ok(()) | Err(ChainError::KnownBlock) => progress.next_connect += 1,
+                Err(e) =>
+                {
+                    node.reject_block(e, &sender);
+                    progress.retry.push_back(vec![hash.clone()]);
+                    progress.drop_peer(&sender);
+                    break;
+                }
+            }
+        }
+
+        let target = progress.target_height().unwrap_or(0);
+        info!("synchronising: height {}/{target}.", state.chain.len());
+        drop(lg);
+
+        if progress.next_connect == progress.queue.len()
+        {
+            self.finish(progress);
+        }
+    }
+}
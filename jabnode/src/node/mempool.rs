@@ -0,0 +1,686 @@
+use jabcoin::core::{crypto::Sha256Hash, Address, Block, Blockchain, Transaction};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// reasons for refusing a transaction
+#[derive(Debug, PartialEq, Eq)]
+pub enum MempoolError
+{
+    /// the transaction is in the pool already
+    Known,
+
+    /// the transaction isn't valid on top of the best chain
+    Invalid(String),
+
+    /// another transaction of the sender uses the same nonce
+    Conflict
+    {
+        nonce: u64
+    },
+
+    /// the nonce skips nonces neither confirmed nor pending for the sender
+    NonceGap
+    {
+        expected: u64, found: u64
+    },
+
+    /// together with the pending transactions of the sender
+    /// more than the confirmed balance is spent
+    Overspend
+    {
+        balance: u64, spent: u128
+    },
+
+    /// the pool is full of transactions paying at least the same fee per byte
+    FeeTooLow,
+
+    /// the transaction is larger than the whole pool
+    TooLarge(usize),
+}
+
+impl fmt::Display for MempoolError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            MempoolError::Known => write!(f, "already in the pool"),
+            MempoolError::Invalid(e) => write!(f, "{e}"),
+            MempoolError::Conflict { nonce } =>
+            {
+                write!(f, "nonce {nonce} is used by another pending transaction")
+            }
+            MempoolError::NonceGap { expected, found } =>
+            {
+                write!(f, "nonce {found} leaves a gap, expected {expected}")
+            }
+            MempoolError::Overspend { balance, spent } =>
+            {
+                write!(f, "pending transactions spend {spent} of {balance}")
+            }
+            MempoolError::FeeTooLow => write!(f, "fee too low for the full pool"),
+            MempoolError::TooLarge(size) => write!(f, "{size} bytes exceed the pool"),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+struct Entry
+{
+    trx: Transaction,
+    size: usize,
+    added: Instant,
+}
+
+/// the transactions waiting to be included in a block
+pub struct Mempool
+{
+    entries: HashMap<Vec<u8>, Entry>,
+
+    /// hashes of the pending transactions of a sender by their nonce
+    senders: HashMap<Address, BTreeMap<u64, Vec<u8>>>,
+
+    /// bytes occupied by all transactions
+    size: usize,
+
+    max_size: usize,
+
+    /// time after which a transaction which hasn't been confirmed is dropped
+    expiry: Duration,
+}
+
+impl Mempool
+{
+    pub fn new(max_size: usize, expiry: Duration) -> Mempool
+    {
+        Mempool {
+            entries: HashMap::new(),
+            senders: HashMap::new(),
+            size: 0,
+            max_size,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    /// number of bytes of all transactions
+    pub fn size(&self) -> usize
+    {
+        self.size
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&Transaction>
+    {
+        self.entries.get(hash).map(|e| &e.trx)
+    }
+
+    /// pending transactions of `addr` ordered by nonce
+    pub fn by_sender(&self, addr: &Address) -> Vec<&Transaction>
+    {
+        self.senders.get(addr).map_or(vec![], |nonces| {
+            nonces.values().map(|h| &self.entries[h].trx).collect()
+        })
+    }
+
+    /// nonce following the confirmed transactions of `addr` on the best chain
+    /// of `chain` and its pending transactions without a gap
+    pub fn next_nonce(&self, addr: &Address, chain: &Blockchain) -> u64
+    {
+        let mut next = chain.ledger().next_nonce(addr);
+
+        if let Some(nonces) = self.senders.get(addr)
+        {
+            while nonces.contains_key(&next)
+            {
+                next += 1;
+            }
+        }
+        next
+    }
+
+    /// all transactions by descending fee per byte, the older one first on a tie
+    pub fn sorted(&self) -> Vec<&Transaction>
+    {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by(|a, b| by_fee(b, a).then(a.added.cmp(&b.added)));
+
+        entries.into_iter().map(|e| &e.trx).collect()
+    }
+
+    /// add a transaction which is valid on top of the best chain of `chain`,
+    /// transactions paying less per byte are evicted if the pool is full.
+    /// returns the evicted transactions
+    pub fn add(
+        &mut self,
+        trx: Transaction,
+        chain: &Blockchain,
+    ) -> Result<Vec<Transaction>, MempoolError>
+    {
+        let hash = trx.hash();
+
+        if self.contains(&hash)
+        {
+            return Err(MempoolError::Known);
+        }
+
+        chain
+            .verify_transaction(&trx)
+            .map_err(MempoolError::Invalid)?;
+
+        let inp = trx.input();
+        let pending = self.by_sender(inp.get_addr());
+
+        if pending
+            .iter()
+            .any(|t| t.input().get_nonce() == inp.get_nonce())
+        {
+            return Err(MempoolError::Conflict {
+                nonce: inp.get_nonce(),
+            });
+        }
+
+        // a later transaction couldn't be confirmed before the missing ones
+        let expected = self.next_nonce(inp.get_addr(), chain);
+        if inp.get_nonce() > expected
+        {
+            return Err(MempoolError::NonceGap {
+                expected,
+                found: inp.get_nonce(),
+            });
+        }
+
+        // the value received by pending transactions doesn't count until confirmed
+        let balance = chain.ledger().balance(inp.get_addr());
+        let spent = pending
+            .iter()
+            .chain(std::iter::once(&&trx))
+            .map(|t| t.input().get_value() as u128)
+            .sum();
+
+        if spent > balance as u128
+        {
+            return Err(MempoolError::Overspend { balance, spent });
+        }
+
+        let size = trx.size();
+        if size > self.max_size
+        {
+            return Err(MempoolError::TooLarge(size));
+        }
+
+        let evicted = self.make_room(&trx, size)?;
+
+        self.senders
+            .entry(inp.get_addr().clone())
+            .or_default()
+            .insert(inp.get_nonce(), hash.clone());
+
+        self.size += size;
+        self.entries.insert(
+            hash,
+            Entry {
+                trx,
+                size,
+                added: Instant::now(),
+            },
+        );
+
+        Ok(evicted)
+    }
+
+    /// evict the transactions paying the least per byte until `size` more
+    /// bytes fit, fails without evicting anything if `trx` doesn't pay more
+    fn make_room(
+        &mut self,
+        trx: &Transaction,
+        size: usize,
+    ) -> Result<Vec<Transaction>, MempoolError>
+    {
+        if self.size + size <= self.max_size
+        {
+            return Ok(vec![]);
+        }
+
+        let mut entries: Vec<(&Vec<u8>, &Entry)> = self.entries.iter().collect();
+        entries.sort_by(|(_, a), (_, b)| by_fee(a, b).then(b.added.cmp(&a.added)));
+
+        let mut freed = 0;
+        let mut victims = vec![];
+
+        // all transactions leaving the pool, including the successors of victims
+        let mut evicting: HashSet<&Vec<u8>> = HashSet::new();
+
+        for (hash, entry) in entries
+        {
+            if self.size - freed + size <= self.max_size
+            {
+                break;
+            }
+
+            if evicting.contains(hash)
+            {
+                continue;
+            }
+
+            // the transaction would lose its predecessor
+            let predecessor = entry.trx.input().get_addr() == trx.input().get_addr()
+                && entry.trx.input().get_nonce() < trx.input().get_nonce();
+
+            let pays_more = fee_rate(trx.fee(), size, entry.trx.fee(), entry.size);
+            if predecessor || pays_more != Ordering::Greater
+            {
+                return Err(MempoolError::FeeTooLow);
+            }
+
+            let inp = entry.trx.input();
+            for (_, h) in self.senders[inp.get_addr()].range(inp.get_nonce()..)
+            {
+                if evicting.insert(h)
+                {
+                    freed += self.entries[h].size;
+                }
+            }
+            victims.push(hash.clone());
+        }
+
+        let mut evicted = vec![];
+        for hash in victims
+        {
+            evicted.extend(self.remove_with_successors(&hash));
+        }
+        Ok(evicted)
+    }
+
+    /// remove a transaction together with the later ones of its sender,
+    /// which can't be confirmed without it
+    fn remove_with_successors(&mut self, hash: &[u8]) -> Vec<Transaction>
+    {
+        let (addr, nonce) = match self.get(hash)
+        {
+            Some(trx) => (trx.input().get_addr().clone(), trx.input().get_nonce()),
+            None => return vec![],
+        };
+
+        let hashes: Vec<Vec<u8>> = self.senders[&addr]
+            .range(nonce..)
+            .map(|(_, h)| h.clone())
+            .collect();
+
+        hashes.iter().filter_map(|h| self.remove(h)).collect()
+    }
+
+    /// remove a single transaction
+    pub fn remove(&mut self, hash: &[u8]) -> Option<Transaction>
+    {
+        let entry = self.entries.remove(hash)?;
+        let addr = entry.trx.input().get_addr();
+
+        if let Some(nonces) = self.senders.get_mut(addr)
+        {
+            nonces.remove(&entry.trx.input().get_nonce());
+
+            if nonces.is_empty()
+            {
+                self.senders.remove(addr);
+            }
+        }
+
+        self.size -= entry.size;
+        Some(entry.trx)
+    }
+
+    /// remove the transactions of a block added to the best chain and those
+    /// using the same nonces, returns the number of removed transactions
+    pub fn remove_confirmed(&mut self, blk: &Block) -> usize
+    {
+        let mut count = 0;
+
+        for trx in blk.transactions()
+        {
+            let inp = trx.input();
+
+            let hashes: Vec<Vec<u8>> = self.senders.get(inp.get_addr()).map_or(vec![], |nonces| {
+                nonces
+                    .range(..=inp.get_nonce())
+                    .map(|(_, h)| h.clone())
+                    .collect()
+            });
+
+            count += hashes.iter().filter_map(|h| self.remove(h)).count();
+        }
+        count
+    }
+
+    /// return the transactions of blocks removed from the best chain to the
+    /// pool, as far as they are valid on top of the new best chain. the blocks
+    /// are given as in `Reorg::disconnected`, starting at the old head
+    pub fn readd_disconnected(&mut self, disconnected: &[Block], chain: &Blockchain) -> usize
+    {
+        // earlier nonces first, later ones would leave a gap
+        disconnected
+            .iter()
+            .rev()
+            .flat_map(|blk| blk.transactions())
+            .filter(|trx| self.add((*trx).clone(), chain).is_ok())
+            .count()
+    }
+
+    /// drop the transactions which are no longer valid on top of the best
+    /// chain, e.g. after a reorganisation. returns the dropped transactions
+    pub fn revalidate(&mut self, chain: &Blockchain) -> Vec<Transaction>
+    {
+        // signatures have been checked when the transactions were added
+        let mut invalid: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| chain.verify_transaction_state(&e.trx).is_err())
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        // transactions left behind a gap in the nonces of their sender
+        for (addr, nonces) in &self.senders
+        {
+            let mut next = chain.ledger().next_nonce(addr);
+
+            let gap = nonces.range(next..).find(|(nonce, _)| {
+                let skipped = **nonce != next;
+                next += 1;
+                skipped
+            });
+            invalid.extend(gap.map(|(_, h)| h.clone()));
+        }
+
+        invalid
+            .iter()
+            .flat_map(|h| self.remove_with_successors(h))
+            .collect()
+    }
+
+    /// drop the transactions which have been waiting for longer than the
+    /// expiry, returns the number of dropped transactions
+    pub fn expire(&mut self, now: Instant) -> usize
+    {
+        let stale: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| now.saturating_duration_since(e.added) > self.expiry)
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        stale
+            .iter()
+            .flat_map(|h| self.remove_with_successors(h))
+            .count()
+    }
+}
+
+/// order of the fee per byte
+fn by_fee(a: &Entry, b: &Entry) -> Ordering
+{
+    fee_rate(a.trx.fee(), a.size, b.trx.fee(), b.size)
+}
+
+/// order of the fee per byte of two transactions of the given sizes
+fn fee_rate(fee_a: u64, size_a: usize, fee_b: u64, size_b: usize) -> Ordering
+{
+    // compare fee / size without rounding
+    (fee_a as u128 * size_b as u128).cmp(&(fee_b as u128 * size_a as u128))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::wallet::Wallet;
+    use jabcoin::core::{ChainParams, Reorg};
+
+    fn mine(mut blk: Block) -> Block
+    {
+        while !blk.check_pow()
+        {
+            blk.update_nounce();
+        }
+        blk
+    }
+
+    /// a chain whose genesis pays the reward to each of the wallets
+    fn funded_chain(wallets: &[&Wallet]) -> Blockchain
+    {
+        let mut chain = Blockchain::new();
+        let subsidy = ChainParams::default().subsidy(0);
+
+        for wallet in wallets
+        {
+            let mut blk = match chain.head()
+            {
+                Some(head) => Block::with_previous(wallet.address(), &head),
+                None => Block::new(wallet.address()),
+            };
+            blk.set_reward(subsidy);
+            chain.append_block(mine(blk)).unwrap();
+        }
+        chain
+    }
+
+    fn pay(wallet: &Wallet, value: u64, fee: u64, nonce: u64) -> Transaction
+    {
+        wallet
+            .pay(vec![(Address::generate_random(), value)], fee, nonce)
+            .unwrap()
+    }
+
+    #[test]
+    fn conflicts()
+    {
+        let wallet = Wallet::generate_random();
+        let chain = funded_chain(&[&wallet]);
+        let mut pool = Mempool::new(usize::MAX, Duration::from_secs(60));
+
+        let first = pay(&wallet, 2, 1, 0);
+        assert_eq!(pool.add(first.clone(), &chain), Ok(vec![]));
+        assert_eq!(pool.add(first.clone(), &chain), Err(MempoolError::Known));
+
+        // same sender and nonce
+        assert_eq!(
+            pool.add(pay(&wallet, 3, 1, 0), &chain),
+            Err(MempoolError::Conflict { nonce: 0 })
+        );
+
+        // the pending transactions may not spend more than the balance
+        pool.add(pay(&wallet, 4, 1, 1), &chain).unwrap();
+        assert!(matches!(
+            pool.add(pay(&wallet, 2, 1, 2), &chain),
+            Err(MempoolError::Overspend { .. })
+        ));
+
+        // the sender has to be able to pay with its confirmed balance
+        let unfunded = pay(&Wallet::generate_random(), 1, 1, 0);
+        assert!(matches!(
+            pool.add(unfunded, &chain),
+            Err(MempoolError::Invalid(_))
+        ));
+
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.by_sender(&wallet.address())[0], &first);
+    }
+
+    #[test]
+    fn nonce_gaps()
+    {
+        let wallet = Wallet::generate_random();
+        let chain = funded_chain(&[&wallet]);
+        let mut pool = Mempool::new(usize::MAX, Duration::from_secs(60));
+
+        assert_eq!(
+            pool.add(pay(&wallet, 1, 1, 1), &chain),
+            Err(MempoolError::NonceGap {
+                expected: 0,
+                found: 1
+            })
+        );
+
+        pool.add(pay(&wallet, 1, 1, 0), &chain).unwrap();
+        pool.add(pay(&wallet, 1, 1, 1), &chain).unwrap();
+        assert_eq!(pool.next_nonce(&wallet.address(), &chain), 2);
+        assert_eq!(
+            pool.add(pay(&wallet, 1, 1, 3), &chain),
+            Err(MempoolError::NonceGap {
+                expected: 2,
+                found: 3
+            })
+        );
+
+        // removing a transaction leaves its successors behind a gap
+        let first = pool.by_sender(&wallet.address())[0].hash();
+        pool.remove(&first);
+        assert_eq!(pool.revalidate(&chain).len(), 1);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn evict_lowest_fee()
+    {
+        let (a, b) = (Wallet::generate_random(), Wallet::generate_random());
+        let chain = funded_chain(&[&a, &b]);
+
+        let cheap = pay(&a, 1, 0, 0);
+        let successor = pay(&a, 1, 2, 1);
+        let medium = pay(&b, 1, 1, 0);
+        let rich = pay(&b, 1, 5, 1);
+
+        // room for three transactions
+        let mut pool = Mempool::new(cheap.size() * 3 + 16, Duration::from_secs(60));
+        pool.add(cheap.clone(), &chain).unwrap();
+        pool.add(successor.clone(), &chain).unwrap();
+        pool.add(medium.clone(), &chain).unwrap();
+
+        // the successor can't be confirmed without the evicted transaction
+        let evicted = pool.add(rich.clone(), &chain).unwrap();
+        assert_eq!(evicted, vec![cheap.clone(), successor]);
+        assert_eq!(pool.sorted(), vec![&rich, &medium]);
+        assert_eq!(pool.size(), rich.size() + medium.size());
+
+        // a full pool only takes transactions paying more than the cheapest
+        let mut pool = Mempool::new(cheap.size() + 16, Duration::from_secs(60));
+        pool.add(medium.clone(), &chain).unwrap();
+        assert_eq!(
+            pool.add(cheap.clone(), &chain),
+            Err(MempoolError::FeeTooLow)
+        );
+        assert!(matches!(
+            Mempool::new(16, Duration::from_secs(60)).add(cheap, &chain),
+            Err(MempoolError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn evict_counting_successors()
+    {
+        let (a, b, c) = (
+            Wallet::generate_random(),
+            Wallet::generate_random(),
+            Wallet::generate_random(),
+        );
+        let chain = funded_chain(&[&a, &b, &c]);
+
+        let cheap = pay(&a, 1, 0, 0);
+        let successor = pay(&a, 1, 3, 1);
+        let medium = pay(&b, 1, 1, 0);
+
+        // takes the room of more than one transaction
+        let recipients = vec![(Address::generate_random(), 1); 2];
+        let large = c.pay(recipients, 8, 0).unwrap();
+        assert!(large.size() > cheap.size() + 16 && large.size() <= cheap.size() * 2 + 16);
+
+        let mut pool = Mempool::new(cheap.size() * 3 + 16, Duration::from_secs(60));
+        pool.add(cheap.clone(), &chain).unwrap();
+        pool.add(successor.clone(), &chain).unwrap();
+        pool.add(medium.clone(), &chain).unwrap();
+
+        // the successor evicted along with the cheapest one makes enough room
+        let evicted = pool.add(large.clone(), &chain).unwrap();
+        assert_eq!(evicted, vec![cheap, successor]);
+        assert_eq!(pool.sorted(), vec![&large, &medium]);
+    }
+
+    #[test]
+    fn confirm_and_expire()
+    {
+        let wallet = Wallet::generate_random();
+        let mut chain = funded_chain(&[&wallet]);
+        let mut pool = Mempool::new(usize::MAX, Duration::from_secs(60));
+
+        let first = pay(&wallet, 1, 1, 0);
+        let second = pay(&wallet, 1, 1, 1);
+        pool.add(first.clone(), &chain).unwrap();
+        pool.add(second.clone(), &chain).unwrap();
+
+        let mut blk = Block::with_previous(wallet.address(), &chain.head().unwrap());
+        blk.add_transaction(first.clone());
+        blk.set_reward(ChainParams::default().subsidy(1) + 1);
+        let blk = mine(blk);
+        chain.append_block(blk.clone()).unwrap();
+
+        assert_eq!(pool.remove_confirmed(&blk), 1);
+        assert!(!pool.contains(&first.hash()) && pool.contains(&second.hash()));
+        assert!(pool.revalidate(&chain).is_empty());
+
+        // confirmed transactions aren't taken back
+        assert_eq!(pool.readd_disconnected(&[blk], &chain), 0);
+
+        assert_eq!(pool.expire(Instant::now()), 0);
+        assert_eq!(pool.expire(Instant::now() + Duration::from_secs(61)), 1);
+        assert_eq!((pool.len(), pool.size()), (0, 0));
+    }
+
+    #[test]
+    fn readd_after_reorg()
+    {
+        let wallet = Wallet::generate_random();
+        let mut chain = funded_chain(&[&wallet]);
+        let gen = chain.head().unwrap();
+
+        let first = pay(&wallet, 1, 1, 0);
+        let second = pay(&wallet, 1, 1, 1);
+        let mut prev = gen.clone();
+
+        for trx in [&first, &second]
+        {
+            let mut blk = Block::with_previous(wallet.address(), &prev);
+            blk.add_transaction(trx.clone());
+            blk.set_reward(ChainParams::default().subsidy(blk.id()) + 1);
+            prev = mine(blk);
+            chain.append_block(prev.clone()).unwrap();
+        }
+
+        // a longer branch without the payments
+        let mut prev = gen;
+        let mut reorg = Reorg::default();
+
+        for _ in 0..3
+        {
+            let mut blk = Block::with_previous(Address::generate_random(), &prev);
+            blk.set_reward(ChainParams::default().subsidy(blk.id()));
+            prev = mine(blk);
+            reorg = chain.append_block(prev.clone()).unwrap();
+        }
+
+        assert_eq!(reorg.disconnected.len(), 2);
+
+        let mut pool = Mempool::new(usize::MAX, Duration::from_secs(60));
+        assert_eq!(pool.readd_disconnected(&reorg.disconnected, &chain), 2);
+        assert_eq!(pool.by_sender(&wallet.address()), vec![&first, &second]);
+    }
+}
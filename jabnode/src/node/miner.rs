@@ -0,0 +1,213 @@
+use crate::node::Node;
+use jabcoin::core::{crypto::Sha256Hash, Address, Block, Transaction};
+use log::{debug, info, trace};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+    Weak,
+};
+
+pub struct Miner
+{
+    node: Weak<Node>,
+
+    /// bumped whenever the head of the chain changes,
+    /// workers abort as soon as it differs from their candidate
+    generation: AtomicU64,
+    shutdown: AtomicBool,
+}
+
+impl Miner
+{
+    pub fn new(node: Weak<Node>) -> Miner
+    {
+        Miner {
+            node,
+            generation: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    pub fn start(&self, miner: Address, count_workers: usize)
+    {
+        assert!(count_workers > 0);
+        info!("starting miner with {count_workers} worker(s).");
+
+        while !self.shutdown.load(Relaxed)
+        {
+            let (candidate, generation) = match self.assemble_candidate(&miner)
+            {
+                Some(c) => c,
+                None => break,
+            };
+
+            debug!(
+                "mining block at height {} with {} transaction(s).",
+                candidate.id(),
+                candidate.transactions().len()
+            );
+
+            let found = AtomicBool::new(false);
+            let chunk = u64::MAX / count_workers as u64;
+
+            let solved = std::thread::scope(|s| {
+                let handles: Vec<_> = (0..count_workers as u64)
+                    .map(|i| {
+                        let blk = candidate.clone();
+                        let found = &found;
+
+                        // partition the nounce space between the workers
+                        let start = i * chunk;
+                        let end = if i + 1 == count_workers as u64
+                        {
+                            u64::MAX
+                        }
+                        else
+                        {
+                            start + chunk
+                        };
+
+                        s.spawn(move || self.search(blk, start, end, generation, found))
+                    })
+                    .collect();
+
+                handles.into_iter().filter_map(|h| h.join().unwrap()).next()
+            });
+
+            match solved
+            {
+                Some(blk) => self.node.upgrade().unwrap().handle_mined_block(blk),
+                None => debug!("aborted candidate at height {}.", candidate.id()),
+            }
+        }
+
+        info!("miner shutdown.");
+    }
+
+    /// build a block on top of the current head from the mempool, blocks
+    /// without transactions are mined as well to keep the chain advancing
+    fn assemble_candidate(&self, miner: &Address) -> Option<(Block, u64)>
+    {
+        let node = self.node.upgrade().unwrap();
+        let state = node.state.lock().unwrap();
+
+        if self.shutdown.load(Relaxed)
+        {
+            return None;
+        }
+
+        let head = state.chain.head();
+        let mut blk = match &head
+        {
+            Some(head) => Block::with_previous(miner.clone(), head),
+            None => Block::new(miner.clone()),
+        };
+        blk.set_bits(state.chain.next_bits(head.as_ref()));
+
+        if let Some(head) = &head
+        {
+            // the timestamp has to exceed the median of the preceding blocks
+            let median = state.chain.median_time_past(&head.hash());
+            blk.set_timestamp(blk.timestamp().max(median + 1));
+        }
+
+        // reserve room for the largest reward and nounce, the length prefix
+        // of the transaction list is part of the empty block already
+        blk.set_reward(u64::MAX);
+        blk.set_nounce(u64::MAX);
+        let mut size = blk.size();
+        blk.set_nounce(0);
+
+        let limit = state.chain.params().max_block_size;
+
+        // only pick transactions which are valid on top of each other,
+        // the mempool is ordered by fee per byte. transactions skipped because
+        // of a missing predecessor are retried in the next pass
+        let mut ledger = state.chain.ledger().clone();
+        let mut fees: u64 = 0;
+        let mut pending: Vec<&Transaction> = state.mempool.sorted();
+        let mut picked = vec![];
+
+        loop
+        {
+            let count = pending.len();
+
+            pending.retain(|trx| {
+                let trx_size = trx.size();
+
+                if size + trx_size > limit
+                {
+                    return true;
+                }
+
+                // signatures have been checked when entering the mempool
+                match ledger.apply_transaction(trx)
+                {
+                    Ok(fee) =>
+                    {
+                        fees = fees.saturating_add(fee);
+                        size += trx_size;
+                        picked.push((*trx).clone());
+                        false
+                    }
+                    Err(e) =>
+                    {
+                        trace!("skipping transaction {} with error {e}.", trx.hash_str());
+                        true
+                    }
+                }
+            });
+
+            if pending.len() == count
+            {
+                break;
+            }
+        }
+
+        blk.add_transactions(picked);
+
+        let subsidy = state.chain.params().subsidy(blk.id());
+        blk.set_reward(subsidy.saturating_add(fees));
+
+        Some((blk, self.generation.load(Relaxed)))
+    }
+
+    /// search the nounce range [start, end) for a valid proof-of-work
+    fn search(
+        &self,
+        mut blk: Block,
+        start: u64,
+        end: u64,
+        generation: u64,
+        found: &AtomicBool,
+    ) -> Option<Block>
+    {
+        for nounce in start..end
+        {
+            if found.load(Relaxed) || self.generation.load(Relaxed) != generation
+            {
+                return None;
+            }
+
+            blk.set_nounce(nounce);
+
+            if blk.check_pow()
+            {
+                found.store(true, Relaxed);
+                return Some(blk);
+            }
+        }
+        None
+    }
+
+    /// abort the current candidate, e.g. because a new block arrived
+    pub fn notify_new_head(&self)
+    {
+        self.generation.fetch_add(1, Relaxed);
+    }
+
+    pub fn request_stop(&self)
+    {
+        self.shutdown.store(true, Relaxed);
+        self.notify_new_head();
+    }
+}
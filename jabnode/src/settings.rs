@@ -0,0 +1,492 @@
+use crate::network::connection::MAX_PAYLOAD;
+use crate::node::Config;
+use clap::Args;
+use jabcoin::core::{difficulty::target_from_bits, Address};
+use jabcoin::network::MAX_BLOCKS;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// reasons for refusing to start with the given settings
+#[derive(Debug)]
+pub enum SettingsError
+{
+    Io(PathBuf, io::Error),
+
+    /// the config file isn't valid toml or has unknown keys
+    Toml(PathBuf, toml::de::Error),
+
+    /// a setting has a value the node can't work with
+    Invalid
+    {
+        key: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for SettingsError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            SettingsError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            SettingsError::Toml(path, e) => write!(f, "invalid config {}: {e}", path.display()),
+            SettingsError::Invalid { key, reason } => write!(f, "invalid {key}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+fn invalid(key: &'static str, reason: impl fmt::Display) -> SettingsError
+{
+    SettingsError::Invalid {
+        key,
+        reason: reason.to_string(),
+    }
+}
+
+/// settings of a node read from a config file, the environment or the command
+/// line. unset values are taken from the next source, then from the `Config`
+#[derive(Args, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings
+{
+    /// directory of the block store
+    #[arg(long, env = "JABNODE_DATA_DIR", value_name = "DIR")]
+    pub data_dir: Option<String>,
+
+    /// when blocks are flushed to the disk: always, never or every n blocks
+    #[arg(long, env = "JABNODE_FSYNC", value_name = "POLICY")]
+    pub fsync: Option<String>,
+
+    /// value created by the coinbase of the first blocks
+    #[arg(long, env = "JABNODE_INITIAL_SUBSIDY", value_name = "N")]
+    pub initial_subsidy: Option<u64>,
+
+    /// number of blocks after which the subsidy is halved
+    #[arg(long, env = "JABNODE_HALVING_INTERVAL", value_name = "N")]
+    pub halving_interval: Option<u64>,
+
+    /// most bytes of a serialized block
+    #[arg(long, env = "JABNODE_MAX_BLOCK_SIZE", value_name = "BYTES")]
+    pub max_block_size: Option<usize>,
+
+    /// compact target of the genesis block, also the easiest target allowed
+    #[arg(long, env = "JABNODE_INITIAL_BITS", value_name = "BITS")]
+    pub initial_bits: Option<u32>,
+
+    /// number of blocks after which the target is adjusted
+    #[arg(long, env = "JABNODE_RETARGET_INTERVAL", value_name = "N")]
+    pub retarget_interval: Option<u64>,
+
+    /// desired number of seconds between two blocks
+    #[arg(long, env = "JABNODE_TARGET_BLOCK_TIME", value_name = "SECS")]
+    pub target_block_time: Option<u64>,
+
+    /// seconds the timestamp of a block may be ahead of the local clock
+    #[arg(long, env = "JABNODE_MAX_FUTURE_DRIFT", value_name = "SECS")]
+    pub max_future_drift: Option<u64>,
+
+    /// cache the ledger of the blockchain on start
+    #[arg(long, env = "JABNODE_BUILD_CACHE", value_name = "BOOL")]
+    pub build_cache: Option<bool>,
+
+    /// mine new blocks
+    #[arg(long, env = "JABNODE_MINE", value_name = "BOOL")]
+    pub mine: Option<bool>,
+
+    /// number of mining threads
+    #[arg(long, env = "JABNODE_CHAIN_WORKERS", value_name = "N")]
+    pub chain_workers: Option<usize>,
+
+    /// json file with the address receiving the mining rewards
+    #[arg(long, env = "JABNODE_MINER", value_name = "FILE")]
+    pub miner: Option<PathBuf>,
+
+    /// minimum fee per 1000 bytes for relaying a transaction
+    #[arg(long, env = "JABNODE_MIN_RELAY_FEE", value_name = "FEE")]
+    pub min_relay_fee: Option<u64>,
+
+    /// most bytes of unconfirmed transactions kept
+    #[arg(long, env = "JABNODE_MEMPOOL_SIZE", value_name = "BYTES")]
+    pub mempool_size: Option<usize>,
+
+    /// seconds after which unconfirmed transactions are dropped
+    #[arg(long, env = "JABNODE_MEMPOOL_EXPIRY", value_name = "SECS")]
+    pub mempool_expiry: Option<u64>,
+
+    /// accept connections from other nodes
+    #[arg(long, env = "JABNODE_LISTEN", value_name = "BOOL")]
+    pub listen: Option<bool>,
+
+    /// address to accept connections on, `[::]:port` covers ipv4 as well
+    #[arg(long, env = "JABNODE_LISTEN_ADDR", value_name = "ADDR")]
+    pub listen_addr: Option<String>,
+
+    /// number of connections handshaking at the same time
+    #[arg(long, env = "JABNODE_COMM_WORKERS", value_name = "N")]
+    pub comm_workers: Option<usize>,
+
+    /// number of outbound connections to keep
+    #[arg(long, env = "JABNODE_TARGET_OUTBOUND", value_name = "N")]
+    pub target_outbound: Option<usize>,
+
+    /// most connections accepted from other nodes
+    #[arg(long, env = "JABNODE_MAX_INBOUND", value_name = "N")]
+    pub max_inbound: Option<usize>,
+
+    /// seed peer as ip address or host name with an optional port, repeatable
+    #[arg(
+        long = "seed",
+        env = "JABNODE_SEEDS",
+        value_name = "HOST",
+        value_delimiter = ','
+    )]
+    pub seeds: Option<Vec<String>>,
+
+    /// off, error, warn, info, debug or trace
+    #[arg(long, env = "JABNODE_LOG_LEVEL", value_name = "LEVEL")]
+    pub log_level: Option<String>,
+}
+
+impl Settings
+{
+    /// read the settings of a toml file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Settings, SettingsError>
+    {
+        let path = path.as_ref();
+        let s =
+            std::fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_path_buf(), e))?;
+
+        Settings::from_toml(&s).map_err(|e| SettingsError::Toml(path.to_path_buf(), e))
+    }
+
+    pub fn from_toml(s: &str) -> Result<Settings, toml::de::Error>
+    {
+        toml::from_str(s)
+    }
+
+    /// fill the values missing here with those of `other`
+    pub fn or(self, other: Settings) -> Settings
+    {
+        Settings {
+            data_dir: self.data_dir.or(other.data_dir),
+            fsync: self.fsync.or(other.fsync),
+            initial_subsidy: self.initial_subsidy.or(other.initial_subsidy),
+            halving_interval: self.halving_interval.or(other.halving_interval),
+            max_block_size: self.max_block_size.or(other.max_block_size),
+            initial_bits: self.initial_bits.or(other.initial_bits),
+            retarget_interval: self.retarget_interval.or(other.retarget_interval),
+            target_block_time: self.target_block_time.or(other.target_block_time),
+            max_future_drift: self.max_future_drift.or(other.max_future_drift),
+            build_cache: self.build_cache.or(other.build_cache),
+            mine: self.mine.or(other.mine),
+            chain_workers: self.chain_workers.or(other.chain_workers),
+            miner: self.miner.or(other.miner),
+            min_relay_fee: self.min_relay_fee.or(other.min_relay_fee),
+            mempool_size: self.mempool_size.or(other.mempool_size),
+            mempool_expiry: self.mempool_expiry.or(other.mempool_expiry),
+            listen: self.listen.or(other.listen),
+            listen_addr: self.listen_addr.or(other.listen_addr),
+            comm_workers: self.comm_workers.or(other.comm_workers),
+            target_outbound: self.target_outbound.or(other.target_outbound),
+            max_inbound: self.max_inbound.or(other.max_inbound),
+            seeds: self.seeds.or(other.seeds),
+            log_level: self.log_level.or(other.log_level),
+        }
+    }
+
+    /// the log level, if set
+    pub fn log_level(&self) -> Result<Option<LevelFilter>, SettingsError>
+    {
+        self.log_level
+            .as_deref()
+            .map(|s| {
+                s.parse()
+                    .map_err(|_| invalid("log-level", format!("{s:?}")))
+            })
+            .transpose()
+    }
+
+    /// override the values of `cfg` with the ones set here and check the result
+    pub fn apply(self, cfg: &mut Config) -> Result<(), SettingsError>
+    {
+        if let Some(dir) = self.data_dir
+        {
+            cfg.blkpath = dir;
+        }
+
+        if let Some(fsync) = self.fsync
+        {
+            cfg.fsync = fsync.parse().map_err(|e| invalid("fsync", e))?;
+        }
+
+        if let Some(path) = self.miner
+        {
+            let s =
+                std::fs::read_to_string(&path).map_err(|e| SettingsError::Io(path.clone(), e))?;
+            let miner = serde_json::from_str::<Address>(&s)
+                .map_err(|e| invalid("miner", format!("{}: {e}", path.display())))?;
+            cfg.miner = Some(miner);
+        }
+
+        if let Some(addr) = self.listen_addr
+        {
+            cfg.listen = addr
+                .parse::<SocketAddr>()
+                .map_err(|e| invalid("listen-addr", format!("{addr:?}: {e}")))?;
+        }
+
+        let params = &mut cfg.params;
+        params.initial_subsidy = self.initial_subsidy.unwrap_or(params.initial_subsidy);
+        params.halving_interval = self.halving_interval.unwrap_or(params.halving_interval);
+        params.max_block_size = self.max_block_size.unwrap_or(params.max_block_size);
+        params.initial_bits = self.initial_bits.unwrap_or(params.initial_bits);
+        params.retarget_interval = self.retarget_interval.unwrap_or(params.retarget_interval);
+        params.target_block_time = self.target_block_time.unwrap_or(params.target_block_time);
+        params.max_future_drift = self.max_future_drift.unwrap_or(params.max_future_drift);
+
+        cfg.build_cache = self.build_cache.unwrap_or(cfg.build_cache);
+        cfg.mine = self.mine.unwrap_or(cfg.mine);
+        cfg.count_chain_workers = self.chain_workers.unwrap_or(cfg.count_chain_workers);
+        cfg.min_relay_fee = self.min_relay_fee.unwrap_or(cfg.min_relay_fee);
+        cfg.mempool_size = self.mempool_size.unwrap_or(cfg.mempool_size);
+        cfg.mempool_expiry = self
+            .mempool_expiry
+            .map_or(cfg.mempool_expiry, Duration::from_secs);
+        cfg.listen_communication = self.listen.unwrap_or(cfg.listen_communication);
+        cfg.count_comm_workers = self.comm_workers.unwrap_or(cfg.count_comm_workers);
+        cfg.target_outbound = self.target_outbound.unwrap_or(cfg.target_outbound);
+        cfg.max_inbound = self.max_inbound.unwrap_or(cfg.max_inbound);
+        cfg.seeds = self.seeds.unwrap_or(std::mem::take(&mut cfg.seeds));
+
+        validate(cfg)
+    }
+}
+
+/// check the values the node can't start with
+pub fn validate(cfg: &Config) -> Result<(), SettingsError>
+{
+    if cfg.blkpath.is_empty()
+    {
+        return Err(invalid("data-dir", "empty path"));
+    }
+
+    if Path::new(&cfg.blkpath).is_file()
+    {
+        return Err(invalid("data-dir", format!("{} is a file", cfg.blkpath)));
+    }
+
+    if cfg.params.halving_interval == 0
+    {
+        return Err(invalid("halving-interval", "must be at least 1"));
+    }
+
+    if cfg.params.max_block_size == 0
+    {
+        return Err(invalid("max-block-size", "no block fits"));
+    }
+
+    // blocks are relayed one by one and requested in batches,
+    // a batch and its length prefix must fit into one frame
+    let batch = cfg.params.max_block_size.saturating_mul(MAX_BLOCKS);
+    if cfg.params.max_block_size > MAX_PAYLOAD || batch.saturating_add(4) > MAX_PAYLOAD
+    {
+        return Err(invalid(
+            "max-block-size",
+            format!("{MAX_BLOCKS} blocks exceed the frame limit of {MAX_PAYLOAD} bytes"),
+        ));
+    }
+
+    if target_from_bits(cfg.params.initial_bits).bits() == 0
+    {
+        return Err(invalid(
+            "initial-bits",
+            format!("{:#010x} is no target", cfg.params.initial_bits),
+        ));
+    }
+
+    if cfg.params.retarget_interval < 2
+    {
+        return Err(invalid("retarget-interval", "must be at least 2"));
+    }
+
+    if cfg.params.target_block_time == 0
+    {
+        return Err(invalid("target-block-time", "must be at least 1"));
+    }
+
+    // the rewards of a random address would be lost
+    if cfg.mine && cfg.miner.is_none()
+    {
+        return Err(invalid(
+            "miner",
+            "mining needs an address receiving the rewards",
+        ));
+    }
+
+    if cfg.mine && cfg.count_chain_workers == 0
+    {
+        return Err(invalid("chain-workers", "mining needs at least one worker"));
+    }
+
+    if cfg.mempool_size == 0
+    {
+        return Err(invalid("mempool-size", "no transaction fits"));
+    }
+
+    if cfg.mempool_expiry.is_zero()
+    {
+        return Err(invalid(
+            "mempool-expiry",
+            "transactions would expire right away",
+        ));
+    }
+
+    if cfg.listen_communication && cfg.listen.port() == 0
+    {
+        return Err(invalid(
+            "listen-addr",
+            "port 0 can't be advertised to peers",
+        ));
+    }
+
+    if cfg.count_comm_workers == 0
+    {
+        return Err(invalid("comm-workers", "at least one worker is needed"));
+    }
+
+    if let Some(seed) = cfg.seeds.iter().find(|s| s.trim().is_empty())
+    {
+        return Err(invalid("seed", format!("{seed:?}")));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::store::FsyncPolicy;
+    use jabcoin::core::ChainParams;
+
+    fn config() -> Config
+    {
+        let mut cfg = Config::with_default();
+        cfg.miner = Some(Address::generate_random());
+        cfg.blkpath = std::env::temp_dir()
+            .join(format!("jabnode-settings-{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .into();
+        cfg
+    }
+
+    #[test]
+    fn precedence()
+    {
+        let file = Settings::from_toml(
+            r#"
+            data-dir = "/var/lib/jabnode"
+            fsync = "10"
+            mine = false
+            listen-addr = "127.0.0.1:27183"
+            seeds = ["seed.example.org", "10.0.0.1:27182"]
+            target-outbound = 4
+            mempool-expiry = 600
+            retarget-interval = 50
+            "#,
+        )
+        .unwrap();
+
+        let cli = Settings {
+            mine: Some(true),
+            seeds: Some(vec![]),
+            ..Settings::default()
+        };
+
+        let mut cfg = config();
+        cli.or(file).apply(&mut cfg).unwrap();
+
+        assert_eq!(cfg.blkpath, "/var/lib/jabnode");
+        assert_eq!(cfg.fsync, FsyncPolicy::Every(10));
+        assert_eq!(cfg.listen, "127.0.0.1:27183".parse().unwrap());
+        assert_eq!(cfg.target_outbound, 4);
+        assert_eq!(cfg.mempool_expiry, Duration::from_secs(600));
+        assert_eq!(cfg.params.retarget_interval, 50);
+        assert!(cfg.mine);
+        assert!(cfg.seeds.is_empty());
+
+        // unset values keep the defaults
+        assert_eq!(cfg.max_inbound, Config::with_default().max_inbound);
+        assert_eq!(
+            cfg.params.target_block_time,
+            ChainParams::default().target_block_time
+        );
+    }
+
+    #[test]
+    fn reject_invalid()
+    {
+        assert!(Settings::from_toml("unknown = 1").is_err());
+        assert!(Settings::from_toml("mine = \"yes\"").is_err());
+
+        let cases = [
+            ("fsync", "fsync = \"sometimes\""),
+            ("fsync", "fsync = \"0\""),
+            ("listen-addr", "listen-addr = \"localhost\""),
+            ("listen-addr", "listen-addr = \"[::]:0\""),
+            ("halving-interval", "halving-interval = 0"),
+            ("max-block-size", "max-block-size = 0"),
+            ("max-block-size", "max-block-size = 2097152"),
+            ("max-block-size", "max-block-size = 9223372036854775807"),
+            ("initial-bits", "initial-bits = 0"),
+            ("retarget-interval", "retarget-interval = 1"),
+            ("target-block-time", "target-block-time = 0"),
+            ("chain-workers", "chain-workers = 0"),
+            ("comm-workers", "comm-workers = 0"),
+            ("mempool-size", "mempool-size = 0"),
+            ("mempool-expiry", "mempool-expiry = 0"),
+            ("seed", "seeds = [\" \"]"),
+        ];
+
+        for (key, toml) in cases
+        {
+            let res = Settings::from_toml(toml).unwrap().apply(&mut config());
+            assert!(
+                matches!(res, Err(SettingsError::Invalid { key: k, .. }) if k == key),
+                "{toml}"
+            );
+        }
+
+        // mining without a beneficiary
+        let mut cfg = config();
+        cfg.miner = None;
+        assert!(matches!(
+            Settings::default().apply(&mut cfg),
+            Err(SettingsError::Invalid { key: "miner", .. })
+        ));
+
+        let level = Settings {
+            log_level: Some("loud".into()),
+            ..Settings::default()
+        };
+        assert!(level.log_level().is_err());
+
+        let missing = Settings {
+            miner: Some("/nonexistent/address.json".into()),
+            ..Settings::default()
+        };
+        assert!(matches!(
+            missing.apply(&mut config()),
+            Err(SettingsError::Io(..))
+        ));
+    }
+}
@@ -0,0 +1,555 @@
+use crate::network::{Connection, HandshakeError, PeerType, Session, Version};
+use crate::wallet::Wallet;
+use jabcoin::core::{
+    crypto::Sha256Hash, Address, BlockHeader, ChainError, Decode, DecodeError, HeaderChain,
+    Transaction, TransactionProof,
+};
+use jabcoin::network::{Header, Message, ProofRequest, MAX_PROOFS};
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// time a full node has to answer a request
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// reasons for a light client to give up on its full node
+#[derive(Debug)]
+pub enum SpvError
+{
+    Io(io::Error),
+    Handshake(HandshakeError),
+
+    /// the peer doesn't keep the blocks light clients depend on
+    NotFullNode,
+
+    /// the session has been closed
+    Disconnected,
+
+    /// the peer didn't answer within `RESPONSE_TIMEOUT`
+    Timeout(Header),
+
+    /// the response to a request couldn't be decoded
+    Malformed(Header, DecodeError),
+
+    /// the peer sent headers which don't form a valid chain
+    InvalidHeader(ChainError),
+
+    /// a transaction isn't part of the block it has been proven for
+    InvalidProof(String),
+
+    /// a transaction couldn't be created
+    Payment(&'static str),
+}
+
+impl fmt::Display for SpvError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            SpvError::Io(e) => write!(f, "{e}"),
+            SpvError::Handshake(e) => write!(f, "handshake failed: {e}"),
+            SpvError::NotFullNode => write!(f, "peer isn't a full node"),
+            SpvError::Disconnected => write!(f, "disconnected"),
+            SpvError::Timeout(h) => write!(f, "no answer to {h:?}"),
+            SpvError::Malformed(h, e) => write!(f, "malformed {h:?}: {e}"),
+            SpvError::InvalidHeader(e) => write!(f, "invalid header: {e}"),
+            SpvError::InvalidProof(trx) => write!(f, "invalid proof of transaction {trx}"),
+            SpvError::Payment(e) => write!(f, "invalid payment: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SpvError {}
+
+impl From<io::Error> for SpvError
+{
+    fn from(e: io::Error) -> Self
+    {
+        SpvError::Io(e)
+    }
+}
+
+impl From<HandshakeError> for SpvError
+{
+    fn from(e: HandshakeError) -> Self
+    {
+        SpvError::Handshake(e)
+    }
+}
+
+/// a transaction touching the wallet
+struct Tracked
+{
+    trx: Transaction,
+
+    /// block the transaction has been proven to be part of
+    block: Option<Vec<u8>>,
+}
+
+/// a client keeping only the headers of the chain. it relies on a full node
+/// for proofs of the transactions touching its wallet and checks them
+/// against the headers with the most work
+pub struct LightClient
+{
+    wallet: Wallet,
+    address: Address,
+
+    session: Arc<Session>,
+    inbox: mpsc::Receiver<Message>,
+
+    /// messages received while waiting for a response
+    pending: VecDeque<Message>,
+
+    headers: HeaderChain,
+    history: HashMap<Vec<u8>, Tracked>,
+
+    /// height of the first block which hasn't been searched for proofs yet
+    scanned: u64,
+
+    /// nonce of the next payment, following the payments proven
+    /// to be confirmed and those submitted but still unconfirmed
+    next_nonce: u64,
+}
+
+impl LightClient
+{
+    /// connect to the first reachable full node of `peers`,
+    /// announcing ourselves as a light node
+    pub fn connect(peers: &[SocketAddr], wallet: Wallet) -> Result<LightClient, SpvError>
+    {
+        let mut last = SpvError::Disconnected;
+
+        for peer in peers
+        {
+            match LightClient::open_session(*peer)
+            {
+                Ok((session, inbox)) =>
+                {
+                    let address = wallet.address();
+                    return Ok(LightClient {
+                        wallet,
+                        address,
+                        session,
+                        inbox,
+                        pending: VecDeque::new(),
+                        headers: HeaderChain::new(),
+                        history: HashMap::new(),
+                        scanned: 0,
+                        next_nonce: 0,
+                    });
+                }
+                Err(e) =>
+                {
+                    warn!("{peer}: {:<30} {e}.", "failed to connect with error");
+                    last = e;
+                }
+            }
+        }
+        Err(last)
+    }
+
+    fn open_session(peer: SocketAddr) -> Result<(Arc<Session>, mpsc::Receiver<Message>), SpvError>
+    {
+        let mut conn = Connection::new_try_peer_addr(peer)?;
+
+        let version = Version::new(vec![], 0, PeerType::LightNode, 0, rand::random());
+        let remote = conn.handshake(&version)?;
+
+        if remote.ptype() != PeerType::FullNode
+        {
+            return Err(SpvError::NotFullNode);
+        }
+
+        info!(
+            "{peer}: {:<30} {}.",
+            "connected to full node at height",
+            remote.height()
+        );
+
+        let (tx, inbox) = mpsc::channel();
+        let session = Session::start(conn, peer, remote, false, move |msg| {
+            let _ = tx.send(msg);
+        });
+        Ok((session, inbox))
+    }
+
+    pub fn address(&self) -> &Address
+    {
+        &self.address
+    }
+
+    pub fn headers(&self) -> &HeaderChain
+    {
+        &self.headers
+    }
+
+    /// number of blocks of the best chain including the transaction,
+    /// 0 for unconfirmed or unknown transactions
+    pub fn confirmations(&self, hash: &[u8]) -> u64
+    {
+        self.history
+            .get(hash)
+            .and_then(|t| t.block.as_ref())
+            .map_or(0, |blk| self.headers.confirmations(blk))
+    }
+
+    /// transactions touching the wallet with their confirmations
+    pub fn history(&self) -> Vec<(&Transaction, u64)>
+    {
+        let mut history: Vec<_> = self
+            .history
+            .iter()
+            .map(|(hash, t)| (&t.trx, self.confirmations(hash)))
+            .collect();
+
+        // unconfirmed and recent transactions first
+        history.sort_by_key(|(_, confs)| *confs);
+        history
+    }
+
+    /// download the headers following ours, then the proofs of
+    /// the transactions touching the wallet in the new blocks
+    pub fn sync(&mut self) -> Result<(), SpvError>
+    {
+        // new blocks are announced with headers as well, so any headers
+        // message may answer the request. an empty one tells the full
+        // node has nothing left to send, one without new headers that
+        // it follows a branch we don't consider best
+        loop
+        {
+            let msg = Message::with_payload(Header::RequestHeaders, &self.headers.locator());
+            self.request(msg, Header::Headers)?;
+
+            let headers: Vec<BlockHeader> = self.wait_for(Header::Headers)?;
+            if headers.is_empty()
+            {
+                break;
+            }
+
+            // a gap before announced headers needs no handling here,
+            // the next locator asks for the headers it is missing
+            let (added, gap) = self.add_headers(headers)?;
+            if added == 0 && !gap
+            {
+                break;
+            }
+        }
+
+        info!(
+            "{:<30} {}.",
+            "synchronised headers at height",
+            self.headers.len()
+        );
+
+        self.fetch_proofs()
+    }
+
+    /// handle the messages the full node sends on its own for at most
+    /// `timeout`, returns true if the best chain changed
+    pub fn poll(&mut self, timeout: Duration) -> Result<bool, SpvError>
+    {
+        let deadline = Instant::now() + timeout;
+        let tip = self.headers.tip().map(|h| h.hash());
+
+        while let Some(msg) = self.next_msg(deadline)?
+        {
+            if msg.header != Header::Headers
+            {
+                continue;
+            }
+
+            let headers = msg
+                .payload::<Vec<BlockHeader>>()
+                .map_err(|e| SpvError::Malformed(Header::Headers, e))?;
+
+            // an announced block may follow some we haven't seen
+            if self.add_headers(headers)?.1
+            {
+                self.sync()?;
+            }
+            else
+            {
+                self.fetch_proofs()?;
+            }
+        }
+
+        Ok(self.headers.tip().map(|h| h.hash()) != tip)
+    }
+
+    /// sign and send a payment to the full node, returns the transaction
+    pub fn submit(&mut self, to: Vec<(Address, u64)>, fee: u64) -> Result<Transaction, SpvError>
+    {
+        let trx = self
+            .wallet
+            .pay(to, fee, self.next_nonce)
+            .map_err(SpvError::Payment)?;
+
+        info!("{:<30} {}.", "submitting transaction", trx.hash_str());
+
+        let msg = Message::with_payload(Header::BroadcastTransaction, &trx);
+        if !self.session.send(msg)
+        {
+            return Err(SpvError::Disconnected);
+        }
+
+        self.next_nonce += 1;
+        self.history.insert(
+            trx.hash(),
+            Tracked {
+                trx: trx.clone(),
+                block: None,
+            },
+        );
+        Ok(trx)
+    }
+
+    pub fn close(self)
+    {
+        self.session.close();
+        self.session.join();
+    }
+
+    /// add the headers in order, returns the number of new headers and
+    /// true if one of them doesn't extend a known header and the chain
+    /// has to be synchronised
+    fn add_headers(&mut self, headers: Vec<BlockHeader>) -> Result<(usize, bool), SpvError>
+    {
+        let tip = self.headers.tip().map(|h| h.hash());
+        let mut added = 0;
+
+        for header in headers
+        {
+            match self.headers.add(header)
+            {
+                Ok(()) => added += 1,
+                Err(ChainError::KnownBlock) => (),
+                Err(ChainError::UnknownPredecessor) => return Ok((added, true)),
+                Err(e) => return Err(SpvError::InvalidHeader(e)),
+            }
+        }
+
+        // blocks of an abandoned branch have to be searched again
+        if let Some(fork) = tip.and_then(|t| self.headers.fork_point(&t))
+        {
+            if fork + 1 < self.scanned
+            {
+                warn!("reorganisation: rescanning from height {}.", fork + 1);
+                self.scanned = fork + 1;
+            }
+        }
+        Ok((added, false))
+    }
+
+    /// ask for the proofs of the transactions touching the wallet in
+    /// the blocks we haven't searched yet and check them against the headers
+    fn fetch_proofs(&mut self) -> Result<(), SpvError>
+    {
+        while self.scanned < self.headers.len()
+        {
+            let req = ProofRequest {
+                addresses: vec![self.address.clone()],
+                from: self.scanned,
+            };
+
+            let msg = Message::with_payload(Header::RequestProofs, &req);
+            self.request(msg, Header::Proofs)?;
+
+            let proofs: Vec<TransactionProof> = self.wait_for(Header::Proofs)?;
+            let count = proofs.len();
+            let mut last = None;
+
+            for proof in proofs
+            {
+                // blocks found after our last sync are searched again later
+                let height = match self.headers.get(&proof.block)
+                {
+                    Some(header) if self.headers.is_best(&proof.block) => header.height(),
+                    _ => continue,
+                };
+
+                let header = self.headers.get(&proof.block).unwrap();
+                if height < req.from
+                    || !proof.transaction.involves(&self.address)
+                    || !proof.verify(header)
+                {
+                    return Err(SpvError::InvalidProof(proof.transaction.hash_str()));
+                }
+
+                debug!(
+                    "{:<30} {} at height {height}.",
+                    "proven transaction",
+                    proof.transaction.hash_str()
+                );
+
+                let inp = proof.transaction.input();
+                if inp.get_addr() == &self.address
+                {
+                    self.next_nonce = self.next_nonce.max(inp.get_nonce() + 1);
+                }
+
+                last = last.max(Some(height));
+                self.history.insert(
+                    proof.transaction.hash(),
+                    Tracked {
+                        trx: proof.transaction,
+                        block: Some(proof.block),
+                    },
+                );
+            }
+
+            // the response ends with a complete block once it reaches the limit
+            self.scanned = match (count >= MAX_PROOFS, last)
+            {
+                (true, Some(last)) => last + 1,
+                _ => self.headers.len(),
+            };
+        }
+        Ok(())
+    }
+
+    fn request(&self, msg: Message, expected: Header) -> Result<(), SpvError>
+    {
+        match self.session.send(msg)
+        {
+            true => Ok(()),
+            false =>
+            {
+                warn!("{:<30} {expected:?}.", "disconnected while waiting for");
+                Err(SpvError::Disconnected)
+            }
+        }
+    }
+
+    /// wait for the response to a request, setting other messages aside
+    fn wait_for<T: Decode>(&mut self, expected: Header) -> Result<T, SpvError>
+    {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut other = vec![];
+
+        let res = loop
+        {
+            let msg = match self
+                .inbox
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(msg) => msg,
+                Err(mpsc::RecvTimeoutError::Timeout) => break Err(SpvError::Timeout(expected)),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break Err(SpvError::Disconnected),
+            };
+
+            if msg.header != expected
+            {
+                other.push(msg);
+                continue;
+            }
+
+            break msg
+                .payload::<T>()
+                .map_err(|e| SpvError::Malformed(expected, e));
+        };
+
+        self.pending.extend(other);
+        res
+    }
+
+    /// the next message set aside or received before `deadline`
+    fn next_msg(&mut self, deadline: Instant) -> Result<Option<Message>, SpvError>
+    {
+        if let Some(msg) = self.pending.pop_front()
+        {
+            return Ok(Some(msg));
+        }
+
+        match self
+            .inbox
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            Ok(msg) => Ok(Some(msg)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(SpvError::Disconnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use jabcoin::core::Block;
+    use std::net::TcpListener;
+
+    fn mine(mut blk: Block) -> Block
+    {
+        while !blk.check_pow()
+        {
+            blk.update_nounce();
+        }
+        blk
+    }
+
+    /// the full node answers with the headers of an equally long
+    /// branch, which the client has seen after its own
+    #[test]
+    fn sync_equal_work_branch()
+    {
+        let miner = Address::generate_random();
+        let gen = mine(Block::new(miner.clone()));
+
+        let mut a = Block::with_previous(miner.clone(), &gen);
+        a.set_timestamp(gen.timestamp() + 1);
+        let a = mine(a);
+
+        let mut b = Block::with_previous(Address::generate_random(), &gen);
+        b.set_timestamp(gen.timestamp() + 2);
+        let b = mine(b);
+
+        let tip = a.hash();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let full = std::thread::spawn(move || {
+            let mut conn = Connection::new(listener.accept().unwrap().0);
+            let version = Version::new(vec![], 2, PeerType::FullNode, 0, 1);
+            conn.handshake(&version).unwrap();
+
+            let mut first = true;
+            while let Ok(msg) = conn.read_msg()
+            {
+                let res = match msg.header
+                {
+                    Header::RequestHeaders if first =>
+                    {
+                        first = false;
+                        vec![gen.header().clone(), a.header().clone()]
+                    }
+                    Header::RequestHeaders => vec![b.header().clone()],
+                    Header::RequestProofs =>
+                    {
+                        let proofs: Vec<TransactionProof> = vec![];
+                        conn.write_msg(&Message::with_payload(Header::Proofs, &proofs))
+                            .unwrap();
+                        continue;
+                    }
+                    _ => continue,
+                };
+                conn.write_msg(&Message::with_payload(Header::Headers, &res))
+                    .unwrap();
+            }
+        });
+
+        let mut client = LightClient::connect(&[addr], Wallet::generate_random()).unwrap();
+        client.sync().unwrap();
+
+        // ties keep the branch seen first
+        assert_eq!(client.headers().len(), 2);
+        assert_eq!(client.headers().tip().map(|h| h.hash()), Some(tip));
+
+        client.close();
+        full.join().unwrap();
+    }
+}
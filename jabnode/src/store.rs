@@ -0,0 +1,760 @@
+use jabcoin::core::{
+    crypto::{Digest, Sha256, Sha256Hash},
+    Block, ChainStore, Decode, Encode, Ledger, StateStore,
+};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// marks the start of every record
+const MAGIC: [u8; 4] = *b"JABC";
+
+/// magic, payload length, block hash and payload checksum
+const RECORD_HEADER_LEN: u64 = 4 + 4 + 32 + 4;
+
+/// size after which a new segment file is started
+const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// commits between two snapshots of the ledger, the blocks after
+/// a stale snapshot are replayed on the next start
+const SNAPSHOT_INTERVAL: u64 = 64;
+
+/// when appended blocks are flushed to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy
+{
+    /// after every block, survives power loss
+    Always,
+
+    /// after the given number of blocks
+    Every(u64),
+
+    /// leave it to the operating system, survives crashes of the node only
+    Never,
+}
+
+impl FromStr for FsyncPolicy
+{
+    type Err = String;
+
+    /// `always`, `never` or the number of blocks between flushes
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            n => match n.parse()
+            {
+                Ok(0) | Err(_) => Err(format!(
+                    "expected always, never or a number of blocks, got {s:?}"
+                )),
+                Ok(n) => Ok(FsyncPolicy::Every(n)),
+            },
+        }
+    }
+}
+
+/// position of a block inside the segment files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location
+{
+    segment: u32,
+
+    // offset of the payload
+    offset: u64,
+    len: u32,
+}
+
+/// append-only block storage, blocks are written to numbered segment
+/// files as records of a header followed by the binary encoded block
+pub struct BlockStore
+{
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    segment_size: u64,
+
+    index: HashMap<Vec<u8>, Location>,
+
+    // hashes in the order the blocks were appended
+    order: Vec<Vec<u8>>,
+
+    // segment blocks are appended to
+    current: File,
+    current_id: u32,
+    current_len: u64,
+
+    // blocks appended since the last sync
+    unsynced: u64,
+}
+
+fn segment_path(dir: &Path, id: u32) -> PathBuf
+{
+    dir.join(format!("blk{id:05}.dat"))
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4]
+{
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl BlockStore
+{
+    pub fn open<P: AsRef<Path>>(dir: P, fsync: FsyncPolicy) -> io::Result<BlockStore>
+    {
+        BlockStore::with_segment_size(dir, fsync, DEFAULT_SEGMENT_SIZE)
+    }
+
+    /// open the store in `dir`, truncating a record at the end of the last
+    /// segment which has only partially been written before a crash.
+    /// fails on damaged records anywhere else
+    pub fn with_segment_size<P: AsRef<Path>>(
+        dir: P,
+        fsync: FsyncPolicy,
+        segment_size: u64,
+    ) -> io::Result<BlockStore>
+    {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut index = HashMap::new();
+        let mut order = vec![];
+        let mut id = 0;
+
+        while segment_path(&dir, id).exists()
+        {
+            id += 1;
+        }
+
+        for i in 0..id
+        {
+            BlockStore::scan_segment(&dir, i, i + 1 == id, &mut index, &mut order)?;
+        }
+
+        // continue with the last segment
+        let current_id = id.saturating_sub(1);
+        let current = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, current_id))?;
+        let current_len = current.metadata()?.len();
+
+        info!(
+            "opened block store in {} with {} block(s).",
+            dir.display(),
+            order.len()
+        );
+
+        Ok(BlockStore {
+            dir,
+            fsync,
+            segment_size,
+            index,
+            order,
+            current,
+            current_id,
+            current_len,
+            unsynced: 0,
+        })
+    }
+
+    /// index all records of a segment, a torn record at the
+    /// end of the `last` segment is cut off
+    fn scan_segment(
+        dir: &Path,
+        id: u32,
+        last: bool,
+        index: &mut HashMap<Vec<u8>, Location>,
+        order: &mut Vec<Vec<u8>>,
+    ) -> io::Result<()>
+    {
+        let path = segment_path(dir, id);
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let len = file.metadata()?.len();
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut pos: u64 = 0;
+        while pos < len
+        {
+            match BlockStore::parse_record(&data[pos as usize..])
+            {
+                Some((hash, payload_len)) =>
+                {
+                    if !index.contains_key(&hash)
+                    {
+                        let location = Location {
+                            segment: id,
+                            offset: pos + RECORD_HEADER_LEN,
+                            len: payload_len,
+                        };
+                        index.insert(hash.clone(), location);
+                        order.push(hash);
+                    }
+                    pos += RECORD_HEADER_LEN + payload_len as u64;
+                }
+                None if last && BlockStore::is_torn(&data[pos as usize..]) =>
+                {
+                    warn!(
+                        "truncating torn write in {} at offset {pos}, dropping {} byte(s).",
+                        path.display(),
+                        len - pos
+                    );
+                    file.set_len(pos)?;
+                    file.sync_all()?;
+                    break;
+                }
+                None =>
+                {
+                    return Err(invalid_data(format!(
+                        "damaged record in {} at offset {pos}",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// true if the damaged record at the start of `data` reaches the end of
+    /// the file, as left behind by a write interrupted by a crash
+    fn is_torn(data: &[u8]) -> bool
+    {
+        if (data.len() as u64) < RECORD_HEADER_LEN
+        {
+            return true;
+        }
+
+        let len = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        RECORD_HEADER_LEN + len as u64 >= data.len() as u64
+    }
+
+    /// returns the hash and payload length of a complete and intact record
+    fn parse_record(data: &[u8]) -> Option<(Vec<u8>, u32)>
+    {
+        if (data.len() as u64) < RECORD_HEADER_LEN || data[..4] != MAGIC
+        {
+            return None;
+        }
+
+        let len = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        let hash = data[8..40].to_vec();
+        let sum = &data[40..44];
+
+        let payload =
+            data.get(RECORD_HEADER_LEN as usize..RECORD_HEADER_LEN as usize + len as usize)?;
+
+        if checksum(payload) != sum
+        {
+            return None;
+        }
+        Some((hash, len))
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.index.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.order.is_empty()
+    }
+
+    /// append a block, blocks which are already stored are skipped
+    pub fn append(&mut self, blk: &Block) -> io::Result<()>
+    {
+        let hash = blk.hash();
+        if self.contains(&hash)
+        {
+            return Ok(());
+        }
+
+        let payload = blk.to_bytes();
+
+        if self.current_len > 0
+            && self.current_len + RECORD_HEADER_LEN + payload.len() as u64 > self.segment_size
+        {
+            self.next_segment()?;
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&MAGIC);
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&hash);
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+
+        if let Err(e) = self.current.write_all(&record)
+        {
+            // drop what has been written so the file matches the index
+            self.current.set_len(self.current_len)?;
+            return Err(e);
+        }
+
+        let location = Location {
+            segment: self.current_id,
+            offset: self.current_len + RECORD_HEADER_LEN,
+            len: payload.len() as u32,
+        };
+        self.current_len += record.len() as u64;
+        self.index.insert(hash.clone(), location);
+        self.order.push(hash);
+
+        self.unsynced += 1;
+        match self.fsync
+        {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn next_segment(&mut self) -> io::Result<()>
+    {
+        self.sync()?;
+
+        self.current_id += 1;
+        self.current = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, self.current_id))?;
+        self.current_len = 0;
+        Ok(())
+    }
+
+    /// flush all appended blocks to the disk
+    pub fn sync(&mut self) -> io::Result<()>
+    {
+        self.current.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn read(&self, location: &Location) -> io::Result<Block>
+    {
+        let mut file = File::open(segment_path(&self.dir, location.segment))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+
+        let mut payload = vec![0; location.len as usize];
+        file.read_exact(&mut payload)?;
+
+        Block::from_bytes(&payload).map_err(invalid_data)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> io::Result<Option<Block>>
+    {
+        match self.index.get(hash)
+        {
+            Some(location) => self.read(location).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// all stored blocks in the order they were appended,
+    /// each one is read when it is reached
+    pub fn blocks(&self) -> impl Iterator<Item = io::Result<Block>> + '_
+    {
+        self.order.iter().map(|hash| self.read(&self.index[hash]))
+    }
+}
+
+impl Drop for BlockStore
+{
+    fn drop(&mut self)
+    {
+        if let Err(e) = self.sync()
+        {
+            warn!("failed to sync block store with error {e}.");
+        }
+    }
+}
+
+/// chain backend on top of a block store, blocks are read
+/// from the segment files whenever they are needed
+pub struct DiskChainStore
+{
+    disk: BlockStore,
+}
+
+impl DiskChainStore
+{
+    pub fn new(disk: BlockStore) -> DiskChainStore
+    {
+        DiskChainStore { disk }
+    }
+}
+
+impl ChainStore for DiskChainStore
+{
+    fn get(&self, hash: &[u8]) -> Option<Block>
+    {
+        self.disk.get(hash).unwrap_or_else(|e| {
+            warn!("failed to read block with error {e}.");
+            None
+        })
+    }
+
+    fn contains(&self, hash: &[u8]) -> bool
+    {
+        self.disk.contains(hash)
+    }
+
+    fn insert(&mut self, block: Block) -> Result<(), String>
+    {
+        self.disk.append(&block).map_err(|e| e.to_string())
+    }
+
+    fn len(&self) -> usize
+    {
+        self.disk.len()
+    }
+
+    fn blocks(&self) -> Box<dyn Iterator<Item = Block> + '_>
+    {
+        Box::new(self.disk.blocks().filter_map(|blk| {
+            blk.map_err(|e| warn!("failed to read block with error {e}."))
+                .ok()
+        }))
+    }
+}
+
+/// state backend keeping a snapshot of the ledger and the hash of its
+/// block in a single file, which is replaced atomically every
+/// `SNAPSHOT_INTERVAL` commits and when the store is dropped
+pub struct DiskStateStore
+{
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    head: Vec<u8>,
+    ledger: Ledger,
+
+    // commits since the last snapshot
+    unwritten: u64,
+
+    // snapshots since the last sync
+    unsynced: u64,
+}
+
+impl DiskStateStore
+{
+    pub fn open<P: AsRef<Path>>(path: P, fsync: FsyncPolicy) -> io::Result<DiskStateStore>
+    {
+        let path = path.as_ref().to_path_buf();
+
+        let (head, ledger) = match std::fs::read(&path)
+        {
+            Ok(data) => <(Vec<u8>, Ledger)>::from_bytes(&data).map_err(invalid_data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (vec![], Ledger::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(DiskStateStore {
+            path,
+            fsync,
+            head,
+            ledger,
+            unwritten: 0,
+            unsynced: 0,
+        })
+    }
+
+    /// replace the snapshot with the current head and ledger
+    fn write(&mut self) -> io::Result<()>
+    {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+
+        let mut data = vec![];
+        self.head.encode(&mut data);
+        self.ledger.encode(&mut data);
+        file.write_all(&data)?;
+
+        self.unsynced += 1;
+        let sync = match self.fsync
+        {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+
+        if sync
+        {
+            file.sync_all()?;
+            self.unsynced = 0;
+        }
+
+        std::fs::rename(&tmp, &self.path)?;
+        self.unwritten = 0;
+        Ok(())
+    }
+}
+
+impl Drop for DiskStateStore
+{
+    fn drop(&mut self)
+    {
+        if self.unwritten == 0
+        {
+            return;
+        }
+
+        if let Err(e) = self.write()
+        {
+            warn!("failed to write state snapshot with error {e}.");
+        }
+    }
+}
+
+impl StateStore for DiskStateStore
+{
+    fn ledger(&self) -> &Ledger
+    {
+        &self.ledger
+    }
+
+    fn head(&self) -> &[u8]
+    {
+        &self.head
+    }
+
+    fn commit(&mut self, head: &[u8], ledger: Ledger) -> Result<(), String>
+    {
+        self.head = head.to_vec();
+        self.ledger = ledger;
+        self.unwritten += 1;
+
+        if self.unwritten >= SNAPSHOT_INTERVAL
+        {
+            self.write().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use jabcoin::core::{Address, Blockchain, ChainParams};
+
+    fn temp_dir(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("jabnode-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chain(len: usize) -> Vec<Block>
+    {
+        let miner = Address::generate_random();
+        let mut blks = vec![Block::new(miner.clone())];
+
+        for _ in 1..len
+        {
+            let next = Block::with_previous(miner.clone(), blks.last().unwrap());
+            blks.push(next);
+        }
+        blks
+    }
+
+    #[test]
+    fn append_and_reopen()
+    {
+        let dir = temp_dir("reopen");
+        let blks = chain(5);
+
+        {
+            let mut store = BlockStore::with_segment_size(&dir, FsyncPolicy::Never, 1024).unwrap();
+            for blk in &blks
+            {
+                store.append(blk).unwrap();
+            }
+
+            // duplicates are skipped
+            store.append(&blks[0]).unwrap();
+            assert_eq!(store.len(), 5);
+            assert_eq!(store.get(&blks[3].hash()).unwrap().as_ref(), Some(&blks[3]));
+        }
+
+        // small segments force several files
+        assert!(segment_path(&dir, 1).exists());
+
+        let store = BlockStore::open(&dir, FsyncPolicy::Always).unwrap();
+        assert_eq!(
+            store.blocks().collect::<io::Result<Vec<_>>>().unwrap(),
+            blks
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_torn_write()
+    {
+        let dir = temp_dir("torn");
+        let blks = chain(3);
+
+        {
+            let mut store = BlockStore::open(&dir, FsyncPolicy::Always).unwrap();
+            for blk in &blks
+            {
+                store.append(blk).unwrap();
+            }
+        }
+
+        // cut the last record in half
+        let path = segment_path(&dir, 0);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 20).unwrap();
+
+        let mut store = BlockStore::open(&dir, FsyncPolicy::Always).unwrap();
+        assert_eq!(
+            store.blocks().collect::<io::Result<Vec<_>>>().unwrap(),
+            blks[..2]
+        );
+
+        // appending continues after the last intact record
+        store.append(&blks[2]).unwrap();
+        drop(store);
+
+        let store = BlockStore::open(&dir, FsyncPolicy::Always).unwrap();
+        assert_eq!(
+            store.blocks().collect::<io::Result<Vec<_>>>().unwrap(),
+            blks
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_damaged_records()
+    {
+        let dir = temp_dir("damaged");
+        let blks = chain(4);
+
+        {
+            let mut store = BlockStore::with_segment_size(&dir, FsyncPolicy::Never, 1024).unwrap();
+            for blk in &blks
+            {
+                store.append(blk).unwrap();
+            }
+        }
+        assert!(segment_path(&dir, 1).exists());
+
+        // flip a byte of the first payload, followed by intact records
+        let path = segment_path(&dir, 0);
+        let mut data = std::fs::read(&path).unwrap();
+        data[RECORD_HEADER_LEN as usize] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let err = BlockStore::open(&dir, FsyncPolicy::Never).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // nothing has been cut off
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn periodic_snapshots()
+    {
+        let dir = temp_dir("snapshots");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ledger.dat");
+
+        let mut ledger = Ledger::new();
+        let mut state = DiskStateStore::open(&path, FsyncPolicy::Never).unwrap();
+
+        for i in 1..SNAPSHOT_INTERVAL
+        {
+            let mut blk = Block::new(Address::generate_random());
+            blk.set_reward(i);
+            ledger.apply_block(&blk).unwrap();
+            state.commit(&[i as u8], ledger.clone()).unwrap();
+        }
+        assert!(!path.exists());
+
+        state.commit(&[0xff], ledger.clone()).unwrap();
+        let stored = DiskStateStore::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(stored.head(), &[0xff]);
+        assert_eq!(stored.ledger(), &ledger);
+
+        // the commits after the last snapshot are written on drop
+        state.commit(&[0xfe], Ledger::new()).unwrap();
+        drop(state);
+
+        let stored = DiskStateStore::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(stored.head(), &[0xfe]);
+        assert_eq!(stored.ledger(), &Ledger::new());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disk_backed_chain()
+    {
+        let dir = temp_dir("chain");
+        let miner = Address::generate_random();
+
+        let open = || {
+            let blocks = DiskChainStore::new(BlockStore::open(&dir, FsyncPolicy::Never).unwrap());
+            let state = DiskStateStore::open(dir.join("ledger.dat"), FsyncPolicy::Never).unwrap();
+            Blockchain::with_stores(ChainParams::default(), Box::new(blocks), Box::new(state))
+                .unwrap()
+        };
+
+        let mut blkchain = open();
+        let mut prev: Option<Block> = None;
+
+        for _ in 0..3
+        {
+            let mut blk = match &prev
+            {
+                Some(prev) => Block::with_previous(miner.clone(), prev),
+                None => Block::new(miner.clone()),
+            };
+            blk.set_reward(ChainParams::default().subsidy(blk.id()));
+
+            while !blk.check_pow()
+            {
+                blk.update_nounce();
+            }
+
+            blkchain.append_block(blk.clone()).unwrap();
+            prev = Some(blk);
+        }
+
+        let ledger = blkchain.ledger().clone();
+        drop(blkchain);
+
+        // the state file holds the ledger at the head
+        let state = DiskStateStore::open(dir.join("ledger.dat"), FsyncPolicy::Never).unwrap();
+        assert_eq!(state.ledger(), &ledger);
+        assert_eq!(state.head(), prev.as_ref().unwrap().hash());
+
+        let blkchain = open();
+        assert_eq!(blkchain.len(), 3);
+        assert_eq!(blkchain.head(), prev);
+        assert_eq!(blkchain.ledger(), &ledger);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 use jabcoin::core::crypto::{generate_random_rsa_pair, PaddingScheme, RsaPrivateKey};
-use jabcoin::core::Transaction;
+use jabcoin::core::{Address, Input, Output, Transaction};
+use std::io;
+use std::path::Path;
 
+#[derive(Clone)]
 pub struct Wallet
 {
     key: RsaPrivateKey,
@@ -20,9 +23,79 @@ impl Wallet
         Wallet { key }
     }
 
+    /// read the private key of a json file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Wallet>
+    {
+        let s = std::fs::read_to_string(path)?;
+        let key =
+            serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Wallet { key })
+    }
+
+    /// write the private key to a json file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()>
+    {
+        let s = serde_json::to_string(&self.key)?;
+        std::fs::write(path, s)
+    }
+
+    /// address receiving the value sent to the wallet
+    pub fn address(&self) -> Address
+    {
+        Address::with_key(self.key.to_public_key())
+    }
+
     pub fn sign(&self, trx: &mut Transaction)
     {
         let p = PaddingScheme::new_pkcs1v15_sign(None);
         trx.set_signature(self.key.sign(p, &trx.hash_ignore_sig()).unwrap());
     }
+
+    /// a signed transaction paying `to` and the miner's `fee`,
+    /// `nonce` has to be the number of transactions sent so far
+    pub fn pay(
+        &self,
+        to: Vec<(Address, u64)>,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, &'static str>
+    {
+        let output = Output::with_addrs(to)?;
+        let value = output
+            .total_value()
+            .and_then(|v| v.checked_add(fee))
+            .ok_or("value overflow")?;
+
+        let mut trx = Transaction::new(Input::new(self.address(), value, nonce), output, fee);
+        self.sign(&mut trx);
+        Ok(trx)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn pay()
+    {
+        let wallet = Wallet::generate_random();
+        let to = Address::generate_random();
+
+        let trx = wallet.pay(vec![(to.clone(), 10)], 2, 3).unwrap();
+        assert!(trx.check_validity());
+        assert_eq!(trx.input().get_addr(), &wallet.address());
+        assert_eq!(trx.input().get_value(), 12);
+        assert_eq!(trx.input().get_nonce(), 3);
+        assert!(trx.involves(&to));
+
+        let path = std::env::temp_dir().join(format!("jabnode-wallet-{}", std::process::id()));
+        wallet.save(&path).unwrap();
+        let loaded = Wallet::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.address(), wallet.address());
+    }
 }
@@ -0,0 +1,100 @@
+use jabnode::node::{Config, Node, Phase};
+use jabnode::KillToken;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+struct TestNode
+{
+    node: Arc<Node>,
+    addr: SocketAddr,
+    killtoken: Arc<KillToken>,
+    thread: JoinHandle<Result<(), String>>,
+    dir: PathBuf,
+}
+
+impl TestNode
+{
+    /// start a node listening on a free local port with an empty chain
+    fn start(name: &str, seeds: &[&TestNode]) -> TestNode
+    {
+        let dir = std::env::temp_dir().join(format!("jabnode-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // the port is free again once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut cfg = Config::with_default();
+        cfg.blkpath = dir.to_str().unwrap().into();
+        cfg.mine = false;
+        cfg.listen = addr;
+        cfg.seeds = seeds.iter().map(|s| s.addr.to_string()).collect();
+
+        let killtoken = Arc::new(KillToken::new());
+        let node = Node::new(cfg, Arc::clone(&killtoken));
+
+        let cpy = Arc::clone(&node);
+        let thread = std::thread::spawn(move || cpy.start());
+
+        let slf = TestNode {
+            node,
+            addr,
+            killtoken,
+            thread,
+            dir,
+        };
+
+        assert!(wait_until(|| slf.node.sync_status().phase == Phase::Done));
+        slf
+    }
+
+    fn is_connected(&self, other: &TestNode) -> bool
+    {
+        self.node.peers().contains(&other.addr)
+    }
+
+    fn stop(self)
+    {
+        self.killtoken.activate();
+        self.thread.join().unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn wait_until<F: Fn() -> bool>(cond: F) -> bool
+{
+    let deadline = Instant::now() + Duration::from_secs(30);
+
+    while Instant::now() < deadline
+    {
+        if cond()
+        {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    cond()
+}
+
+#[test]
+fn broadcast_nodes()
+{
+    // several nodes on one host are told apart by their ports
+    let a = TestNode::start("broadcast-a", &[]);
+    let b = TestNode::start("broadcast-b", &[&a]);
+    assert!(wait_until(|| a.is_connected(&b) && b.is_connected(&a)));
+
+    // c only knows a, which shares the advertised address of b
+    let c = TestNode::start("broadcast-c", &[&a]);
+    assert!(wait_until(|| c.is_connected(&a) && c.is_connected(&b)));
+    assert!(wait_until(|| b.is_connected(&c)));
+
+    c.stop();
+    b.stop();
+    a.stop();
+}
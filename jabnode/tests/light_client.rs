@@ -0,0 +1,88 @@
+use jabcoin::core::{crypto::Sha256Hash, Address};
+use jabnode::node::{Config, Node, Phase};
+use jabnode::spv::LightClient;
+use jabnode::wallet::Wallet;
+use jabnode::KillToken;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn light_client()
+{
+    let dir = std::env::temp_dir().join(format!("jabnode-light-client-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    // the full node mines to the wallet of the light client
+    let wallet = Wallet::generate_random();
+
+    let mut cfg = Config::with_default();
+    cfg.blkpath = dir.to_str().unwrap().into();
+    cfg.listen = addr;
+    cfg.seeds = vec![];
+    cfg.mine = true;
+    cfg.miner = Some(wallet.address());
+
+    let killtoken = Arc::new(KillToken::new());
+    let node = Node::new(cfg, Arc::clone(&killtoken));
+
+    let cpy = Arc::clone(&node);
+    let thread = std::thread::spawn(move || cpy.start());
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while node.sync_status().phase != Phase::Done && Instant::now() < deadline
+    {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // the listener may not be up yet
+    let mut client = loop
+    {
+        match LightClient::connect(&[addr], wallet.clone())
+        {
+            Ok(client) => break client,
+            Err(e) if Instant::now() > deadline => panic!("{e}"),
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    client.sync().unwrap();
+
+    // the reward of the genesis block pays for the transaction
+    while client.headers().is_empty() && Instant::now() < deadline
+    {
+        client.poll(Duration::from_millis(100)).unwrap();
+    }
+    assert!(!client.headers().is_empty());
+
+    let trx = client
+        .submit(vec![(Address::generate_random(), 5)], 1)
+        .unwrap();
+    assert_eq!(client.confirmations(&trx.hash()), 0);
+
+    // a payment following an unconfirmed one takes the next nonce
+    let next = client
+        .submit(vec![(Address::generate_random(), 2)], 1)
+        .unwrap();
+    assert_eq!(next.input().get_nonce(), trx.input().get_nonce() + 1);
+
+    let confirmed = |client: &LightClient| {
+        client.confirmations(&trx.hash()) >= 1 && client.confirmations(&next.hash()) >= 1
+    };
+
+    while !confirmed(&client) && Instant::now() < deadline
+    {
+        client.poll(Duration::from_millis(100)).unwrap();
+    }
+    assert!(confirmed(&client));
+    assert_eq!(client.history().len(), 2);
+
+    client.close();
+    killtoken.activate();
+    thread.join().unwrap().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}